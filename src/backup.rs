@@ -0,0 +1,430 @@
+// Numbered backups of a store in a backup directory:
+//     shared/<cf>_<level>_<id>_<crc32>_<size>  ssts, copied once and shared by backups
+//     private/<backup id>/                    the memtable as level 0 ssts, manifest files
+//                                              and the empty memtable log
+//     meta/<backup id>                         files of the backup with their checksums
+// Ssts are named by content too, since ids are reused, e.g. after repair or restore.
+// The memtable is checkpointed, so the store isn't flushed.
+// Metadata is written last and removed first, so an interrupted backup or purge
+// only leaves unreferenced files, which the next purge removes.
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::clock::{Clock, SystemClock};
+use crate::store::Store;
+
+use anyhow::{ensure, Context, Result};
+use bincode::{Decode, Encode};
+
+const SHARED_DIR: &str = "shared";
+const PRIVATE_DIR: &str = "private";
+const META_DIR: &str = "meta";
+const TMP_DIR: &str = "tmp";
+
+// CRC-32 (IEEE) of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
+
+// CRC-32 of data continued by `bytes`, given the CRC-32 of the data so far.
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    // Relative to the store directory, separated by '/'.
+    pub path: String,
+    pub size: u64,
+    pub checksum: u32,
+    // Name in shared/ of an sst, None for files in private/<backup id>/.
+    pub shared: Option<String>,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u64,
+    // Milliseconds since unix epoch.
+    pub timestamp: i64,
+    pub last_seqno: u64,
+    pub files: Vec<BackupFile>,
+}
+
+impl BackupInfo {
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+pub struct BackupEngine {
+    dir: PathBuf,
+}
+
+// Path of `path` relative to `root`, separated by '/'.
+fn relative_path(root: &Path, path: &Path) -> Result<String> {
+    let parts = path
+        .strip_prefix(root)?
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>();
+    Ok(parts.join("/"))
+}
+
+// Relative paths of the files under `dir`, sorted.
+fn relative_files(dir: &Path) -> Result<Vec<String>> {
+    fn visit(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                visit(root, &path, files)?;
+            } else {
+                files.push(relative_path(root, &path)?);
+            }
+        }
+        Ok(())
+    }
+    let mut files = Vec::new();
+    visit(dir, dir, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+// Write `bytes` to `path` through a temporary file, so `path` is either complete or missing.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Size and checksum of the file at `src`, read in chunks, which are also written to `dest`.
+fn stream(src: &Path, mut dest: Option<&mut File>) -> Result<(u64, u32)> {
+    let mut file = File::open(src).with_context(|| format!("Failed to read {src:?}"))?;
+    let mut buf = vec![0; 1 << 16];
+    let (mut size, mut checksum) = (0, 0);
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {src:?}")),
+        };
+        size += n as u64;
+        checksum = crc32_update(checksum, &buf[..n]);
+        if let Some(dest) = dest.as_mut() {
+            dest.write_all(&buf[..n])?;
+        }
+    }
+    Ok((size, checksum))
+}
+
+// Copy `src` to `dest` through a temporary file, so `dest` is either complete or missing.
+// Returns its size and checksum.
+fn copy_atomically(src: &Path, dest: &Path) -> Result<(u64, u32)> {
+    let tmp = dest.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    let copied = stream(src, Some(&mut file))?;
+    file.sync_all()?;
+    fs::rename(&tmp, dest)?;
+    Ok(copied)
+}
+
+impl BackupEngine {
+    // Create the backup directory if missing.
+    pub fn open(backup_dir: &Path) -> Result<BackupEngine> {
+        for dir in [SHARED_DIR, PRIVATE_DIR, META_DIR] {
+            fs::create_dir_all(backup_dir.join(dir))?;
+        }
+        Ok(BackupEngine {
+            dir: backup_dir.to_path_buf(),
+        })
+    }
+
+    // Backups ordered by id.
+    pub fn backups(&self) -> Result<Vec<BackupInfo>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(self.dir.join(META_DIR))? {
+            // Skip temporary files of interrupted backups.
+            if let Ok(id) = entry?.file_name().to_string_lossy().parse() {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        ids.into_iter().map(|id| self.backup(id)).collect()
+    }
+
+    pub fn backup(&self, backup_id: u64) -> Result<BackupInfo> {
+        let path = self.dir.join(META_DIR).join(backup_id.to_string());
+        let bytes = fs::read(&path).with_context(|| format!("Backup {backup_id} doesn't exist"))?;
+        let (info, _) = bincode::decode_from_slice(&bytes, bincode::config::standard())?;
+        Ok(info)
+    }
+
+    fn location(&self, backup_id: u64, file: &BackupFile) -> PathBuf {
+        match &file.shared {
+            Some(name) => self.dir.join(SHARED_DIR).join(name),
+            None => self
+                .dir
+                .join(PRIVATE_DIR)
+                .join(backup_id.to_string())
+                .join(&file.path),
+        }
+    }
+
+    // Back up `store` as of now, copying only ssts that no earlier backup holds.
+    pub fn create_backup(&self, store: &Store) -> Result<BackupInfo> {
+        let backups = self.backups()?;
+        let id = backups.last().map_or(1, |info| info.id + 1);
+        // Names in shared/ of the ssts earlier backups hold.
+        let shared = backups
+            .iter()
+            .flat_map(|info| &info.files)
+            .filter_map(|file| file.shared.clone())
+            .collect::<BTreeSet<_>>();
+        let (tmp, private) = (
+            self.dir.join(TMP_DIR),
+            self.dir.join(PRIVATE_DIR).join(id.to_string()),
+        );
+        for dir in [&tmp, &private] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+
+        // The pinned version keeps its ssts on disk until they are copied.
+        let version = store.current_version();
+        store.checkpoint_memtable(&tmp, &version)?;
+        let mut files = Vec::new();
+        for sst_id in version.active_sst_ids() {
+            let src = sst_id.path(store.dir());
+            let path = relative_path(store.dir(), &src)?;
+            let (size, checksum) = stream(&src, None)?;
+            let name = format!("{}_{checksum:08x}_{size}", path.replace('/', "_"));
+            if !shared.contains(&name) {
+                copy_atomically(&src, &self.dir.join(SHARED_DIR).join(&name))?;
+            }
+            files.push(BackupFile {
+                path,
+                size,
+                checksum,
+                shared: Some(name),
+            });
+        }
+        for path in relative_files(&tmp)? {
+            let (src, dest) = (tmp.join(&path), private.join(&path));
+            let (size, checksum) = stream(&src, None)?;
+            fs::create_dir_all(dest.parent().unwrap())?;
+            fs::rename(src, dest)?;
+            files.push(BackupFile {
+                path,
+                size,
+                checksum,
+                shared: None,
+            });
+        }
+        fs::remove_dir_all(&tmp)?;
+
+        let info = BackupInfo {
+            id,
+            timestamp: SystemClock.now_millis(),
+            last_seqno: store.last_seqno(),
+            files,
+        };
+        let meta = bincode::encode_to_vec(&info, bincode::config::standard())?;
+        write_atomically(&self.dir.join(META_DIR).join(id.to_string()), &meta)?;
+        Ok(info)
+    }
+
+    // Check the size and checksum of a file of the backup, while writing it to `dest`.
+    fn check_file(&self, backup_id: u64, file: &BackupFile, dest: Option<&mut File>) -> Result<()> {
+        let location = self.location(backup_id, file);
+        let (size, checksum) = stream(&location, dest)?;
+        ensure!(
+            size == file.size,
+            "{location:?} has {size} bytes, expected {}",
+            file.size
+        );
+        ensure!(
+            checksum == file.checksum,
+            "{location:?} has checksum {checksum:08x}, expected {:08x}",
+            file.checksum
+        );
+        Ok(())
+    }
+
+    // Check that every file of the backup is intact.
+    pub fn verify(&self, backup_id: u64) -> Result<()> {
+        let info = self.backup(backup_id)?;
+        for file in &info.files {
+            self.check_file(backup_id, file, None)?;
+        }
+        Ok(())
+    }
+
+    // Write the store of the backup to `dest_dir`, which should be empty or missing.
+    // Files are checked while written to a directory next to it, which replaces it
+    // only once all are intact, so a failed restore leaves `dest_dir` as it was.
+    pub fn restore(&self, backup_id: u64, dest_dir: &Path) -> Result<()> {
+        ensure!(
+            !dest_dir.exists() || fs::read_dir(dest_dir)?.next().is_none(),
+            "Restore directory {dest_dir:?} isn't empty"
+        );
+        let info = self.backup(backup_id)?;
+        let mut tmp_name = dest_dir
+            .file_name()
+            .with_context(|| format!("Can't restore to {dest_dir:?}"))?
+            .to_owned();
+        tmp_name.push(".restoring");
+        let tmp = dest_dir.with_file_name(tmp_name);
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        if let Err(err) = self.write_files(&info, &tmp) {
+            fs::remove_dir_all(&tmp)?;
+            return Err(err);
+        }
+        if dest_dir.exists() {
+            fs::remove_dir(dest_dir)?;
+        }
+        fs::rename(&tmp, dest_dir)?;
+        Ok(())
+    }
+
+    fn write_files(&self, info: &BackupInfo, dir: &Path) -> Result<()> {
+        for file in &info.files {
+            let dest = dir.join(&file.path);
+            fs::create_dir_all(dest.parent().unwrap())?;
+            let mut out = File::create(&dest)?;
+            self.check_file(info.id, file, Some(&mut out))?;
+            out.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Remove all but the newest `keep` backups, and files no remaining backup refers to.
+    // Returns ids of the removed backups.
+    pub fn purge_old_backups(&self, keep: usize) -> Result<Vec<u64>> {
+        let backups = self.backups()?;
+        let purged = backups.len().saturating_sub(keep);
+        let mut removed = Vec::new();
+        for info in &backups[..purged] {
+            fs::remove_file(self.dir.join(META_DIR).join(info.id.to_string()))?;
+            removed.push(info.id);
+        }
+
+        let kept = &backups[purged..];
+        let private = kept
+            .iter()
+            .map(|info| info.id.to_string())
+            .collect::<BTreeSet<_>>();
+        for entry in fs::read_dir(self.dir.join(PRIVATE_DIR))? {
+            let entry = entry?;
+            if !private.contains(&*entry.file_name().to_string_lossy()) {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+        let shared = kept
+            .iter()
+            .flat_map(|info| info.files.iter().filter_map(|file| file.shared.clone()))
+            .collect::<BTreeSet<_>>();
+        for entry in fs::read_dir(self.dir.join(SHARED_DIR))? {
+            let entry = entry?;
+            if !shared.contains(&*entry.file_name().to_string_lossy()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::backup::*;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    #[test]
+    fn test_backup_engine() -> Result<()> {
+        ensure!(crc32(b"123456789") == 0xcbf4_3926);
+
+        let store_dir = create_test_dir()?;
+        let backup_dir = create_test_dir()?;
+        let mut store = Store::new(&store_dir)?;
+        let engine = BackupEngine::open(&backup_dir)?;
+        store.insert(b"a".to_vec(), b"1".to_vec())?;
+        store.flush()?;
+        store.insert(b"b".to_vec(), b"2".to_vec())?;
+        let first = engine.create_backup(&store)?;
+
+        // Only the new sst and the memtable are copied.
+        store.insert(b"c".to_vec(), b"3".to_vec())?;
+        store.flush()?;
+        let second = engine.create_backup(&store)?;
+        ensure!((first.id, second.id) == (1, 2));
+        let ssts = |info: &BackupInfo| info.files.iter().filter(|f| f.shared.is_some()).count();
+        ensure!((ssts(&first), ssts(&second)) == (1, 2), "{second:?}");
+        ensure!(fs::read_dir(backup_dir.join(SHARED_DIR))?.count() == 2);
+        ensure!(!backup_dir.join(TMP_DIR).exists());
+
+        for info in [&first, &second] {
+            engine.verify(info.id)?;
+            let restore_dir = create_test_dir()?.join("restored");
+            engine.restore(info.id, &restore_dir)?;
+            let restored = Store::open(&restore_dir)?;
+            ensure!(restored.get(b"b")? == Some(b"2".to_vec()));
+            ensure!(restored.get(b"c")?.is_some() == (info.id == 2));
+            ensure!(restored.last_seqno() == info.last_seqno);
+            ensure!(engine.restore(info.id, &restore_dir).is_err());
+        }
+
+        // A corrupt file fails verify and restore, which leaves nothing behind.
+        let file = second.files.last().unwrap();
+        let location = engine.location(2, file);
+        let intact = fs::read(&location)?;
+        fs::write(&location, b"corrupt")?;
+        ensure!(format!("{:#}", engine.verify(2).unwrap_err()).contains("has 7 bytes"));
+        let parent = create_test_dir()?;
+        ensure!(engine.restore(2, &parent.join("restored")).is_err());
+        ensure!(fs::read_dir(&parent)?.next().is_none());
+        fs::write(&location, intact)?;
+        engine.restore(2, &parent.join("restored"))?;
+
+        // Purging keeps the files of the newest backups.
+        let third = engine.create_backup(&store)?;
+        ensure!(engine.purge_old_backups(2)? == [1]);
+        ensure!(engine.backups()?.iter().map(|b| b.id).collect::<Vec<_>>() == [2, 3]);
+        ensure!(engine.backup(1).is_err());
+        ensure!(fs::read_dir(backup_dir.join(SHARED_DIR))?.count() == ssts(&third));
+        engine.verify(2)?;
+        ensure!(engine.purge_old_backups(0)? == [2, 3]);
+        ensure!(fs::read_dir(backup_dir.join(PRIVATE_DIR))?.next().is_none());
+        ensure!(fs::read_dir(backup_dir.join(SHARED_DIR))?.next().is_none());
+
+        // An sst at the same path with the same size but other content is copied too.
+        engine.create_backup(&store)?;
+        let other_dir = create_test_dir()?;
+        let mut other = Store::new(&other_dir)?;
+        other.insert(b"a".to_vec(), b"9".to_vec())?;
+        other.flush()?;
+        let info = engine.create_backup(&other)?;
+        ensure!(fs::read_dir(backup_dir.join(SHARED_DIR))?.count() == 3);
+        let restore_dir = create_test_dir()?.join("restored");
+        engine.restore(info.id, &restore_dir)?;
+        ensure!(Store::open(&restore_dir)?.get(b"a")? == Some(b"9".to_vec()));
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+// Benchmarks in the style of LevelDB's db_bench, run by `qikv bench`.
+// Keys are the numbers below the count, zero padded to the key size so they sort as numbers.
+// Each of the threads runs its share of the operations on the same store,
+// except in readwhilewriting where one more thread writes until the readers finish.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::store::{IoStats, Store};
+
+use anyhow::{ensure, Result};
+use rand::Rng;
+
+pub const BENCHMARKS: &[&str] = &[
+    "fillseq",
+    "fillrandom",
+    "overwrite",
+    "readrandom",
+    "readseq",
+    "seekrandom",
+    "deleterandom",
+    "readwhilewriting",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchOptions {
+    pub key_size: usize,
+    pub value_size: usize,
+    // Operations of each benchmark, over all threads.
+    pub count: u64,
+    pub threads: usize,
+}
+
+impl Default for BenchOptions {
+    fn default() -> BenchOptions {
+        BenchOptions {
+            key_size: 16,
+            value_size: 100,
+            count: 100_000,
+            threads: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub name: String,
+    pub ops: u64,
+    // Reads that found their key.
+    pub found: u64,
+    // Bytes of keys and values written or read.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    // Of every operation, sorted.
+    pub latencies: Vec<Duration>,
+    // Store counters accumulated during the benchmark, writer thread included.
+    pub io: IoStats,
+}
+
+impl BenchResult {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / (1 << 20) as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // Latency below which `p` percent of operations finish, by nearest rank.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+// What a thread did.
+#[derive(Default)]
+struct ThreadResult {
+    found: u64,
+    bytes: u64,
+    latencies: Vec<Duration>,
+}
+
+impl ThreadResult {
+    // Run `op` and record its latency and the bytes it returns.
+    fn time<F: FnOnce() -> Result<Option<usize>>>(&mut self, op: F) -> Result<()> {
+        let start = Instant::now();
+        let bytes = op()?;
+        self.latencies.push(start.elapsed());
+        if let Some(bytes) = bytes {
+            self.found += 1;
+            self.bytes += bytes as u64;
+        }
+        Ok(())
+    }
+}
+
+struct Bench<'a> {
+    store: &'a RwLock<Store>,
+    options: BenchOptions,
+}
+
+impl Bench<'_> {
+    fn key(&self, i: u64) -> Vec<u8> {
+        format!("{i:0width$}", width = self.options.key_size).into_bytes()
+    }
+
+    fn random_key(&self) -> Vec<u8> {
+        self.key(rand::thread_rng().gen_range(0..self.options.count))
+    }
+
+    fn value(&self) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        (0..self.options.value_size)
+            .map(|_| rng.gen_range(b'a'..=b'z'))
+            .collect()
+    }
+
+    fn write(&self, key: Vec<u8>) -> Result<Option<usize>> {
+        let value = self.value();
+        let bytes = key.len() + value.len();
+        self.store.write().unwrap().insert(key, value)?;
+        Ok(Some(bytes))
+    }
+
+    fn read(&self, key: &[u8]) -> Result<Option<usize>> {
+        let value = self.store.read().unwrap().get(key)?;
+        Ok(value.map(|v| key.len() + v.len()))
+    }
+
+    // Run `ops` operations of a thread, whose share of keys starts at `first`.
+    fn run_thread(&self, name: &str, first: u64, ops: u64) -> Result<ThreadResult> {
+        let mut result = ThreadResult::default();
+        match name {
+            "fillseq" => {
+                for i in first..first + ops {
+                    result.time(|| self.write(self.key(i)))?;
+                }
+            }
+            "fillrandom" | "overwrite" => {
+                for _ in 0..ops {
+                    result.time(|| self.write(self.random_key()))?;
+                }
+            }
+            "readrandom" | "readwhilewriting" => {
+                for _ in 0..ops {
+                    let key = self.random_key();
+                    result.time(|| self.read(&key))?;
+                }
+            }
+            // Each thread reads from the first key, and the iterator pins its version.
+            "readseq" => {
+                let store = self.store.read().unwrap();
+                let mut iter = store.scan(None, None)?;
+                for _ in 0..ops {
+                    result.time(|| Ok(iter.next().transpose()?.map(|(k, v)| k.len() + v.len())))?;
+                }
+            }
+            // Seek to a random key and read the pair at or after it.
+            "seekrandom" => {
+                for _ in 0..ops {
+                    let key = self.random_key();
+                    result.time(|| {
+                        let store = self.store.read().unwrap();
+                        let mut iter = store.scan(Some(&key), None)?;
+                        Ok(iter.next().transpose()?.map(|(k, v)| k.len() + v.len()))
+                    })?;
+                }
+            }
+            "deleterandom" => {
+                for _ in 0..ops {
+                    let key = self.random_key();
+                    result.time(|| {
+                        self.store.write().unwrap().remove(&key)?;
+                        Ok(Some(key.len()))
+                    })?;
+                }
+            }
+            _ => unreachable!("Checked by run()"),
+        }
+        Ok(result)
+    }
+}
+
+// Run benchmark `name` on `store`.
+pub fn run(store: &RwLock<Store>, name: &str, options: BenchOptions) -> Result<BenchResult> {
+    ensure!(
+        BENCHMARKS.contains(&name),
+        "Unknown benchmark {name}, expected one of {}",
+        BENCHMARKS.join(", ")
+    );
+    ensure!(
+        options.count > 0 && options.threads > 0,
+        "Benchmarks need at least one operation and thread"
+    );
+    ensure!(
+        (options.count - 1).to_string().len() <= options.key_size,
+        "Keys of {} bytes can't hold {} distinct numbers",
+        options.key_size,
+        options.count
+    );
+    let bench = Bench { store, options };
+    let io_before = store.read().unwrap().io_stats();
+    let writing = AtomicBool::new(name == "readwhilewriting");
+    let start = Instant::now();
+    let results = thread::scope(|scope| {
+        // Returns at once unless the benchmark is readwhilewriting.
+        let writer = scope.spawn(|| -> Result<()> {
+            while writing.load(Ordering::Relaxed) {
+                bench.write(bench.random_key())?;
+            }
+            Ok(())
+        });
+        let threads = options.threads as u64;
+        let workers = (0..threads)
+            .map(|t| {
+                // Spread the remainder over the first threads.
+                let first = t * (options.count / threads) + u64::min(t, options.count % threads);
+                let ops = options.count / threads + u64::from(t < options.count % threads);
+                let bench = &bench;
+                scope.spawn(move || bench.run_thread(name, first, ops))
+            })
+            .collect::<Vec<_>>();
+        let results = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>();
+        writing.store(false, Ordering::Relaxed);
+        writer.join().unwrap()?;
+        results.into_iter().collect::<Result<Vec<_>>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    let mut result = BenchResult {
+        name: name.to_owned(),
+        ops: 0,
+        found: 0,
+        bytes: 0,
+        elapsed,
+        latencies: Vec::new(),
+        io: store.read().unwrap().io_stats().since(&io_before),
+    };
+    for thread in results {
+        result.ops += thread.latencies.len() as u64;
+        result.found += thread.found;
+        result.bytes += thread.bytes;
+        result.latencies.extend(thread.latencies);
+    }
+    result.latencies.sort();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use crate::bench::*;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    #[test]
+    fn test_bench() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let store = RwLock::new(Store::new(&test_dir)?);
+        // Enough bytes to flush the memtable.
+        let options = BenchOptions {
+            key_size: 8,
+            value_size: 2000,
+            count: 600,
+            threads: 2,
+        };
+        let mut results = Vec::new();
+        for name in BENCHMARKS {
+            results.push(run(&store, name, options)?);
+        }
+        for result in &results {
+            ensure!(
+                result.ops == 600 && result.latencies.len() == 600,
+                "{}",
+                result.name
+            );
+            ensure!(result.percentile(50.0) <= result.percentile(99.0));
+            ensure!(result.percentile(100.0) == *result.latencies.last().unwrap());
+        }
+        let (fillseq, readrandom) = (&results[0], &results[3]);
+        ensure!(fillseq.found == 600 && fillseq.bytes == 600 * 2008);
+        ensure!(fillseq.io.user_bytes_written == fillseq.bytes);
+        // Every write goes to the memtable log, and some of them on to ssts.
+        ensure!(fillseq.io.log_bytes_written > fillseq.bytes);
+        ensure!(fillseq.io.write_amplification() > 1.0);
+        ensure!(readrandom.found == 600 && readrandom.io.gets == 600);
+        ensure!(readrandom.io.read_amplification() > 0.0);
+        // Sequential reads stop at the last key.
+        ensure!(results[4].found == 600);
+
+        ensure!(run(&store, "fillbatch", options).is_err());
+        let short_keys = BenchOptions {
+            key_size: 2,
+            ..options
+        };
+        ensure!(run(&store, "fillseq", short_keys).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,986 @@
+// Command-line interface of the qikv binary.
+// Keys and values are passed and printed as utf8 by default, or as hex or base64.
+// With --json, results are printed as JSON objects, one per line.
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::bench::{self, BenchOptions, BenchResult, BENCHMARKS};
+use crate::comparator::builtin_comparator;
+use crate::export::{self, Format, RecordFormat};
+use crate::manifest::{ManifestAction, ManifestDump};
+use crate::memtable::ValueUpdate;
+use crate::options::Options;
+use crate::shell;
+use crate::sst_dump::SstDump;
+use crate::sstable::SstId;
+use crate::store::Store;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+pub const USAGE: &str = "\
+Usage: qikv --db <path> [options] <command> [args]
+       qikv [options] sst-dump <file>
+
+Commands:
+    put <key> <value>     Insert or overwrite a pair
+    get <key>             Print the value of a key
+    rm <key>              Delete a key
+    scan [start] [end]    Print pairs with keys in [start, end)
+    export [start] [end]  Write pairs with keys in [start, end) as JSON Lines or CSV
+    import <file>         Load pairs from a JSON Lines or CSV file, - for stdin
+    checkpoint <dir>      Write a copy of the store to a new directory, linking its ssts
+    shell                 Run commands interactively on the opened store
+    sst-dump <file>       Print the index, key range and sizes of an sst file and verify it
+    manifest-dump         Print the manifest snapshot and the actions in its log
+    check                 Check the files of the store and report problems
+    repair                Rebuild the manifest from sst files and the memtable log
+    bench [benchmark...]  Run benchmarks on the store, all of them by default:
+                          fillseq, fillrandom, overwrite, readrandom, readseq,
+                          seekrandom, deleterandom and readwhilewriting
+
+Options:
+    --db <path>               Store directory, created if missing
+    --encoding <enc>          Encoding of keys and values: utf8, hex or base64
+    --key-encoding <enc>      Encoding of keys only
+    --value-encoding <enc>    Encoding of values only
+    --prefix <prefix>         Scan keys starting with prefix
+    --limit <n>               Scan at most n pairs
+    --json                    Print results as JSON lines
+    --format <fmt>            Format of export and import: jsonl or csv, jsonl by default
+    --batch-size <n>          Pairs of each import batch, 10000 by default
+    --entries                 Also print every record of the sst file
+    --comparator <name>       Comparator of the sst file, bytewise by default
+    --num <n>                 Operations of each benchmark, 100000 by default
+    --key-size <n>            Bytes of benchmark keys, 16 by default
+    --value-size <n>          Bytes of benchmark values, 100 by default
+    --threads <n>             Threads running each benchmark, 1 by default
+";
+
+// Start and end of a range of keys, None where it's unbounded.
+type KeyRange = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+impl Encoding {
+    pub fn parse(name: &str) -> Result<Encoding> {
+        match name {
+            "utf8" => Ok(Encoding::Utf8),
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            _ => Err(anyhow!(
+                "Unknown encoding {name}, expected utf8, hex or base64"
+            )),
+        }
+    }
+
+    // Bytes written as `text`.
+    pub fn decode(&self, text: &str) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Hex => {
+                ensure!(text.len() % 2 == 0, "Hex {text:?} has odd length");
+                text.as_bytes()
+                    .chunks(2)
+                    .map(|pair| {
+                        std::str::from_utf8(pair)
+                            .ok()
+                            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                            .ok_or_else(|| anyhow!("Invalid hex {text:?}"))
+                    })
+                    .collect()
+            }
+            Encoding::Base64 => {
+                // Padding is optional.
+                let trimmed = text.trim_end_matches('=');
+                ensure!(trimmed.len() % 4 != 1, "Invalid base64 {text:?}");
+                let mut bytes = Vec::new();
+                let (mut acc, mut bits) = (0_u32, 0);
+                for c in trimmed.bytes() {
+                    let sextet = BASE64_ALPHABET
+                        .iter()
+                        .position(|&a| a == c)
+                        .ok_or_else(|| anyhow!("Invalid base64 {text:?}"))?;
+                    acc = (acc << 6) | sextet as u32;
+                    bits += 6;
+                    if bits >= 8 {
+                        bits -= 8;
+                        bytes.push((acc >> bits) as u8);
+                        acc &= (1 << bits) - 1;
+                    }
+                }
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn encode(&self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Encoding::Utf8 => Ok(String::from_utf8(bytes.to_vec())
+                .map_err(|_| anyhow!("{bytes:?} is not valid utf8, try hex or base64 encoding"))?),
+            Encoding::Hex => Ok(bytes.iter().map(|b| format!("{b:02x}")).collect()),
+            Encoding::Base64 => {
+                let mut text = String::new();
+                for chunk in bytes.chunks(3) {
+                    let n = chunk
+                        .iter()
+                        .enumerate()
+                        .fold(0_u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+                    for i in 0..4 {
+                        if i <= chunk.len() {
+                            text.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+                        } else {
+                            text.push('=');
+                        }
+                    }
+                }
+                Ok(text)
+            }
+        }
+    }
+}
+
+// Quote `text` as a JSON string.
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Smallest key after every key starting with `prefix`. None if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&b| b != 0xff)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+#[derive(Clone)]
+pub(crate) struct Args {
+    pub(crate) db: Option<PathBuf>,
+    pub(crate) key_encoding: Encoding,
+    pub(crate) value_encoding: Encoding,
+    pub(crate) json: bool,
+    pub(crate) prefix: Option<String>,
+    pub(crate) limit: Option<usize>,
+    pub(crate) entries: bool,
+    pub(crate) comparator: Option<String>,
+    pub(crate) bench: BenchOptions,
+    pub(crate) format: Format,
+    pub(crate) batch_size: usize,
+    // The command and its operands.
+    pub(crate) positional: Vec<String>,
+}
+
+impl Args {
+    pub(crate) fn parse(args: &[String]) -> Result<Args> {
+        let defaults = Args {
+            db: None,
+            key_encoding: Encoding::Utf8,
+            value_encoding: Encoding::Utf8,
+            json: false,
+            prefix: None,
+            limit: None,
+            entries: false,
+            comparator: None,
+            bench: BenchOptions::default(),
+            format: Format::Jsonl,
+            batch_size: 10_000,
+            positional: Vec::new(),
+        };
+        defaults.parse_onto(args)
+    }
+
+    // Parse a command line over these options, keeping those it doesn't set.
+    pub(crate) fn parse_onto(&self, args: &[String]) -> Result<Args> {
+        let mut parsed = Args {
+            prefix: None,
+            limit: None,
+            positional: Vec::new(),
+            ..self.clone()
+        };
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().ok_or_else(|| anyhow!("{arg} requires a value"));
+            match arg.as_str() {
+                "--db" => parsed.db = Some(PathBuf::from(value()?)),
+                "--encoding" => {
+                    parsed.key_encoding = Encoding::parse(value()?)?;
+                    parsed.value_encoding = parsed.key_encoding;
+                }
+                "--key-encoding" => parsed.key_encoding = Encoding::parse(value()?)?,
+                "--value-encoding" => parsed.value_encoding = Encoding::parse(value()?)?,
+                "--prefix" => parsed.prefix = Some(value()?.clone()),
+                "--limit" => {
+                    parsed.limit = Some(value()?.parse().context("--limit requires a number")?)
+                }
+                "--json" => parsed.json = true,
+                "--format" => parsed.format = Format::parse(value()?)?,
+                "--batch-size" => {
+                    parsed.batch_size =
+                        value()?.parse().context("--batch-size requires a number")?
+                }
+                "--entries" => parsed.entries = true,
+                "--comparator" => parsed.comparator = Some(value()?.clone()),
+                "--num" => {
+                    parsed.bench.count = value()?.parse().context("--num requires a number")?
+                }
+                "--key-size" => {
+                    parsed.bench.key_size =
+                        value()?.parse().context("--key-size requires a number")?
+                }
+                "--value-size" => {
+                    parsed.bench.value_size =
+                        value()?.parse().context("--value-size requires a number")?
+                }
+                "--threads" => {
+                    parsed.bench.threads =
+                        value()?.parse().context("--threads requires a number")?
+                }
+                arg if arg.starts_with("--") => bail!("Unknown option {arg}\n\n{USAGE}"),
+                _ => parsed.positional.push(arg.clone()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    // Operands of the command, which takes from `min` to `max` of them.
+    pub(crate) fn operands(&self, min: usize, max: usize) -> Result<&[String]> {
+        let operands = &self.positional[1..];
+        ensure!(
+            (min..=max).contains(&operands.len()),
+            "{} takes {min} to {max} arguments, got {}\n\n{USAGE}",
+            self.positional[0],
+            operands.len()
+        );
+        Ok(operands)
+    }
+
+    pub(crate) fn key(&self, text: &str) -> Result<Vec<u8>> {
+        self.key_encoding.decode(text)
+    }
+
+    // Range of scan and export, given by operands or --prefix.
+    fn scan_range(&self) -> Result<KeyRange> {
+        let operands = self.operands(0, 2)?;
+        match &self.prefix {
+            Some(prefix) => {
+                ensure!(
+                    operands.is_empty(),
+                    "{} takes either a range or --prefix",
+                    self.positional[0]
+                );
+                let prefix = self.key(prefix)?;
+                let end = prefix_end(&prefix);
+                Ok((Some(prefix), end))
+            }
+            None => Ok((
+                operands.first().map(|s| self.key(s)).transpose()?,
+                operands.get(1).map(|s| self.key(s)).transpose()?,
+            )),
+        }
+    }
+
+    fn record_format(&self) -> RecordFormat {
+        RecordFormat {
+            format: self.format,
+            key_encoding: self.key_encoding,
+            value_encoding: self.value_encoding,
+        }
+    }
+
+    fn print_pair<W: Write>(&self, out: &mut W, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let key = self.key_encoding.encode(key)?;
+        let value = value.map(|v| self.value_encoding.encode(v)).transpose()?;
+        if self.json {
+            let value = value.map_or_else(|| "null".to_owned(), |v| json_string(&v));
+            writeln!(out, "{{\"key\":{},\"value\":{value}}}", json_string(&key))?;
+        } else {
+            match value {
+                Some(value) => writeln!(out, "{key}\t{value}")?,
+                None => writeln!(out, "{key}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Run the command in `args`, which don't include the program name.
+pub fn run<W: Write>(args: &[String], out: &mut W) -> Result<()> {
+    let args = Args::parse(args)?;
+    let command = match args.positional.first() {
+        Some(command) => command.as_str(),
+        None => bail!("Missing command\n\n{USAGE}"),
+    };
+    if command == "help" {
+        write!(out, "{USAGE}")?;
+        return Ok(());
+    }
+    if command == "sst-dump" {
+        return sst_dump(&args, out);
+    }
+    let db = args
+        .db
+        .as_ref()
+        .ok_or_else(|| anyhow!("Missing --db <path>\n\n{USAGE}"))?;
+    // Opening the store would recover the manifest and drop a torn log.
+    if command == "manifest-dump" {
+        args.operands(0, 0)?;
+        return manifest_dump(&args, db, out);
+    }
+    if command == "check" {
+        args.operands(0, 0)?;
+        return check(&args, db, out);
+    }
+    if command == "repair" {
+        args.operands(0, 0)?;
+        return repair(&args, db, out);
+    }
+    let mut store = Store::open(db).with_context(|| format!("Failed to open store {db:?}"))?;
+    if command == "shell" {
+        args.operands(0, 0)?;
+        return shell::run(store, &args);
+    }
+    if command == "bench" {
+        return run_bench(&args, store, out);
+    }
+    execute(&args, &mut store, out)
+}
+
+// Run a put, get, rm or scan command on `store`.
+pub(crate) fn execute<W: Write>(args: &Args, store: &mut Store, out: &mut W) -> Result<()> {
+    let command = args.positional[0].as_str();
+    match command {
+        "put" => {
+            let operands = args.operands(2, 2)?;
+            let value = args.value_encoding.decode(&operands[1])?;
+            store.insert(args.key(&operands[0])?, value)?;
+        }
+        "get" => {
+            let text = &args.operands(1, 1)?[0];
+            let key = args.key(text)?;
+            match store.get(&key)? {
+                Some(value) => {
+                    if args.json {
+                        args.print_pair(out, &key, Some(&value))?;
+                    } else {
+                        writeln!(out, "{}", args.value_encoding.encode(&value)?)?;
+                    }
+                }
+                // Absence is a result in JSON, and an error otherwise so that scripts can check it.
+                None if args.json => args.print_pair(out, &key, None)?,
+                None => bail!("Key {text} not found"),
+            }
+        }
+        "rm" | "del" => {
+            let key = args.key(&args.operands(1, 1)?[0])?;
+            store.remove(&key)?;
+        }
+        "scan" => {
+            let (start, end) = args.scan_range()?;
+            let pairs = store.scan(start.as_deref(), end.as_deref())?;
+            for kv in pairs.take(args.limit.unwrap_or(usize::MAX)) {
+                let (k, v) = kv?;
+                args.print_pair(out, &k, Some(&v))?;
+            }
+        }
+        "export" => {
+            ensure!(!args.json, "export takes --format instead of --json");
+            let (start, end) = args.scan_range()?;
+            let pairs = store.scan(start.as_deref(), end.as_deref())?;
+            let pairs = pairs.take(args.limit.unwrap_or(usize::MAX));
+            export::export(pairs, args.record_format(), out)?;
+        }
+        "checkpoint" => store.checkpoint(Path::new(&args.operands(1, 1)?[0]))?,
+        "import" => {
+            let path = &args.operands(1, 1)?[0];
+            let report = if path == "-" {
+                let stdin = io::stdin();
+                export::import(store, stdin.lock(), args.record_format(), args.batch_size)?
+            } else {
+                let file = File::open(path).with_context(|| format!("Failed to open {path}"))?;
+                export::import(
+                    store,
+                    BufReader::new(file),
+                    args.record_format(),
+                    args.batch_size,
+                )?
+            };
+            writeln!(
+                out,
+                "imported {} pairs, {} ssts ingested, {} batches written",
+                report.records,
+                report.ingested.len(),
+                report.written_batches
+            )?;
+        }
+        _ => bail!("Unknown command {command}\n\n{USAGE}"),
+    }
+    Ok(())
+}
+
+fn sst_dump<W: Write>(args: &Args, out: &mut W) -> Result<()> {
+    let path = Path::new(&args.operands(1, 1)?[0]);
+    ensure!(!args.json, "sst-dump doesn't support --json");
+    let name = args.comparator.as_deref().unwrap_or("bytewise");
+    let comparator =
+        builtin_comparator(name).ok_or_else(|| anyhow!("Unknown comparator {name}"))?;
+    let dump = SstDump::open(path, comparator)?;
+    let table = dump.table();
+    let key = |key: &[u8]| args.key_encoding.encode(key);
+
+    let (records, range_tombstones, index) = dump.block_sizes();
+    writeln!(out, "file: {path:?}")?;
+    writeln!(
+        out,
+        "size: {} bytes (records {records}, range tombstones {range_tombstones}, \
+         index {index}, block sizes 16)",
+        dump.file_size()
+    )?;
+    let metadata = table.metadata();
+    writeln!(
+        out,
+        "key range: {} .. {}",
+        key(metadata.first_key)?,
+        key(metadata.last_key)?
+    )?;
+    writeln!(out, "range tombstones: {}", table.range_tombstones().len())?;
+    for t in table.range_tombstones() {
+        writeln!(
+            out,
+            "    [{}, {}) @{}",
+            key(&t.start)?,
+            key(&t.end)?,
+            t.seqno
+        )?;
+    }
+    writeln!(out, "sparse index: {} entries", table.index().len())?;
+    for (k, offset) in table.index() {
+        writeln!(out, "    {} @{} at {offset}", key(&k.user_key)?, k.seqno)?;
+    }
+    if args.entries {
+        writeln!(out, "entries:")?;
+        for wrapped_kv in table.iter() {
+            let (k, v) = wrapped_kv?;
+            let value = |v: &[u8]| args.value_encoding.encode(v);
+            let update = match &v {
+                ValueUpdate::Value(v) => format!("value {}", value(v)?),
+                ValueUpdate::Tombstone => "tombstone".to_owned(),
+                ValueUpdate::Merge(v) => format!("merge {}", value(v)?),
+                ValueUpdate::Expiring(v, expire_at) => {
+                    format!("value {} expiring at {expire_at}", value(v)?)
+                }
+            };
+            writeln!(out, "    {} @{} {update}", key(&k.user_key)?, k.seqno)?;
+        }
+    }
+
+    // Print what could be read before failing on a corrupted file.
+    let counts = dump.verify()?;
+    writeln!(
+        out,
+        "records: {} (values {}, tombstones {}, merges {}, expiring {})",
+        counts.total(),
+        counts.values,
+        counts.tombstones,
+        counts.merges,
+        counts.expiring
+    )?;
+    writeln!(out, "verified: ok")?;
+    Ok(())
+}
+
+fn manifest_dump<W: Write>(args: &Args, db: &Path, out: &mut W) -> Result<()> {
+    ensure!(!args.json, "manifest-dump doesn't support --json");
+    let dump = ManifestDump::read(db)?;
+    let key = |key: &[u8]| args.key_encoding.encode(key);
+    let sst = |id: &SstId| format!("{}/{}/{}", id.cf, id.level, id.id);
+
+    writeln!(out, "current: {} {}", dump.snapshot_name, dump.log_name)?;
+    let manifest = &dump.snapshot;
+    writeln!(out, "comparator: {}", manifest.comparator_name())?;
+    writeln!(out, "last seqno: {}", manifest.last_seqno())?;
+    let mut families = manifest.family_names().collect::<Vec<_>>();
+    families.sort_by_key(|&(_, cf)| cf);
+    for (name, cf) in families {
+        let family = manifest.family(cf);
+        writeln!(out, "family {name} ({cf}):")?;
+        for (level, id) in family.new_ids() {
+            writeln!(out, "    new id of level {level}: {id}")?;
+        }
+        for (level, k) in family.compact_keys() {
+            writeln!(out, "    compact key of level {level}: {}", key(k)?)?;
+        }
+        for level in 0..=family.max_level() {
+            for id in family.get_sst_by_level(level) {
+                let (first, last) = family
+                    .sst_range(&id)
+                    .ok_or_else(|| anyhow!("Sst {} has no range", sst(&id)))?;
+                writeln!(
+                    out,
+                    "    sst {} [{}, {}]",
+                    sst(&id),
+                    key(first)?,
+                    key(last)?
+                )?;
+            }
+        }
+    }
+
+    writeln!(out, "log: {} bytes", dump.log_size)?;
+    let mut batch_start = true;
+    for (offset, action) in &dump.actions {
+        if batch_start {
+            writeln!(out, "batch at {offset}:")?;
+            batch_start = false;
+        }
+        let line = match action {
+            ManifestAction::Commit => {
+                batch_start = true;
+                "commit".to_owned()
+            }
+            ManifestAction::Add((id, first, last)) => {
+                format!("add {} [{}, {}]", sst(id), key(first)?, key(last)?)
+            }
+            ManifestAction::Remove((id,)) => format!("remove {}", sst(id)),
+            ManifestAction::NewId((cf, level)) => format!("new id of {cf}/{level}"),
+            ManifestAction::NextCompact((cf, level)) => format!("next compact of {cf}/{level}"),
+            ManifestAction::LastSeqno((seqno,)) => format!("last seqno {seqno}"),
+            ManifestAction::CreateFamily((cf, name)) => format!("create family {name} ({cf})"),
+        };
+        writeln!(out, "    {line}")?;
+    }
+    let uncommitted = dump.uncommitted();
+    if let Some((offset, _)) = uncommitted.first() {
+        writeln!(
+            out,
+            "torn tail: {} uncommitted actions from offset {offset}, discarded on recovery",
+            uncommitted.len()
+        )?;
+    }
+    if dump.decoded_size < dump.log_size {
+        writeln!(
+            out,
+            "torn tail: {} undecodable bytes from offset {}, discarded on recovery",
+            dump.log_size - dump.decoded_size,
+            dump.decoded_size
+        )?;
+    }
+    Ok(())
+}
+
+// Options with the comparator given by --comparator, if any.
+fn comparator_options(args: &Args) -> Result<Option<Options>> {
+    let name = match &args.comparator {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let comparator =
+        builtin_comparator(name).ok_or_else(|| anyhow!("Unknown comparator {name}"))?;
+    Ok(Some(Options {
+        comparator,
+        ..Options::default()
+    }))
+}
+
+fn check<W: Write>(args: &Args, db: &Path, out: &mut W) -> Result<()> {
+    let report = match comparator_options(args)? {
+        Some(options) => Store::verify_with_options(db, options)?,
+        None => Store::verify(db)?,
+    };
+    if args.json {
+        let problems = report
+            .problems
+            .iter()
+            .map(|p| json_string(&p.to_string()))
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "{{\"ssts\":{},\"log_writes\":{},\"problems\":[{}]}}",
+            report.ssts,
+            report.log_writes,
+            problems.join(",")
+        )?;
+    } else {
+        writeln!(out, "ssts: {}", report.ssts)?;
+        writeln!(out, "log writes: {}", report.log_writes)?;
+        for problem in &report.problems {
+            writeln!(out, "problem: {problem}")?;
+        }
+    }
+    ensure!(report.is_ok(), "Found {} problems", report.problems.len());
+    Ok(())
+}
+
+fn run_bench<W: Write>(args: &Args, store: Store, out: &mut W) -> Result<()> {
+    let mut names = args.operands(0, BENCHMARKS.len())?.to_vec();
+    if names.is_empty() {
+        names = BENCHMARKS.iter().map(|name| name.to_string()).collect();
+    }
+    let store = RwLock::new(store);
+    for name in &names {
+        let result = bench::run(&store, name, args.bench)?;
+        print_bench_result(args, &result, out)?;
+    }
+    Ok(())
+}
+
+fn print_bench_result<W: Write>(args: &Args, result: &BenchResult, out: &mut W) -> Result<()> {
+    let micros = |p| result.percentile(p).as_secs_f64() * 1e6;
+    let (p50, p95, p99, p999, max) = (
+        micros(50.0),
+        micros(95.0),
+        micros(99.0),
+        micros(99.9),
+        micros(100.0),
+    );
+    if args.json {
+        writeln!(
+            out,
+            "{{\"benchmark\":{},\"ops\":{},\"found\":{},\"seconds\":{:.6},\"ops_per_sec\":{:.1},\
+             \"mb_per_sec\":{:.3},\"p50_us\":{p50:.1},\"p95_us\":{p95:.1},\"p99_us\":{p99:.1},\
+             \"p999_us\":{p999:.1},\"max_us\":{max:.1},\"write_amplification\":{:.3},\
+             \"read_amplification\":{:.3}}}",
+            json_string(&result.name),
+            result.ops,
+            result.found,
+            result.elapsed.as_secs_f64(),
+            result.ops_per_sec(),
+            result.mb_per_sec(),
+            result.io.write_amplification(),
+            result.io.read_amplification()
+        )?;
+    } else {
+        writeln!(
+            out,
+            "{:<16} {:>12.1} ops/sec {:>10.3} MB/s  ({} of {} found)",
+            result.name,
+            result.ops_per_sec(),
+            result.mb_per_sec(),
+            result.found,
+            result.ops
+        )?;
+        writeln!(
+            out,
+            "    latency us: p50 {p50:.1}, p95 {p95:.1}, p99 {p99:.1}, p99.9 {p999:.1}, max {max:.1}"
+        )?;
+        writeln!(
+            out,
+            "    write amplification {:.3}, read amplification {:.3}",
+            result.io.write_amplification(),
+            result.io.read_amplification()
+        )?;
+    }
+    Ok(())
+}
+
+fn repair<W: Write>(args: &Args, db: &Path, out: &mut W) -> Result<()> {
+    let report = match comparator_options(args)? {
+        Some(options) => Store::repair_with_options(db, options)?,
+        None => Store::repair(db)?,
+    };
+    let sst = |id: &SstId| format!("{}/{}/{}", id.cf, id.level, id.id);
+    writeln!(out, "ssts: {}", report.ssts.len())?;
+    for id in &report.rewritten {
+        writeln!(out, "rewritten {}", sst(id))?;
+    }
+    for (old, new) in &report.moved {
+        writeln!(out, "moved {} to {}", sst(old), sst(new))?;
+    }
+    for id in &report.flushed {
+        writeln!(out, "flushed memtable log to {}", sst(id))?;
+    }
+    for path in &report.quarantined {
+        writeln!(out, "quarantined {path:?}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::*;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    fn run_cli(db: &std::path::Path, args: &[&str]) -> Result<String> {
+        let mut args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        args.splice(0..0, ["--db".to_owned(), db.to_str().unwrap().to_owned()]);
+        let mut out = Vec::new();
+        run(&args, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn test_encoding() -> Result<()> {
+        for (bytes, hex, base64) in [
+            (&b""[..], "", ""),
+            (b"f", "66", "Zg=="),
+            (b"fo", "666f", "Zm8="),
+            (b"foo", "666f6f", "Zm9v"),
+            (&[0, 0xff, 0x10, 0x80], "00ff1080", "AP8QgA=="),
+        ] {
+            ensure!(Encoding::Hex.encode(bytes)? == hex);
+            ensure!(Encoding::Base64.encode(bytes)? == base64);
+            ensure!(Encoding::Hex.decode(hex)? == bytes);
+            ensure!(Encoding::Base64.decode(base64)? == bytes);
+            ensure!(Encoding::Base64.decode(base64.trim_end_matches('='))? == bytes);
+        }
+        ensure!(Encoding::Hex.decode("abc").is_err() && Encoding::Hex.decode("zz").is_err());
+        ensure!(Encoding::Base64.decode("A").is_err() && Encoding::Base64.decode("A*==").is_err());
+        ensure!(Encoding::Utf8.encode(&[0xff]).is_err());
+        ensure!(json_string("a\"b\\\n\u{1}") == r#""a\"b\\\n\u0001""#);
+        ensure!(prefix_end(b"ab\xff") == Some(b"ac".to_vec()));
+        ensure!(prefix_end(b"\xff\xff").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_commands() -> Result<()> {
+        let db = create_test_dir()?;
+        for key in ["apple", "apricot", "banana", "cherry"] {
+            run_cli(&db, &["put", key, &key.to_uppercase()])?;
+        }
+        ensure!(run_cli(&db, &["get", "banana"])? == "BANANA\n");
+        ensure!(run_cli(&db, &["get", "durian"]).is_err());
+        ensure!(
+            run_cli(&db, &["--json", "get", "durian"])? == "{\"key\":\"durian\",\"value\":null}\n"
+        );
+
+        run_cli(&db, &["rm", "banana"])?;
+        ensure!(run_cli(&db, &["scan"])? == "apple\tAPPLE\napricot\tAPRICOT\ncherry\tCHERRY\n");
+        let checkpoint = db.join("checkpoint");
+        run_cli(&db, &["checkpoint", checkpoint.to_str().unwrap()])?;
+        ensure!(run_cli(&checkpoint, &["get", "cherry"])? == "CHERRY\n");
+        ensure!(run_cli(&db, &["scan", "apricot", "cherry"])? == "apricot\tAPRICOT\n");
+        ensure!(run_cli(&db, &["scan", "--prefix", "ap", "--limit", "1"])? == "apple\tAPPLE\n");
+        ensure!(
+            run_cli(
+                &db,
+                &[
+                    "--json",
+                    "--value-encoding",
+                    "hex",
+                    "scan",
+                    "--prefix",
+                    "ch"
+                ]
+            )? == "{\"key\":\"cherry\",\"value\":\"434845525259\"}\n"
+        );
+
+        // Binary pairs.
+        run_cli(&db, &["--encoding", "hex", "put", "00ff", "0102"])?;
+        ensure!(
+            run_cli(
+                &db,
+                &[
+                    "--key-encoding",
+                    "base64",
+                    "--value-encoding",
+                    "hex",
+                    "get",
+                    "AP8="
+                ]
+            )? == "0102\n"
+        );
+        ensure!(run_cli(&db, &["scan", "--prefix", "\0"]).is_err());
+
+        ensure!(run_cli(&db, &["put", "only-key"]).is_err());
+        ensure!(run_cli(&db, &["frobnicate"]).is_err());
+        ensure!(run(&["get".to_owned(), "apple".to_owned()], &mut Vec::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sst_dump_command() -> Result<()> {
+        let db = create_test_dir()?;
+        let mut store = Store::new(&db)?;
+        store.insert(b"a".to_vec(), b"1".to_vec())?;
+        store.remove(b"b")?;
+        store.flush()?;
+        let path = crate::sstable::family_dir(&db, 0).join("0").join("0");
+        let args = ["sst-dump", "--entries", path.to_str().unwrap()].map(String::from);
+        let mut out = Vec::new();
+        run(&args, &mut out)?;
+        let dump = String::from_utf8(out)?;
+        for line in [
+            "key range: a .. b\n",
+            "sparse index: 2 entries\n    a @1 at 0\n",
+            "entries:\n    a @1 value 1\n    b @2 tombstone\n",
+            "records: 2 (values 1, tombstones 1, merges 0, expiring 0)\nverified: ok\n",
+        ] {
+            ensure!(dump.contains(line), "{line:?} is missing from {dump}");
+        }
+        let args = [
+            "sst-dump",
+            "--comparator",
+            "reverse_bytewise",
+            path.to_str().unwrap(),
+        ];
+        ensure!(run(&args.map(String::from), &mut Vec::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_dump_command() -> Result<()> {
+        let db = create_test_dir()?;
+        let mut store = Store::new(&db)?;
+        store.insert(b"a".to_vec(), b"1".to_vec())?;
+        store.insert(b"c".to_vec(), b"1".to_vec())?;
+        store.flush()?;
+        store.open_column_family("users", crate::options::Options::default())?;
+        drop(store);
+
+        let dump = run_cli(&db, &["manifest-dump"])?;
+        for line in [
+            "comparator: bytewise\nlast seqno: 0\nfamily default (0):\n",
+            "batch at 0:\n    new id of 0/0\n    add 0/0/0 [a, c]\n    last seqno 2\n    commit\n",
+            "    create family users (1)\n    commit\n",
+        ] {
+            ensure!(dump.contains(line), "{line:?} is missing from {dump}");
+        }
+        ensure!(!dump.contains("torn tail"));
+
+        // An uncommitted action followed by half of another.
+        let (_, log_name) = dump.lines().next().unwrap()[9..].split_once(' ').unwrap();
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(db.join(log_name))?;
+        let action = ManifestAction::LastSeqno((1000,));
+        let encoded = bincode::encode_to_vec(&action, bincode::config::standard())?;
+        log.write_all(&encoded)?;
+        log.write_all(&encoded[..1])?;
+        drop(log);
+        let dump = run_cli(&db, &["manifest-dump"])?;
+        ensure!(dump.contains("    last seqno 1000\ntorn tail: 1 uncommitted actions"));
+        ensure!(dump.contains("torn tail: 1 undecodable bytes"));
+
+        // Recovery drops the tail, so the next commit doesn't apply it.
+        let mut store = Store::open(&db)?;
+        store.insert(b"b".to_vec(), b"1".to_vec())?;
+        store.flush()?;
+        drop(store);
+        let dump = run_cli(&db, &["manifest-dump"])?;
+        ensure!(!dump.contains("torn tail") && !dump.contains("last seqno 1000"));
+        ensure!(dump.contains("last seqno 3\n    commit\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_command() -> Result<()> {
+        let db = create_test_dir()?;
+        run_cli(&db, &["put", "a", "1"])?;
+        ensure!(run_cli(&db, &["check"])? == "ssts: 0\nlog writes: 1\n");
+        std::fs::write(db.join("MANIFEST_LOG_100"), b"")?;
+        let mut out = Vec::new();
+        let args = ["--db", db.to_str().unwrap(), "--json", "check"].map(String::from);
+        ensure!(run(&args, &mut out).is_err());
+        let report = String::from_utf8(out)?;
+        ensure!(report.starts_with("{\"ssts\":0,\"log_writes\":1,\"problems\":[\""));
+        ensure!(report.contains("MANIFEST_LOG_100\\\" isn't referenced by manifest\"]}"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_import_commands() -> Result<()> {
+        let db = create_test_dir()?;
+        for (k, v) in [("a", "1"), ("b", "2"), ("c", "3")] {
+            run_cli(&db, &["put", k, v])?;
+        }
+        ensure!(
+            run_cli(&db, &["export", "b"])?
+                == "{\"key\":\"b\",\"value\":\"2\"}\n{\"key\":\"c\",\"value\":\"3\"}\n"
+        );
+        let csv = run_cli(
+            &db,
+            &[
+                "--format",
+                "csv",
+                "--encoding",
+                "hex",
+                "export",
+                "--prefix",
+                "61",
+            ],
+        )?;
+        ensure!(csv == "key,value\n61,31\n");
+        ensure!(run_cli(&db, &["--json", "export"]).is_err());
+
+        let file = db.join("pairs.csv");
+        std::fs::write(&file, "key,value\nx,9\ny,\"8,7\"\n")?;
+        let copy = create_test_dir()?;
+        let args = [
+            "--format",
+            "csv",
+            "--batch-size",
+            "1",
+            "import",
+            file.to_str().unwrap(),
+        ];
+        ensure!(run_cli(&copy, &args)? == "imported 2 pairs, 2 ssts ingested, 0 batches written\n");
+        ensure!(run_cli(&copy, &["scan"])? == "x\t9\ny\t8,7\n");
+        ensure!(run_cli(&copy, &["import", "missing.jsonl"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bench_command() -> Result<()> {
+        let db = create_test_dir()?;
+        let report = run_cli(
+            &db,
+            &[
+                "--num",
+                "50",
+                "--threads",
+                "2",
+                "bench",
+                "fillseq",
+                "readrandom",
+            ],
+        )?;
+        let lines = report.lines().collect::<Vec<_>>();
+        ensure!(lines.len() == 6, "{report}");
+        ensure!(lines[0].starts_with("fillseq ") && lines[3].starts_with("readrandom "));
+        ensure!(
+            lines[3].ends_with("(50 of 50 found)") && lines[4].starts_with("    latency us: p50 ")
+        );
+        let json = run_cli(&db, &["--json", "--num", "50", "bench", "seekrandom"])?;
+        ensure!(json.starts_with("{\"benchmark\":\"seekrandom\",\"ops\":50,\"found\":50,"));
+        ensure!(run_cli(&db, &["bench", "fillbatch"]).is_err());
+        ensure!(run_cli(&db, &["--threads", "many", "bench"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_command() -> Result<()> {
+        let db = create_test_dir()?;
+        run_cli(&db, &["put", "a", "1"])?;
+        ensure!(run_cli(&db, &["check"]).is_ok());
+        let report = run_cli(&db, &["repair"])?;
+        ensure!(report.starts_with("ssts: 1\nflushed memtable log to 0/0/0\nquarantined "));
+        ensure!(run_cli(&db, &["check"])? == "ssts: 1\nlog writes: 0\n");
+        ensure!(run_cli(&db, &["get", "a"])? == "1\n");
+        ensure!(run_cli(&db, &["--comparator", "reverse_bytewise", "repair"]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+// Time source for expiring entries.
+// Injectable so that tests don't need to sleep.
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use chrono::Utc;
+
+pub trait Clock: Send + Sync {
+    // Milliseconds since unix epoch.
+    fn now_millis(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        Utc::now().timestamp_millis()
+    }
+}
+
+// Only moves when told to.
+#[derive(Default)]
+pub struct ManualClock {
+    now: AtomicI64,
+}
+
+impl ManualClock {
+    pub fn new(now_millis: i64) -> ManualClock {
+        ManualClock {
+            now: AtomicI64::new(now_millis),
+        }
+    }
+
+    pub fn advance_millis(&self, millis: i64) {
+        self.now.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> i64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
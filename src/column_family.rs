@@ -0,0 +1,162 @@
+// Column families are keyspaces sharing one store.
+// Each family has its own memtable, levels, bloom filter and options,
+// while all of them share the memtable log and the manifest.
+//
+// Since the log is shared, it can only be truncated when every family is flushed,
+// so memtables of all families are flushed together in a single manifest commit.
+// A write batch spanning families is applied in a single memtable commit.
+use crate::memtable::ValueUpdate;
+
+pub const DEFAULT_COLUMN_FAMILY: u32 = 0;
+pub const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+
+// Handle returned by Store::open_column_family().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnFamily {
+    id: u32,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(id: u32) -> ColumnFamily {
+        ColumnFamily { id }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+// Writes applied by Store::write(), recovered all or nothing.
+#[derive(Default)]
+pub struct WriteBatch {
+    writes: Vec<(u32, Vec<u8>, ValueUpdate)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn insert(&mut self, cf: &ColumnFamily, key: Vec<u8>, value: Vec<u8>) {
+        self.push(cf.id, key, ValueUpdate::Value(value));
+    }
+
+    pub fn remove(&mut self, cf: &ColumnFamily, key: &[u8]) {
+        self.push(cf.id, key.to_vec(), ValueUpdate::Tombstone);
+    }
+
+    // The family should have a merge operator.
+    pub fn merge(&mut self, cf: &ColumnFamily, key: Vec<u8>, operand: Vec<u8>) {
+        self.push(cf.id, key, ValueUpdate::Merge(operand));
+    }
+
+    pub(crate) fn push(&mut self, cf: u32, key: Vec<u8>, update: ValueUpdate) {
+        self.writes.push((cf, key, update));
+    }
+
+    pub(crate) fn into_writes(self) -> Vec<(u32, Vec<u8>, ValueUpdate)> {
+        self.writes
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::column_family::*;
+    use crate::merge::U64AddOperator;
+    use crate::options::Options;
+    use crate::sstable::family_dir;
+    use crate::store::Store;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    fn counter(n: u64) -> Vec<u8> {
+        n.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_column_family() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let counter_options = Options {
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..Options::default()
+        };
+        let mut store = Store::new(&test_dir)?;
+        let users = store.open_column_family("users", Options::default())?;
+        let counters = store.open_column_family("counters", counter_options.clone())?;
+        ensure!(store.column_family("users") == Some(users));
+        ensure!(store.column_family("missing").is_none());
+
+        // Keyspaces are separate.
+        store.insert(b"alice".to_vec(), b"default".to_vec())?;
+        let mut batch = WriteBatch::new();
+        batch.insert(&users, b"alice".to_vec(), b"admin".to_vec());
+        batch.merge(&counters, b"logins".to_vec(), counter(1));
+        store.write(batch)?;
+        ensure!(store.get(b"alice")? == Some(b"default".to_vec()));
+        ensure!(store.get_cf(&users, b"alice")? == Some(b"admin".to_vec()));
+        ensure!(store.get_cf(&counters, b"alice")?.is_none());
+
+        // Each family merges with its own options. A rejected batch writes nothing.
+        let mut batch = WriteBatch::new();
+        batch.insert(&users, b"bob".to_vec(), b"user".to_vec());
+        batch.merge(&users, b"alice".to_vec(), counter(1));
+        ensure!(store.write(batch).is_err());
+        ensure!(store.get_cf(&users, b"bob")?.is_none());
+
+        // Families are flushed together and compacted into their own levels.
+        for i in 0..4 {
+            store.insert_cf(&users, format!("user{i}").into_bytes(), b"user".to_vec())?;
+            store.merge_cf(&counters, b"logins".to_vec(), counter(1))?;
+            store.flush()?;
+        }
+        ensure!(family_dir(&test_dir, users.id()).join("1").exists());
+        ensure!(store.get_cf(&counters, b"logins")? == Some(counter(5)));
+        store.remove_cf(&users, b"alice")?;
+        let keys = store
+            .scan_cf(&users, None, None)?
+            .map(|kv| kv.map(|(k, _)| k))
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(
+            keys == (0..4)
+                .map(|i| format!("user{i}").into_bytes())
+                .collect::<Vec<_>>()
+        );
+
+        // Families and their writes in the shared log are recovered.
+        store.merge_cf(&counters, b"logins".to_vec(), counter(1))?;
+        drop(store);
+        let mut store = Store::recover(&test_dir)?;
+        ensure!(store.column_family("users") == Some(users));
+
+        // Without its merge operator, counters is flushed but not compacted until reopened.
+        let counters = store.column_family("counters").unwrap();
+        for i in 0..4 {
+            store.insert_cf(&counters, format!("other{i}").into_bytes(), counter(i))?;
+            store.insert(format!("key{i}").into_bytes(), b"value".to_vec())?;
+            store.flush()?;
+        }
+        store.compact()?;
+        ensure!(matches!(store.stats()?[2].levels[0], (0, 4, _)));
+        let counters = store.open_column_family("counters", counter_options)?;
+        store.compact()?;
+        ensure!(store.stats()?[2]
+            .levels
+            .iter()
+            .all(|&(level, ..)| level > 0));
+        ensure!(store.get_cf(&counters, b"logins")? == Some(counter(6)));
+        ensure!(store.get_cf(&users, b"alice")?.is_none());
+        ensure!(store.get(b"alice")? == Some(b"default".to_vec()));
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+// Order of user keys in memtables, sstables and manifest.
+// The name is persisted in manifest, and reopening a store with another comparator fails,
+// since files written in one order can't be read in another.
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::memtable::InternalKey;
+
+pub trait Comparator: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Should be a total order, and Equal only for identical keys.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    // By user key, then newer versions first.
+    fn compare_internal(&self, a: &InternalKey, b: &InternalKey) -> Ordering {
+        self.compare(&a.user_key, &b.user_key)
+            .then_with(|| b.seqno.cmp(&a.seqno))
+    }
+}
+
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "reverse_bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+// Keys are unsigned big-endian integers, e.g. u64::to_be_bytes().
+// Shorter keys are padded with zeros, so [1] < [0, 2].
+// Encodings of the same number are ordered by length.
+pub struct U64BigEndianComparator;
+
+impl Comparator for U64BigEndianComparator {
+    fn name(&self) -> &str {
+        "u64_big_endian"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        fn strip(key: &[u8]) -> &[u8] {
+            let zeros = key.iter().take_while(|&&byte| byte == 0).count();
+            &key[zeros..]
+        }
+        let (stripped_a, stripped_b) = (strip(a), strip(b));
+        stripped_a
+            .len()
+            .cmp(&stripped_b.len())
+            .then_with(|| stripped_a.cmp(stripped_b))
+            .then_with(|| a.len().cmp(&b.len()))
+    }
+}
+
+// Comparators of this module by name, e.g. the one recorded in manifest.
+pub fn builtin_comparator(name: &str) -> Option<Arc<dyn Comparator>> {
+    match name {
+        "bytewise" => Some(Arc::new(BytewiseComparator)),
+        "reverse_bytewise" => Some(Arc::new(ReverseBytewiseComparator)),
+        "u64_big_endian" => Some(Arc::new(U64BigEndianComparator)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::comparator::*;
+    use crate::options::Options;
+    use crate::sstable::family_dir;
+    use crate::store::Store;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    fn keys(store: &Store, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<Vec<Vec<u8>>> {
+        store
+            .scan(start, end)?
+            .map(|kv| kv.map(|(k, _)| k))
+            .collect()
+    }
+
+    #[test]
+    fn test_reverse_bytewise() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let options = || Options {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            ..Options::default()
+        };
+        let mut store = Store::new_with_options(&test_dir, options())?;
+        // Flushed and compacted into level 1, then some left in memtable.
+        for (i, key) in [b"a", b"c", b"e", b"b"].iter().enumerate() {
+            store.insert(key.to_vec(), vec![i as u8])?;
+            store.flush()?;
+        }
+        store.insert(b"d".to_vec(), b"d".to_vec())?;
+        ensure!(family_dir(&test_dir, 0).join("1").exists());
+        ensure!(keys(&store, None, None)? == [b"e", b"d", b"c", b"b", b"a"]);
+        ensure!(keys(&store, Some(b"d"), Some(b"a"))? == [b"d", b"c", b"b"]);
+        ensure!(store.get(b"c")? == Some(vec![1]));
+
+        // Ranges are in comparator order too.
+        ensure!(store.delete_range(b"b", b"d").is_err());
+        store.delete_range(b"d", b"b")?;
+        ensure!(keys(&store, None, None)? == [b"e", b"b", b"a"]);
+
+        // Families share the comparator.
+        ensure!(store
+            .open_column_family("bytewise", Options::default())
+            .is_err());
+        store.open_column_family("reverse", options())?;
+
+        // Files written in one order can't be read in another.
+        drop(store);
+        ensure!(Store::recover(&test_dir).is_err());
+        let store = Store::recover_with_options(&test_dir, options())?;
+        ensure!(keys(&store, None, None)? == [b"e", b"b", b"a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_u64_big_endian() -> Result<()> {
+        let cmp = U64BigEndianComparator;
+        ensure!(cmp
+            .compare(&2_u64.to_be_bytes(), &300_u64.to_be_bytes())
+            .is_lt());
+        // Encodings of different lengths are ordered by value.
+        ensure!(cmp.compare(&[2], &[1, 44]).is_lt());
+        ensure!(cmp.compare(&[0, 0, 5], &[4]).is_gt());
+        ensure!(cmp.compare(&[0, 7], &[7]).is_gt());
+        ensure!(cmp.compare(&[7], &[7]).is_eq());
+        Ok(())
+    }
+}
@@ -0,0 +1,489 @@
+// Export pairs to JSON Lines or CSV and import them back, used by `qikv export` and `qikv import`.
+// JSON Lines records are objects like {"key":"k","value":"v"}, as printed by --json,
+// and a null value deletes the key on import.
+// CSV files start with a key,value header, and fields with commas, quotes or newlines are quoted.
+// Keys and values are written with the encodings of the command line.
+use std::fs;
+use std::io::{BufRead, Write};
+
+use crate::cli::{json_string, Encoding};
+use crate::column_family::WriteBatch;
+use crate::memtable::ValueUpdate;
+use crate::sstable::{SSTable, SstId};
+use crate::store::Store;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+const CSV_HEADER: &str = "key,value";
+// Temporary table of a sorted batch, in the store directory until it's ingested.
+const IMPORT_SST_FILENAME: &str = "IMPORT_SST";
+
+// Name of a field, empty in CSV, and its text, None for JSON null.
+type Field = (String, Option<String>);
+// A key and its value, None for a deleted key.
+type Pair = (Vec<u8>, Option<Vec<u8>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jsonl,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format> {
+        match name {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(anyhow!("Unknown format {name}, expected jsonl or csv")),
+        }
+    }
+}
+
+// How pairs are written in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordFormat {
+    pub format: Format,
+    pub key_encoding: Encoding,
+    pub value_encoding: Encoding,
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_owned()
+    }
+}
+
+// Write `pairs` in `format` and return their number.
+pub fn export<I, W>(pairs: I, format: RecordFormat, out: &mut W) -> Result<u64>
+where
+    I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    W: Write,
+{
+    if format.format == Format::Csv {
+        writeln!(out, "{CSV_HEADER}")?;
+    }
+    let mut count = 0;
+    for kv in pairs {
+        let (k, v) = kv?;
+        let key = format.key_encoding.encode(&k)?;
+        let value = format.value_encoding.encode(&v)?;
+        match format.format {
+            Format::Jsonl => writeln!(
+                out,
+                "{{\"key\":{},\"value\":{}}}",
+                json_string(&key),
+                json_string(&value)
+            )?,
+            Format::Csv => writeln!(out, "{},{}", csv_field(&key), csv_field(&value))?,
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Parser of a single JSON object whose values are strings or null.
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => bail!("Expected {expected:?} but found {c:?}"),
+            None => bail!("Expected {expected:?} but found end of line"),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let hex = (0..4).filter_map(|_| self.chars.next()).collect::<String>();
+        // from_str_radix() also takes a sign.
+        ensure!(
+            hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            "Invalid escape \\u{hex}"
+        );
+        Ok(u32::from_str_radix(&hex, 16)?)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut text = String::new();
+        loop {
+            match self
+                .chars
+                .next()
+                .ok_or_else(|| anyhow!("Unterminated string"))?
+            {
+                '"' => return Ok(text),
+                '\\' => {
+                    let c = match self.chars.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let mut code = self.hex4()?;
+                            // Characters outside the basic plane are escaped as surrogate pairs.
+                            if (0xd800..0xdc00).contains(&code) {
+                                ensure!(
+                                    self.chars.next() == Some('\\')
+                                        && self.chars.next() == Some('u'),
+                                    "Unpaired surrogate \\u{code:04x}"
+                                );
+                                let low = self.hex4()?;
+                                ensure!(
+                                    (0xdc00..0xe000).contains(&low),
+                                    "Invalid low surrogate \\u{low:04x} after \\u{code:04x}"
+                                );
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code)
+                                .ok_or_else(|| anyhow!("Invalid escape \\u{code:04x}"))?
+                        }
+                        c => bail!("Invalid escape {c:?}"),
+                    };
+                    text.push(c);
+                }
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Vec<Field>> {
+        let mut fields = Vec::new();
+        self.expect('{')?;
+        self.skip_whitespace();
+        if self.chars.next_if_eq(&'}').is_none() {
+            loop {
+                let name = self.string()?;
+                self.expect(':')?;
+                self.skip_whitespace();
+                let value = if self.chars.peek() == Some(&'n') {
+                    let null = (0..4).filter_map(|_| self.chars.next()).collect::<String>();
+                    ensure!(null == "null", "Values should be strings or null");
+                    None
+                } else {
+                    Some(self.string()?)
+                };
+                fields.push((name, value));
+                self.skip_whitespace();
+                match self.chars.next() {
+                    Some(',') => {}
+                    Some('}') => break,
+                    _ => bail!(
+                        "Expected ',' or '}}' after field {:?}",
+                        fields.last().unwrap().0
+                    ),
+                }
+            }
+        }
+        self.skip_whitespace();
+        ensure!(
+            self.chars.peek().is_none(),
+            "Unexpected text after the object"
+        );
+        Ok(fields)
+    }
+}
+
+// Fields of a CSV record, or None if a quoted field goes on in the next line.
+fn parse_csv_record(text: &str) -> Result<Option<Vec<String>>> {
+    let mut fields = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Ok(None),
+                }
+            }
+            ensure!(
+                matches!(chars.peek(), None | Some(',')),
+                "Unexpected text after a quoted field"
+            );
+        } else {
+            while let Some(c) = chars.next_if(|&c| c != ',') {
+                ensure!(c != '"', "Quote in an unquoted field");
+                field.push(c);
+            }
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(Some(fields));
+        }
+    }
+}
+
+// Reads records of a file, keeping the line number for errors.
+struct RecordReader<R> {
+    input: R,
+    format: RecordFormat,
+    line: usize,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    // Text of the next line without its line break, None at the end.
+    fn read_line(&mut self) -> Result<Option<String>> {
+        let mut text = String::new();
+        if self.input.read_line(&mut text)? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        let trimmed = text.trim_end_matches('\n').trim_end_matches('\r').len();
+        text.truncate(trimmed);
+        Ok(Some(text))
+    }
+
+    fn next_fields(&mut self) -> Result<Option<Vec<Field>>> {
+        let mut text = match self.read_line()? {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        match self.format.format {
+            Format::Jsonl => {
+                if text.trim().is_empty() {
+                    return self.next_fields();
+                }
+                let mut parser = JsonParser {
+                    chars: text.chars().peekable(),
+                };
+                parser.object().map(Some)
+            }
+            Format::Csv => loop {
+                if let Some(fields) = parse_csv_record(&text)? {
+                    let fields = fields.into_iter().map(|field| (String::new(), Some(field)));
+                    return Ok(Some(fields.collect()));
+                }
+                let next = self
+                    .read_line()?
+                    .ok_or_else(|| anyhow!("Unterminated quoted field"))?;
+                text.push('\n');
+                text.push_str(&next);
+            },
+        }
+    }
+
+    // The next pair, whose value is None for a deleted key.
+    fn next_pair(&mut self) -> Result<Option<Pair>> {
+        let fields = match self.next_fields()? {
+            Some(fields) => fields,
+            None => return Ok(None),
+        };
+        let (key, value) = match self.format.format {
+            Format::Jsonl => {
+                let field = |name: &str| fields.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+                let key = field("key")
+                    .and_then(|key| key.as_ref())
+                    .ok_or_else(|| anyhow!("Missing string \"key\""))?;
+                let value = field("value").ok_or_else(|| anyhow!("Missing \"value\""))?;
+                (key.clone(), value.clone())
+            }
+            Format::Csv => {
+                ensure!(fields.len() == 2, "Expected 2 fields, got {}", fields.len());
+                let mut fields = fields.into_iter().map(|(_, field)| field.unwrap());
+                (fields.next().unwrap(), fields.next())
+            }
+        };
+        let key = self.format.key_encoding.decode(&key)?;
+        let value = value
+            .map(|v| self.format.value_encoding.decode(&v))
+            .transpose()?;
+        Ok(Some((key, value)))
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub records: u64,
+    // Ssts built from sorted batches.
+    pub ingested: Vec<SstId>,
+    // Batches applied as write batches.
+    pub written_batches: usize,
+}
+
+// Load records of `input` in batches of `batch_size`.
+// While the input is sorted with distinct keys, each batch is built into a table and ingested.
+// Otherwise batches are written like puts and deletes, so later records win.
+pub fn import<R: BufRead>(
+    store: &mut Store,
+    input: R,
+    format: RecordFormat,
+    batch_size: usize,
+) -> Result<ImportReport> {
+    ensure!(batch_size > 0, "Batch size should be positive");
+    let mut reader = RecordReader {
+        input,
+        format,
+        line: 0,
+    };
+    if format.format == Format::Csv {
+        let header = reader.read_line()?;
+        ensure!(
+            header.as_deref() == Some(CSV_HEADER),
+            "CSV should start with header {CSV_HEADER}"
+        );
+    }
+
+    let mut report = ImportReport::default();
+    let mut sorted = true;
+    let mut last_key: Option<Vec<u8>> = None;
+    loop {
+        let mut batch = Vec::new();
+        while batch.len() < batch_size {
+            let pair = reader
+                .next_pair()
+                .with_context(|| format!("Failed to import line {}", reader.line))?;
+            match pair {
+                Some(pair) => batch.push(pair),
+                None => break,
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+        report.records += batch.len() as u64;
+        let cmp = store.comparator();
+        for (key, _) in &batch {
+            if let Some(last_key) = &last_key {
+                sorted &= cmp.compare(last_key, key).is_lt();
+            }
+            last_key = Some(key.clone());
+        }
+
+        if sorted {
+            let path = store.workdir().join(IMPORT_SST_FILENAME);
+            let pairs = batch.into_iter().map(|(key, value)| match value {
+                Some(value) => (key, ValueUpdate::Value(value)),
+                None => (key, ValueUpdate::Tombstone),
+            });
+            SSTable::write_external(&path, store.shared_comparator(), pairs)?;
+            let ingested = store.ingest_external_files(std::slice::from_ref(&path));
+            fs::remove_file(&path)?;
+            report.ingested.extend(ingested?);
+        } else {
+            let cf = store.default_column_family();
+            let mut writes = WriteBatch::new();
+            for (key, value) in batch {
+                match value {
+                    Some(value) => writes.insert(&cf, key, value),
+                    None => writes.remove(&cf, &key),
+                }
+            }
+            store.write(writes)?;
+            report.written_batches += 1;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Encoding;
+    use crate::export::*;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    fn record_format(format: Format) -> RecordFormat {
+        RecordFormat {
+            format,
+            key_encoding: Encoding::Utf8,
+            value_encoding: Encoding::Utf8,
+        }
+    }
+
+    #[test]
+    fn test_export_import() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        let pairs = [("a", "1"), ("b,c", "say \"hi\"\nbye"), ("d", "\u{1}é😀")];
+        for (k, v) in pairs {
+            store.insert(k.into(), v.into())?;
+        }
+
+        for format in [Format::Jsonl, Format::Csv] {
+            let mut out = Vec::new();
+            let count = export(store.scan(None, None)?, record_format(format), &mut out)?;
+            ensure!(count == 3);
+            if format == Format::Csv {
+                ensure!(out.starts_with(b"key,value\na,1\n\"b,c\",\"say \"\"hi\"\"\nbye\"\n"));
+            }
+
+            // Sorted input is ingested in two tables.
+            let import_dir = create_test_dir()?;
+            let mut imported = Store::new(&import_dir)?;
+            let report = import(&mut imported, &out[..], record_format(format), 2)?;
+            ensure!(
+                (report.records, report.ingested.len()) == (3, 2),
+                "{report:?}"
+            );
+            ensure!(report.written_batches == 0);
+            let scanned = imported.scan(None, None)?.collect::<Result<Vec<_>>>()?;
+            ensure!(scanned == store.scan(None, None)?.collect::<Result<Vec<_>>>()?);
+            ensure!(!import_dir.join(IMPORT_SST_FILENAME).exists());
+        }
+
+        // Unsorted input is written, and later records win.
+        let input = "{\"key\":\"z\",\"value\":\"1\"}\n\n{ \"value\" : null , \"key\" : \"a\" }\n\
+                     {\"key\":\"z\",\"value\":\"\\u0032\"}\n";
+        let report = import(
+            &mut store,
+            input.as_bytes(),
+            record_format(Format::Jsonl),
+            2,
+        )?;
+        ensure!(
+            (report.records, report.written_batches) == (3, 2),
+            "{report:?}"
+        );
+        ensure!(store.get(b"a")?.is_none() && store.get(b"z")? == Some(b"2".to_vec()));
+
+        // Errors name the line.
+        let err = import(
+            &mut store,
+            "key,value\nx,1\ny\n".as_bytes(),
+            record_format(Format::Csv),
+            10,
+        )
+        .unwrap_err();
+        ensure!(format!("{err:#}") == "Failed to import line 3: Expected 2 fields, got 1");
+        ensure!(store.get(b"x")?.is_none());
+        let jsonl = record_format(Format::Jsonl);
+        ensure!(import(&mut store, "{\"key\":1}".as_bytes(), jsonl, 10).is_err());
+        for (escape, err) in [
+            (
+                "\\ud800\\u0041",
+                "Invalid low surrogate \\u0041 after \\ud800",
+            ),
+            ("\\u+041", "Invalid escape \\u+041"),
+            ("\\u00", "Invalid escape \\u00\","),
+        ] {
+            let input = format!("{{\"key\":\"{escape}\",\"value\":\"1\"}}");
+            let result = import(&mut store, input.as_bytes(), jsonl, 10);
+            ensure!(
+                format!("{:#}", result.unwrap_err()).ends_with(err),
+                "{escape}"
+            );
+        }
+        let input = "{\"key\":\"\\ud83d\\ude00\",\"value\":\"1\"}";
+        import(&mut store, input.as_bytes(), jsonl, 10)?;
+        ensure!(store.get("😀".as_bytes())? == Some(b"1".to_vec()));
+        ensure!(import(&mut store, "a,1".as_bytes(), record_format(Format::Csv), 10).is_err());
+        Ok(())
+    }
+}
@@ -8,10 +8,29 @@
 
 #![allow(unused_imports)]
 
+pub mod backup;
+pub mod bench;
+pub mod cli;
+pub mod clock;
+pub mod column_family;
+pub mod comparator;
+pub mod compaction_filter;
+pub mod export;
 pub mod memtable;
 pub mod sstable;
+pub mod lock;
 pub mod manifest;
+pub mod merge;
+pub mod options;
+pub mod repair;
+pub mod shell;
+pub mod snapshot;
+pub mod sst_dump;
 pub mod store;
+pub mod transaction;
+pub mod typed_store;
+pub mod verify;
+pub mod version;
 
 // Use custom encoding so that iterator over sstable can return references.
 // pub mod encode {
@@ -0,0 +1,157 @@
+// Exclusive locks on user keys for pessimistic transactions.
+//
+// A transaction waiting for a key adds an edge to the wait-for graph, pointing at the holder.
+// Each transaction waits for at most one key, so the graph is a map.
+// If following the edges leads back to the waiter, the waiter aborts to break the deadlock.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::transaction::TransactionError;
+
+use anyhow::Result;
+
+#[derive(Default)]
+struct LockState {
+    next_txn_id: u64,
+    holders: BTreeMap<Vec<u8>, u64>,
+    waits_for: BTreeMap<u64, u64>,
+}
+
+impl LockState {
+    // Whether waiting for `holder` makes `txn_id` wait for itself.
+    fn leads_to(&self, holder: u64, txn_id: u64) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut cur = holder;
+        while visited.insert(cur) {
+            if cur == txn_id {
+                return true;
+            }
+            match self.waits_for.get(&cur) {
+                Some(&next) => cur = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+#[derive(Default)]
+pub struct LockManager {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+impl LockManager {
+    pub fn new() -> LockManager {
+        LockManager::default()
+    }
+
+    pub fn new_txn_id(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_txn_id += 1;
+        state.next_txn_id
+    }
+
+    // Block until `key` is free or already held by `txn_id`.
+    // Fails with TransactionError::LockTimeout or TransactionError::Deadlock.
+    pub fn lock(&self, txn_id: u64, key: &[u8], timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let holder = match state.holders.get(key) {
+                Some(&holder) if holder != txn_id => holder,
+                _ => {
+                    state.holders.insert(key.to_vec(), txn_id);
+                    state.waits_for.remove(&txn_id);
+                    return Ok(());
+                }
+            };
+            if state.leads_to(holder, txn_id) {
+                state.waits_for.remove(&txn_id);
+                return Err(TransactionError::Deadlock.into());
+            }
+            state.waits_for.insert(txn_id, holder);
+
+            let now = Instant::now();
+            if now >= deadline {
+                state.waits_for.remove(&txn_id);
+                return Err(TransactionError::LockTimeout.into());
+            }
+            state = self.released.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    pub fn unlock_all(&self, txn_id: u64, keys: &BTreeSet<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            if state.holders.get(key) == Some(&txn_id) {
+                state.holders.remove(key);
+            }
+        }
+        state.waits_for.remove(&txn_id);
+        self.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::lock::*;
+    use crate::transaction::TransactionError;
+
+    use anyhow::{ensure, Result};
+
+    fn is_error(result: &Result<()>, expected: TransactionError) -> bool {
+        match result {
+            Err(err) => err.downcast_ref() == Some(&expected),
+            Ok(()) => false,
+        }
+    }
+
+    #[test]
+    fn test_lock_timeout() -> Result<()> {
+        let locks = LockManager::new();
+        let (txn1, txn2) = (locks.new_txn_id(), locks.new_txn_id());
+        locks.lock(txn1, b"a", Duration::ZERO)?;
+        // Reentrant.
+        locks.lock(txn1, b"a", Duration::ZERO)?;
+        ensure!(is_error(
+            &locks.lock(txn2, b"a", Duration::from_millis(10)),
+            TransactionError::LockTimeout
+        ));
+        locks.unlock_all(txn1, &BTreeSet::from([b"a".to_vec()]));
+        locks.lock(txn2, b"a", Duration::ZERO)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_deadlock() -> Result<()> {
+        let locks = Arc::new(LockManager::new());
+        let (txn1, txn2) = (locks.new_txn_id(), locks.new_txn_id());
+        locks.lock(txn1, b"a", Duration::ZERO)?;
+        locks.lock(txn2, b"b", Duration::ZERO)?;
+
+        // txn1 waits for txn2 in another thread.
+        let handle = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(txn1, b"b", Duration::from_secs(10)))
+        };
+        while !locks.state.lock().unwrap().waits_for.contains_key(&txn1) {
+            thread::yield_now();
+        }
+
+        // txn2 closes the cycle and is chosen as the victim.
+        ensure!(is_error(
+            &locks.lock(txn2, b"a", Duration::from_secs(10)),
+            TransactionError::Deadlock
+        ));
+        locks.unlock_all(txn2, &BTreeSet::from([b"b".to_vec()]));
+        handle.join().unwrap()?;
+        Ok(())
+    }
+}
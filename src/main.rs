@@ -1,3 +1,13 @@
+use std::io::Write;
+use std::process;
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if let Err(err) = qikv::cli::run(&args, &mut out) {
+        out.flush().ok();
+        eprintln!("Error: {err:#}");
+        process::exit(1);
+    }
 }
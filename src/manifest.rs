@@ -12,22 +12,30 @@ use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::column_family::{DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME};
+use crate::comparator::Comparator;
 use crate::sstable::*;
+use crate::version::{Version, VersionSet};
 // use crate::memtable::MemTable;
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use bincode::{Decode, Encode};
 //
-const MANIFEST_CURRENT: &str = "MANIFEST_CURRENT";
-const MANIFEST_SNAPSHOT_PREFIX: &str = "MANIFEST_SNAPSHOT";
-const MANIFEST_LOG_PREFIX: &str = "MANIFEST_LOG";
+pub(crate) const MANIFEST_CURRENT: &str = "MANIFEST_CURRENT";
+pub(crate) const MANIFEST_SNAPSHOT_PREFIX: &str = "MANIFEST_SNAPSHOT";
+pub(crate) const MANIFEST_LOG_PREFIX: &str = "MANIFEST_LOG";
 
 pub struct ManifestKeeper {
     manifest: Manifest,
     log: File,
     batch: VecDeque<ManifestAction>,
+    versions: VersionSet,
+    comparator: Arc<dyn Comparator>,
     store_dir: PathBuf,
+    // Bytes of ssts added since opened.
+    sst_bytes_written: u64,
 }
 
 impl Deref for ManifestKeeper {
@@ -49,12 +57,24 @@ pub enum ManifestAction {
     Commit,
     Add((SstId, Vec<u8>, Vec<u8>)),
     Remove((SstId,)),
-    NewId((u64,)),
-    NextCompact((u64,)),
+    NewId((u32, u64)),       // (cf, level)
+    NextCompact((u32, u64)), // (cf, level)
+    LastSeqno((u64,)),
+    CreateFamily((u32, String)),
 }
 
 impl ManifestKeeper {
-    pub fn new(store_dir: &Path) -> Result<ManifestKeeper> {
+    pub fn new(store_dir: &Path, comparator: Arc<dyn Comparator>) -> Result<ManifestKeeper> {
+        let manifest = Manifest::new(comparator.name());
+        Self::create(store_dir, manifest, comparator)
+    }
+
+    // Start new manifest files from `manifest`, replacing any current ones.
+    pub(crate) fn create(
+        store_dir: &Path,
+        manifest: Manifest,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<ManifestKeeper> {
         let init_current =
             MANIFEST_SNAPSHOT_PREFIX.to_owned() + "_0" + "\n" + MANIFEST_LOG_PREFIX + "_0";
         fs::write(store_dir.join(MANIFEST_CURRENT), init_current)?;
@@ -69,10 +89,13 @@ impl ManifestKeeper {
             .create(true)
             .open(store_dir.join(MANIFEST_LOG_PREFIX.to_owned() + "_0"))?;
         let mut keeper = ManifestKeeper {
-            manifest: Manifest::new(),
+            versions: VersionSet::new(&manifest, store_dir),
+            manifest,
             log: log_file,
             batch: VecDeque::new(),
+            comparator,
             store_dir: store_dir.to_path_buf(),
+            sst_bytes_written: 0,
         };
         keeper.snapshot(store_dir)?;
         Ok(keeper)
@@ -135,94 +158,106 @@ impl ManifestKeeper {
         Ok(())
     }
 
-    pub fn recover(store_dir: &Path) -> Result<ManifestKeeper> {
+    // Whether `store_dir` has a manifest to recover from.
+    pub fn exists(store_dir: &Path) -> bool {
+        store_dir.join(MANIFEST_CURRENT).exists()
+    }
+
+    // `comparator` should be the one the store is created with.
+    pub fn recover(store_dir: &Path, comparator: Arc<dyn Comparator>) -> Result<ManifestKeeper> {
         // Load snapshot and then replay log.
-        // String read has leading \0 bytes and I don't know why.
-        // Just trim it now.
-        let current = fs::read_to_string(store_dir.join(MANIFEST_CURRENT))?;
-        let names: Vec<_> = current.trim_matches('\0').split_whitespace().collect();
-        let mut snapshot_file = File::open(store_dir.join(names[0]))?;
+        let (snapshot_name, log_name) = read_current(store_dir)?;
+        let mut snapshot_file = File::open(store_dir.join(snapshot_name))?;
         let mut manifest: Manifest =
             bincode::decode_from_std_read(&mut snapshot_file, bincode::config::standard())?;
+        ensure!(
+            manifest.comparator == comparator.name(),
+            "Store is created with comparator {}, but opened with {}",
+            manifest.comparator,
+            comparator.name()
+        );
         let mut log_file = File::options()
             .read(true)
             .write(true)
-            .open(store_dir.join(names[1]))?;
+            .open(store_dir.join(log_name))?;
 
         let mut buf = Vec::new();
         log_file.read_to_end(&mut buf)?; // Now seek to end.
 
-        let mut cur = 0;
+        let (actions, end) = decode_log(&buf);
+        let ends = actions
+            .iter()
+            .skip(1)
+            .map(|(offset, _)| *offset)
+            .chain([end])
+            .collect::<Vec<_>>();
         let mut batch = VecDeque::new();
-        while cur < buf.len() {
-            if let Ok((action, size)) =
-                bincode::decode_from_slice(&buf[cur..], bincode::config::standard())
-            {
-                cur += size;
-                match action {
-                    ManifestAction::Commit => {
-                        while let Some(action) = batch.pop_front() {
-                            manifest.execute_action(action);
-                        }
+        // Offset after the last Commit.
+        let mut committed = 0;
+        for ((_, action), action_end) in actions.into_iter().zip(ends) {
+            match action {
+                ManifestAction::Commit => {
+                    while let Some(action) = batch.pop_front() {
+                        manifest.execute_action(action, comparator.as_ref());
                     }
-                    _ => batch.push_back(action),
+                    committed = action_end;
                 }
-            } else {
-                // Half written batch.
-                // Abandon them.
-                log_file.set_len(cur as u64)?;
-                break;
+                _ => batch.push_back(action),
             }
         }
+        if committed < buf.len() {
+            // Half written batch, decodable or not.
+            // Abandon it, or the next Commit would apply it.
+            log_file.set_len(committed as u64)?;
+            log_file.seek(SeekFrom::Start(committed as u64))?;
+        }
 
         // Now we have a consistent manifest.
-        // Clean up obsolete SST files.
+        // Clean up obsolete SST files, e.g. those still pinned by some version when crashed,
+        // and directories of families whose creation isn't committed.
         // Ignore non-utf8 path and non-numeric path.
-        fs::create_dir_all(store_dir.join(SSTABLE_DIR))?;
-        for entry in fs::read_dir(store_dir.join(SSTABLE_DIR))? {
+        fs::create_dir_all(family_dir(store_dir, DEFAULT_COLUMN_FAMILY))?;
+        for entry in fs::read_dir(store_dir)? {
             let entry = entry?;
             let path = entry.path();
             if path.is_dir() {
-                // Waiting for stablization of let chains.
-                if let Some(path_str) = path.to_str() {
-                    if let Ok(level) = path_str.parse::<u64>() {
-                        match manifest.active_ssts.get(&level) {
-                            Some(ids) => {
-                                for sst in fs::read_dir(
-                                    store_dir.join(SSTABLE_DIR).join(level.to_string()),
-                                )? {
-                                    let sst = sst?;
-                                    let sst_path = sst.path();
-                                    if sst_path.is_file() {
-                                        if let Some(sst_path_str) = sst_path.to_str() {
-                                            if let Ok(id) = sst_path_str.parse::<u64>() {
-                                                if !ids.contains(&id) {
-                                                    fs::remove_file(sst_path)?;
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            None => {
-                                fs::remove_dir_all(path)?;
-                            }
-                        }
+                if let Some(cf) = entry.file_name().to_str().and_then(parse_family_dir) {
+                    match manifest.families.get(&cf) {
+                        Some(family) => family.remove_orphans(&path)?,
+                        None => fs::remove_dir_all(path)?,
                     }
                 }
             }
         }
 
         Ok(ManifestKeeper {
+            versions: VersionSet::new(&manifest, store_dir),
             manifest,
             log: log_file,
             batch: VecDeque::new(),
+            comparator,
             store_dir: store_dir.to_path_buf(),
+            sst_bytes_written: 0,
         })
     }
 
-    pub fn next_compact(&mut self, level: u64) {
-        self.batch.push_back(ManifestAction::NextCompact((level,)));
+    pub fn comparator(&self) -> &Arc<dyn Comparator> {
+        &self.comparator
+    }
+
+    // Bytes of ssts added by flushes, compactions and ingestion since opened.
+    pub fn sst_bytes_written(&self) -> u64 {
+        self.sst_bytes_written
+    }
+
+    // Pin the latest committed manifest.
+    pub fn current_version(&self) -> Arc<Version> {
+        self.versions.current()
+    }
+
+    pub fn next_compact(&mut self, cf: u32, level: u64) {
+        self.batch
+            .push_back(ManifestAction::NextCompact((cf, level)));
     }
 
     pub fn add(&mut self, sst_id: SstId, first_key: &[u8], last_key: &[u8]) {
@@ -237,8 +272,18 @@ impl ManifestKeeper {
         self.batch.push_back(ManifestAction::Remove((*sst_id,)));
     }
 
-    pub fn new_id(&mut self, level: u64) {
-        self.batch.push_back(ManifestAction::NewId((level,)));
+    pub fn new_id(&mut self, cf: u32, level: u64) {
+        self.batch.push_back(ManifestAction::NewId((cf, level)));
+    }
+
+    pub fn create_family(&mut self, cf: u32, name: &str) {
+        self.batch
+            .push_back(ManifestAction::CreateFamily((cf, name.to_owned())));
+    }
+
+    // Record that writes up to `seqno` are persisted in ssts.
+    pub fn set_last_seqno(&mut self, seqno: u64) {
+        self.batch.push_back(ManifestAction::LastSeqno((seqno,)));
     }
 
     pub fn batch_start(&mut self) {
@@ -261,36 +306,258 @@ impl ManifestKeeper {
         self.log.sync_all()?;
 
         // Apply changes to in-memory manifest.
+        // Removed ssts are deleted once no version refers to them.
         while let Some(action) = self.batch.pop_front() {
-            if let ManifestAction::Remove((sst_id,)) = action {
-                match SSTable::remove(&self.store_dir, &sst_id) {
-                    Ok(()) => {},
-                    Err(err) => { eprintln!("Failed to remove SST file {sst_id:#?}: {err}"); },
+            match &action {
+                ManifestAction::Add((sst_id, ..)) => {
+                    // The log is already written, so a counter can't fail the commit.
+                    let metadata = fs::metadata(sst_id.path(&self.store_dir));
+                    self.sst_bytes_written += metadata.map_or(0, |m| m.len());
                 }
+                ManifestAction::Remove((sst_id,)) => self.versions.retire(sst_id),
+                _ => {}
             }
-            self.manifest.execute_action(action);
+            self.manifest
+                .execute_action(action, self.comparator.as_ref());
         }
+        self.versions.install(&self.manifest);
         Ok(())
     }
 }
 
-#[derive(Encode, Decode, PartialEq, Eq)]
+// Names of the current snapshot and log files.
+fn read_current(store_dir: &Path) -> Result<(String, String)> {
+    // String read has leading \0 bytes and I don't know why.
+    // Just trim it now.
+    let current = fs::read_to_string(store_dir.join(MANIFEST_CURRENT))?;
+    let names: Vec<_> = current.trim_matches('\0').split_whitespace().collect();
+    ensure!(
+        names.len() == 2,
+        "{MANIFEST_CURRENT} is malformed: {current:?}"
+    );
+    Ok((names[0].to_owned(), names[1].to_owned()))
+}
+
+// Decode actions of a log with their offsets, up to the first one that doesn't decode.
+// Return them and the offset where decoding stopped.
+fn decode_log(buf: &[u8]) -> (Vec<(usize, ManifestAction)>, usize) {
+    let mut actions = Vec::new();
+    let mut cur = 0;
+    while cur < buf.len() {
+        match bincode::decode_from_slice(&buf[cur..], bincode::config::standard()) {
+            Ok((action, size)) => {
+                actions.push((cur, action));
+                cur += size;
+            }
+            Err(_) => break,
+        }
+    }
+    (actions, cur)
+}
+
+// Manifest files of a store as they are on disk, read without recovering them,
+// which would truncate a torn log.
+pub struct ManifestDump {
+    pub snapshot_name: String,
+    pub log_name: String,
+    pub snapshot: Manifest,
+    // Actions in the log with their offsets. A batch ends with Commit.
+    pub actions: Vec<(usize, ManifestAction)>,
+    pub log_size: usize,
+    // Offset of bytes after the last decodable action. Equals log_size if there are none.
+    pub decoded_size: usize,
+}
+
+impl ManifestDump {
+    pub fn read(store_dir: &Path) -> Result<ManifestDump> {
+        let (snapshot_name, log_name) = read_current(store_dir)?;
+        let snapshot = bincode::decode_from_slice(
+            &fs::read(store_dir.join(&snapshot_name))?,
+            bincode::config::standard(),
+        )?
+        .0;
+        let buf = fs::read(store_dir.join(&log_name))?;
+        let (actions, decoded_size) = decode_log(&buf);
+        Ok(ManifestDump {
+            snapshot_name,
+            log_name,
+            snapshot,
+            actions,
+            log_size: buf.len(),
+            decoded_size,
+        })
+    }
+
+    // The manifest recovery would build, from the snapshot and committed actions.
+    pub fn replay(&self, cmp: &dyn Comparator) -> Manifest {
+        let mut manifest = self.snapshot.clone();
+        let committed = self.actions.len() - self.uncommitted().len();
+        for (_, action) in &self.actions[..committed] {
+            manifest.execute_action(action.clone(), cmp);
+        }
+        manifest
+    }
+
+    // Actions after the last Commit, which recovery discards.
+    pub fn uncommitted(&self) -> &[(usize, ManifestAction)] {
+        let committed = self
+            .actions
+            .iter()
+            .rposition(|(_, action)| *action == ManifestAction::Commit)
+            .map_or(0, |i| i + 1);
+        &self.actions[committed..]
+    }
+}
+
+#[derive(Encode, Decode, PartialEq, Eq, Clone)]
 pub struct Manifest {
+    families: BTreeMap<u32, FamilyManifest>,
+    family_ids: BTreeMap<String, u32>,
+    last_seqno: u64,    // largest seqno persisted in ssts.
+    comparator: String, // Name of the comparator ordering keys of all families.
+}
+
+impl Manifest {
+    pub fn new(comparator: &str) -> Manifest {
+        let mut manifest = Manifest {
+            families: BTreeMap::new(),
+            family_ids: BTreeMap::new(),
+            last_seqno: 0,
+            comparator: comparator.to_owned(),
+        };
+        manifest.create_family(DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME);
+        manifest
+    }
+
+    pub fn last_seqno(&self) -> u64 {
+        self.last_seqno
+    }
+
+    pub fn comparator_name(&self) -> &str {
+        &self.comparator
+    }
+
+    // `cf` should exist.
+    pub fn family(&self, cf: u32) -> &FamilyManifest {
+        self.families
+            .get(&cf)
+            .expect("The column family should exist")
+    }
+
+    fn family_mut(&mut self, cf: u32) -> &mut FamilyManifest {
+        self.families
+            .get_mut(&cf)
+            .expect("The column family should exist")
+    }
+
+    pub fn family_id(&self, name: &str) -> Option<u32> {
+        self.family_ids.get(name).copied()
+    }
+
+    // (name, cf) of every family.
+    pub fn family_names(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.family_ids.iter().map(|(name, &cf)| (&name[..], cf))
+    }
+
+    pub fn next_family_id(&self) -> u32 {
+        match self.families.last_key_value() {
+            Some((&cf, _)) => cf + 1,
+            None => DEFAULT_COLUMN_FAMILY,
+        }
+    }
+
+    fn create_family(&mut self, cf: u32, name: &str) {
+        self.families.insert(cf, FamilyManifest::new(cf));
+        self.family_ids.insert(name.to_owned(), cf);
+    }
+
+    // Used by repair to rebuild a manifest from the files of a store.
+    pub(crate) fn restore_family(&mut self, cf: u32, name: &str) {
+        if !self.families.contains_key(&cf) {
+            self.create_family(cf, name);
+        }
+    }
+
+    // Also makes later ssts of the level get larger ids. The family should be restored.
+    pub(crate) fn restore_sst(
+        &mut self,
+        sst_id: SstId,
+        first_key: &[u8],
+        last_key: &[u8],
+        cmp: &dyn Comparator,
+    ) {
+        let family = self.family_mut(sst_id.cf);
+        family.add_sst(sst_id, first_key, last_key, cmp);
+        let new_id = family.new_ids.entry(sst_id.level).or_default();
+        *new_id = u64::max(*new_id, sst_id.id + 1);
+    }
+
+    // Active ssts of all families.
+    pub fn active_sst_ids(&self) -> Vec<SstId> {
+        self.families
+            .values()
+            .flat_map(|family| family.active_sst_ids())
+            .collect()
+    }
+
+    pub fn execute_action(&mut self, action: ManifestAction, cmp: &dyn Comparator) {
+        match action {
+            ManifestAction::Commit => {}
+            ManifestAction::NextCompact((cf, level)) => {
+                self.family_mut(cf).next_compact_sst(level, cmp);
+            }
+            ManifestAction::Add((sst_id, first_key, last_key)) => {
+                self.family_mut(sst_id.cf)
+                    .add_sst(sst_id, &first_key, &last_key, cmp);
+            }
+            ManifestAction::Remove((sst_id,)) => {
+                self.family_mut(sst_id.cf).remove_sst(&sst_id);
+            }
+            ManifestAction::NewId((cf, level)) => {
+                self.family_mut(cf).new_sst_id(level);
+            }
+            ManifestAction::LastSeqno((seqno,)) => {
+                self.last_seqno = u64::max(self.last_seqno, seqno);
+            }
+            ManifestAction::CreateFamily((cf, name)) => {
+                self.create_family(cf, &name);
+            }
+        }
+    }
+
+    pub fn sort(&self, sst_ids: &[SstId], cmp: &dyn Comparator) -> Vec<SstId> {
+        let mut metas: Vec<_> = sst_ids
+            .iter()
+            .map(|sst_id| {
+                let (first_key, last_key) = self.family(sst_id.cf).sst_ranges.get(sst_id).unwrap();
+                let meta = SSTMetadata {
+                    level: sst_id.level,
+                    id: sst_id.id,
+                    first_key,
+                    last_key,
+                };
+                (meta, *sst_id)
+            })
+            .collect();
+        metas.sort_by(|a, b| a.0.compare(&b.0, cmp));
+        metas.into_iter().map(|(_, sst_id)| sst_id).collect()
+    }
+}
+
+// Levels of a column family.
+#[derive(Encode, Decode, PartialEq, Eq, Clone)]
+pub struct FamilyManifest {
+    cf: u32,
     new_ids: BTreeMap<u64, u64>,          // largest ids for each level.
     compact_keys: BTreeMap<u64, Vec<u8>>, // next compact key in each level.
     active_ssts: BTreeMap<u64, BTreeSet<u64>>,
     sst_ranges: BTreeMap<SstId, (Vec<u8>, Vec<u8>)>,
 }
 
-impl Default for Manifest {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl Manifest {
-    pub fn new() -> Manifest {
-        Manifest {
+impl FamilyManifest {
+    fn new(cf: u32) -> FamilyManifest {
+        FamilyManifest {
+            cf,
             new_ids: BTreeMap::new(),
             compact_keys: BTreeMap::new(),
             active_ssts: BTreeMap::new(),
@@ -298,6 +565,69 @@ impl Manifest {
         }
     }
 
+    fn sst_id(&self, level: u64, id: u64) -> SstId {
+        SstId {
+            cf: self.cf,
+            level,
+            id,
+        }
+    }
+
+    // Remove files in `dir` that are not active ssts of the family.
+    fn remove_orphans(&self, dir: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.is_dir() {
+                // Waiting for stablization of let chains.
+                if let Some(path_str) = entry.file_name().to_str() {
+                    if let Ok(level) = path_str.parse::<u64>() {
+                        match self.active_ssts.get(&level) {
+                            Some(ids) => {
+                                for sst in fs::read_dir(&path)? {
+                                    let sst = sst?;
+                                    let sst_path = sst.path();
+                                    if sst_path.is_file() {
+                                        if let Some(sst_path_str) = sst.file_name().to_str() {
+                                            if let Ok(id) = sst_path_str.parse::<u64>() {
+                                                if !ids.contains(&id) {
+                                                    fs::remove_file(sst_path)?;
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            None => {
+                                fs::remove_dir_all(path)?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn cf(&self) -> u32 {
+        self.cf
+    }
+
+    // Largest id of each level.
+    pub fn new_ids(&self) -> &BTreeMap<u64, u64> {
+        &self.new_ids
+    }
+
+    // Key where the next compaction of each level starts.
+    pub fn compact_keys(&self) -> &BTreeMap<u64, Vec<u8>> {
+        &self.compact_keys
+    }
+
+    // First and last user keys of an active sst.
+    pub fn sst_range(&self, sst_id: &SstId) -> Option<&(Vec<u8>, Vec<u8>)> {
+        self.sst_ranges.get(sst_id)
+    }
+
     pub fn max_level(&self) -> u64 {
         if let Some((&level, _)) = self.active_ssts.last_key_value() {
             level
@@ -307,23 +637,26 @@ impl Manifest {
     }
 
     // level is always <= max_level.
-    fn get_sst_by_key_start(&self, level: u64, key: &Vec<u8>) -> SstId {
+    fn get_sst_by_key_start(&self, level: u64, key: &[u8], cmp: &dyn Comparator) -> SstId {
         // Get all ssts in the level.
         // Sort them by key_start.
         // Find the one with key_start <= key < next key_start.
         let mut ids = self.get_sst_by_level(level);
         ids.sort_unstable_by(|a, b| {
-            self.sst_ranges
-                .get(a)
-                .unwrap()
-                .0
-                .cmp(&self.sst_ranges.get(b).unwrap().0)
+            cmp.compare(
+                &self.sst_ranges.get(a).unwrap().0,
+                &self.sst_ranges.get(b).unwrap().0,
+            )
         });
         let mut iter = ids.iter().peekable();
         while let Some(id) = iter.next() {
             if let Some(next_id) = iter.peek() {
-                if &self.sst_ranges.get(id).unwrap().0 <= key
-                    && &self.sst_ranges.get(next_id).unwrap().0 > key
+                if cmp
+                    .compare(&self.sst_ranges.get(id).unwrap().0, key)
+                    .is_le()
+                    && cmp
+                        .compare(&self.sst_ranges.get(next_id).unwrap().0, key)
+                        .is_gt()
                 {
                     return *id;
                 }
@@ -335,20 +668,23 @@ impl Manifest {
     }
 
     // level is always <= max_level.
-    fn next_sst_start(&self, level: u64, key: &Vec<u8>) -> Vec<u8> {
+    fn next_sst_start(&self, level: u64, key: &[u8], cmp: &dyn Comparator) -> Vec<u8> {
         let mut ids = self.get_sst_by_level(level);
         ids.sort_unstable_by(|a, b| {
-            self.sst_ranges
-                .get(a)
-                .unwrap()
-                .0
-                .cmp(&self.sst_ranges.get(b).unwrap().0)
+            cmp.compare(
+                &self.sst_ranges.get(a).unwrap().0,
+                &self.sst_ranges.get(b).unwrap().0,
+            )
         });
         let mut iter = ids.iter().peekable();
         while let Some(id) = iter.next() {
             if let Some(next_id) = iter.peek() {
-                if &self.sst_ranges.get(id).unwrap().0 <= key
-                    && &self.sst_ranges.get(next_id).unwrap().0 > key
+                if cmp
+                    .compare(&self.sst_ranges.get(id).unwrap().0, key)
+                    .is_le()
+                    && cmp
+                        .compare(&self.sst_ranges.get(next_id).unwrap().0, key)
+                        .is_gt()
                 {
                     return self.sst_ranges.get(next_id).unwrap().0.clone();
                 }
@@ -359,23 +695,23 @@ impl Manifest {
         unreachable!()
     }
 
-    pub fn latest_compact_sst(&self, level: u64) -> SstId {
+    pub fn latest_compact_sst(&self, level: u64, cmp: &dyn Comparator) -> SstId {
         assert!(level <= self.max_level());
         let latest_start = match self.compact_keys.get(&level) {
             Some(key) => key,
             None => &self.sst_ranges.first_key_value().unwrap().1 .0,
         };
-        self.get_sst_by_key_start(level, latest_start)
+        self.get_sst_by_key_start(level, latest_start, cmp)
     }
 
-    pub fn next_compact_sst(&mut self, level: u64) -> SstId {
+    pub fn next_compact_sst(&mut self, level: u64, cmp: &dyn Comparator) -> SstId {
         assert!(level <= self.max_level());
         let next_start = match self.compact_keys.get(&level) {
-            Some(key) => self.next_sst_start(level, key),
+            Some(key) => self.next_sst_start(level, key, cmp),
             None => self.sst_ranges.first_key_value().unwrap().1 .0.clone(),
         };
         self.compact_keys.insert(level, next_start.clone());
-        self.get_sst_by_key_start(level, &next_start)
+        self.get_sst_by_key_start(level, &next_start, cmp)
 
         // let compact_key = self.compact_keys.entry(level)
         // .and_modify(|key| *key = self.next_sst_start(level, key))
@@ -385,13 +721,19 @@ impl Manifest {
 
     pub fn get_sst_by_level(&self, level: u64) -> Vec<SstId> {
         match self.active_ssts.get(&level) {
-            Some(ids) => ids.iter().map(|id| SstId { level, id: *id }).collect(),
+            Some(ids) => ids.iter().map(|id| self.sst_id(level, *id)).collect(),
             None => Vec::new(),
         }
     }
 
-    pub fn add_sst(&mut self, sst_id: SstId, first_key: &[u8], last_key: &[u8]) {
-        assert!(first_key <= last_key);
+    pub fn add_sst(
+        &mut self,
+        sst_id: SstId,
+        first_key: &[u8],
+        last_key: &[u8],
+        cmp: &dyn Comparator,
+    ) {
+        assert!(cmp.compare(first_key, last_key).is_le());
         self.active_ssts
             .entry(sst_id.level)
             .or_default()
@@ -414,18 +756,37 @@ impl Manifest {
             ids.append(
                 &mut v
                     .iter()
-                    .map(|id| SstId { level: *k, id: *id })
+                    .map(|id| self.sst_id(*k, *id))
                     .collect::<Vec<SstId>>(),
             );
         }
         ids
     }
 
-    pub fn get_sst_by_key(&self, key: &[u8]) -> Vec<SstId> {
+    pub fn get_sst_by_key(&self, key: &[u8], cmp: &dyn Comparator) -> Vec<SstId> {
         let mut ssts = Vec::new();
         for id in self.active_sst_ids() {
             let (start, end) = self.sst_ranges.get(&id).unwrap();
-            if key >= start && key <= end {
+            if cmp.compare(key, start).is_ge() && cmp.compare(key, end).is_le() {
+                ssts.push(id);
+            }
+        }
+        ssts
+    }
+
+    // Get ssts overlapping with [start, end). None means unbounded.
+    pub fn get_sst_by_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        cmp: &dyn Comparator,
+    ) -> Vec<SstId> {
+        let mut ssts = Vec::new();
+        for id in self.active_sst_ids() {
+            let (first, last) = self.sst_ranges.get(&id).unwrap();
+            if start.map_or(true, |start| cmp.compare(last, start).is_ge())
+                && end.map_or(true, |end| cmp.compare(first, end).is_lt())
+            {
                 ssts.push(id);
             }
         }
@@ -435,14 +796,7 @@ impl Manifest {
     pub fn level_byte_size(&self, level: u64, db_dir: &Path) -> Result<u64> {
         if let Some(ids) = self.active_ssts.get(&level) {
             ids.iter()
-                .map(|id| {
-                    Ok(db_dir
-                        .join(SSTABLE_DIR)
-                        .join(level.to_string())
-                        .join(id.to_string())
-                        .metadata()?
-                        .len())
-                })
+                .map(|id| Ok(self.sst_id(level, *id).path(db_dir).metadata()?.len()))
                 .sum::<Result<u64, _>>()
         } else {
             Ok(0)
@@ -450,18 +804,18 @@ impl Manifest {
     }
 
     // Get ssts in the next level that overlap with `id`.
-    pub fn get_overlappings(&self, id: &SstId) -> Vec<SstId> {
+    pub fn get_overlappings(&self, id: &SstId, cmp: &dyn Comparator) -> Vec<SstId> {
         let level = id.level + 1;
         let mut overlappings = Vec::new();
         if let Some((start, end)) = self.sst_ranges.get(id) {
             if let Some(ids) = self.active_ssts.get(&level) {
                 for id in ids {
-                    let sst_id = SstId { level, id: *id };
+                    let sst_id = self.sst_id(level, *id);
                     let (s1, e1) = self
                         .sst_ranges
                         .get(&sst_id)
                         .expect("The range should exist");
-                    if end >= s1 && start <= e1 {
+                    if cmp.compare(end, s1).is_ge() && cmp.compare(start, e1).is_le() {
                         overlappings.push(sst_id);
                     }
                 }
@@ -470,10 +824,24 @@ impl Manifest {
         overlappings
     }
 
+    // Whether any active sst in `level` overlaps with [start, end].
+    pub fn level_overlaps(
+        &self,
+        level: u64,
+        start: &[u8],
+        end: &[u8],
+        cmp: &dyn Comparator,
+    ) -> bool {
+        self.get_sst_by_level(level).iter().any(|id| {
+            let (s1, e1) = self.sst_ranges.get(id).expect("The range should exist");
+            cmp.compare(end, s1).is_ge() && cmp.compare(start, e1).is_le()
+        })
+    }
+
     pub fn latest_sst_id(&self, level: u64) -> SstId {
         match self.new_ids.get(&level) {
-            Some(&id) => SstId { level, id },
-            None => SstId { level, id: 0 },
+            Some(&id) => self.sst_id(level, id),
+            None => self.sst_id(level, 0),
         }
     }
 
@@ -483,50 +851,14 @@ impl Manifest {
             .entry(level)
             .and_modify(|i| *i += 1)
             .or_insert(1);
-        SstId { level, id: *id }
-    }
-
-    pub fn execute_action(&mut self, action: ManifestAction) {
-        match action {
-            ManifestAction::Commit => {}
-            ManifestAction::NextCompact((level,)) => {
-                self.next_compact_sst(level);
-            }
-            ManifestAction::Add((sst_id, first_key, last_key)) => {
-                self.add_sst(sst_id, &first_key, &last_key);
-            }
-            ManifestAction::Remove((sst_id,)) => {
-                self.remove_sst(&sst_id);
-            }
-            ManifestAction::NewId((level,)) => {
-                self.new_sst_id(level);
-            }
-        }
-    }
-
-    pub fn sort(&self, sst_ids: &[SstId]) -> Vec<SstId> {
-        let mut metas: Vec<_> = sst_ids
-            .iter()
-            .map(|sst_id| SSTMetadata {
-                level: sst_id.level,
-                id: sst_id.id,
-                first_key: &self.sst_ranges.get(sst_id).unwrap().0,
-                last_key: &self.sst_ranges.get(sst_id).unwrap().1,
-            })
-            .collect();
-        metas.sort();
-        metas
-            .iter()
-            .map(|m| SstId {
-                level: m.level,
-                id: m.id,
-            })
-            .collect()
+        let id = *id;
+        self.sst_id(level, id)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::comparator::BytewiseComparator;
     use crate::manifest::*;
     use crate::test_util::*;
     use anyhow::{ensure, Result};
@@ -549,48 +881,72 @@ mod tests {
         // Imitate common cases.
         let mut actions = Vec::new();
         // Add 4 level 0 sst.
-        actions.push(ManifestAction::NewId((0,)));
+        actions.push(ManifestAction::NewId((0, 0)));
         let key_range = get_random_key_range(16, 17);
         actions.push(ManifestAction::Add((
-            SstId { level: 0, id: 0 },
+            SstId {
+                cf: 0,
+                level: 0,
+                id: 0,
+            },
             key_range.0,
             key_range.1,
         )));
 
-        actions.push(ManifestAction::NewId((0,)));
+        actions.push(ManifestAction::NewId((0, 0)));
         let key_range = get_random_key_range(16, 17);
         actions.push(ManifestAction::Add((
-            SstId { level: 0, id: 1 },
+            SstId {
+                cf: 0,
+                level: 0,
+                id: 1,
+            },
             key_range.0,
             key_range.1,
         )));
 
-        actions.push(ManifestAction::NewId((0,)));
+        actions.push(ManifestAction::NewId((0, 0)));
         let key_range = get_random_key_range(16, 17);
         actions.push(ManifestAction::Add((
-            SstId { level: 0, id: 2 },
+            SstId {
+                cf: 0,
+                level: 0,
+                id: 2,
+            },
             key_range.0,
             key_range.1,
         )));
 
-        actions.push(ManifestAction::NewId((0,)));
+        actions.push(ManifestAction::NewId((0, 0)));
         let key_range = get_random_key_range(16, 17);
         actions.push(ManifestAction::Add((
-            SstId { level: 0, id: 3 },
+            SstId {
+                cf: 0,
+                level: 0,
+                id: 3,
+            },
             key_range.0,
             key_range.1,
         )));
         // Compact to level 1
-        actions.push(ManifestAction::NextCompact((1,)));
-        actions.push(ManifestAction::NewId((1,)));
+        actions.push(ManifestAction::NextCompact((0, 1)));
+        actions.push(ManifestAction::NewId((0, 1)));
         let key_range = get_random_key_range(16, 17);
         actions.push(ManifestAction::Add((
-            SstId { level: 1, id: 0 },
+            SstId {
+                cf: 0,
+                level: 1,
+                id: 0,
+            },
             key_range.0,
             key_range.1,
         )));
         for i in 0..4 {
-            actions.push(ManifestAction::Remove((SstId { level: 0, id: i },)));
+            actions.push(ManifestAction::Remove((SstId {
+                cf: 0,
+                level: 0,
+                id: i,
+            },)));
         }
 
         let test_dir0 = create_test_dir()?;
@@ -598,7 +954,7 @@ mod tests {
         {
             let test_dir = test_dir0.clone();
             thread::spawn(move || {
-                let mut keeper = ManifestKeeper::new(&test_dir)?;
+                let mut keeper = ManifestKeeper::new(&test_dir, Arc::new(BytewiseComparator))?;
                 for action in rx {
                     if let ManifestAction::Commit = action {
                         keeper.commit()?;
@@ -611,7 +967,7 @@ mod tests {
             });
         }
         let test_dir1 = create_test_dir()?;
-        let mut keeper = ManifestKeeper::new(&test_dir1)?;
+        let mut keeper = ManifestKeeper::new(&test_dir1, Arc::new(BytewiseComparator))?;
         for action in &actions {
             tx.send(action.clone())?;
             if let ManifestAction::Commit = action {
@@ -622,7 +978,7 @@ mod tests {
         }
 
         // Rebuild keeper.
-        let keeper0 = ManifestKeeper::recover(&test_dir0)?;
+        let keeper0 = ManifestKeeper::recover(&test_dir0, Arc::new(BytewiseComparator))?;
         ensure!(
             keeper0.eq(&keeper),
             "(Normal Exit) Recovered manifest is corrupted"
@@ -634,17 +990,22 @@ mod tests {
     #[test]
     fn test_new_id() -> Result<()> {
         let test_dir = create_test_dir()?;
-        let mut keeper = ManifestKeeper::new(&test_dir)?;
+        let mut keeper = ManifestKeeper::new(&test_dir, Arc::new(BytewiseComparator))?;
 
         // First compaction.
         for j in 0..4 {
             for i in 0..4 {
                 keeper.batch_start();
                 ensure!(
-                    keeper.latest_sst_id(j) == SstId { level: j, id: i },
+                    keeper.family(0).latest_sst_id(j)
+                        == SstId {
+                            cf: 0,
+                            level: j,
+                            id: i
+                        },
                     "Assigned sst id is wrong. it should be level={j}, id={i}"
                 );
-                keeper.new_id(j);
+                keeper.new_id(0, j);
                 keeper.commit()?;
             }
         }
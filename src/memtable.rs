@@ -1,63 +1,195 @@
 // We use skiplist as container.
 // No hard deletion.
-// Insertion with same key is update.
+// Keys are internal keys, so insertion with same user key adds a new version.
 //
 //
 //
 //
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::ops::{Deref, DerefMut};
+use std::ops::{Bound, Deref, DerefMut};
 use std::path::Path;
+use std::sync::Arc;
 
 
 use anyhow::Result;
 use bincode::{config, Decode, Encode};
-use skiplist::SkipMap;
+use skiplist::OrderedSkipList;
+
+use crate::comparator::Comparator;
 
 pub const MEMTABLE_LOG_FILENAME: &str = "MEMTABLE_LOG";
+pub const MEMTABLE_FLUSH_SIZE: u64 = u64::pow(2, 20); // 1MB
 
 #[derive(Encode, Decode, PartialEq, Eq, Debug, Clone)]
 pub enum ValueUpdate {
     Tombstone,
     Value(Vec<u8>),
+    // Operand folded onto older versions by the merge operator.
+    Merge(Vec<u8>),
+    // Value and its expiry time in milliseconds since unix epoch.
+    Expiring(Vec<u8>, i64),
+}
+
+impl ValueUpdate {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            ValueUpdate::Tombstone => ValueKind::Tombstone,
+            ValueUpdate::Value(_) => ValueKind::Value,
+            ValueUpdate::Merge(_) => ValueKind::Merge,
+            ValueUpdate::Expiring(..) => ValueKind::Value,
+        }
+    }
+
+    // Bytes of the value or operand, 0 for tombstones.
+    pub fn value_len(&self) -> usize {
+        match self {
+            ValueUpdate::Tombstone => 0,
+            ValueUpdate::Value(v) | ValueUpdate::Merge(v) | ValueUpdate::Expiring(v, _) => v.len(),
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        matches!(self, ValueUpdate::Expiring(_, expire_at) if *expire_at <= now)
+    }
+
+    // Expired values read as tombstones, the others as plain values.
+    pub fn resolve_ttl(self, now: i64) -> ValueUpdate {
+        match self {
+            ValueUpdate::Expiring(_, expire_at) if expire_at <= now => ValueUpdate::Tombstone,
+            ValueUpdate::Expiring(v, _) => ValueUpdate::Value(v),
+            update => update,
+        }
+    }
+}
+
+#[derive(Encode, Decode, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ValueKind {
+    Tombstone,
+    Value,
+    Merge,
+}
+
+// Every write is stored as (user_key, seqno, kind).
+// A larger seqno means a newer write, so versions of a key don't rely on file order.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct InternalKey {
+    pub user_key: Vec<u8>,
+    pub seqno: u64,
+    pub kind: ValueKind,
+}
+
+impl InternalKey {
+    pub fn new(user_key: Vec<u8>, seqno: u64, kind: ValueKind) -> InternalKey {
+        InternalKey {
+            user_key,
+            seqno,
+            kind,
+        }
+    }
+
+    // The smallest internal key of `user_key` visible at `seqno`.
+    // Seeking to it finds the newest version whose seqno <= `seqno`.
+    pub fn seek(user_key: &[u8], seqno: u64) -> InternalKey {
+        InternalKey::new(user_key.to_vec(), seqno, ValueKind::Value)
+    }
+}
+
+// (user_key, seqno) identifies a write, kind only describes it.
+impl PartialEq for InternalKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.user_key == other.user_key && self.seqno == other.seqno
+    }
+}
+
+impl Eq for InternalKey {}
+
+// Deletes versions of keys in [start, end) older than `seqno`.
+#[derive(Encode, Decode, PartialEq, Eq, Debug, Clone)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub seqno: u64,
+}
+
+impl RangeTombstone {
+    pub fn contains(&self, cmp: &dyn Comparator, key: &[u8]) -> bool {
+        cmp.compare(&self.start, key).is_le() && cmp.compare(key, &self.end).is_lt()
+    }
+
+    // Whether the version of `key` at `seqno` is deleted.
+    pub fn covers(&self, cmp: &dyn Comparator, key: &[u8], seqno: u64) -> bool {
+        self.contains(cmp, key) && seqno < self.seqno
+    }
+
+    // Whether it overlaps [start, end). None means unbounded.
+    pub fn overlaps(&self, cmp: &dyn Comparator, start: Option<&[u8]>, end: Option<&[u8]>) -> bool {
+        start.map_or(true, |start| cmp.compare(start, &self.end).is_lt())
+            && end.map_or(true, |end| cmp.compare(&self.start, end).is_lt())
+    }
 }
 
+// Largest seqno of tombstones visible at `seqno` that contain `key`. 0 if none.
+// Versions older than it are deleted.
+pub fn range_deleted_seqno<'a, I>(
+    tombstones: I,
+    cmp: &dyn Comparator,
+    key: &[u8],
+    seqno: u64,
+) -> u64
+where
+    I: IntoIterator<Item = &'a RangeTombstone>,
+{
+    tombstones
+        .into_iter()
+        .filter(|t| t.seqno <= seqno && t.contains(cmp, key))
+        .map(|t| t.seqno)
+        .max()
+        .unwrap_or(0)
+}
+
+// Actions of all column families share the log, tagged by family.
 #[derive(Encode, Decode, PartialEq, Eq, Debug, Clone)]
 pub enum MemTableAction {
     Commit,
-    Insert((Vec<u8>, ValueUpdate)),
+    Insert((u32, InternalKey, ValueUpdate)),
+    DeleteRange((u32, RangeTombstone)),
 }
 
+// One memtable per column family.
 pub struct MemTableKeeper {
-    memtable: MemTable,
+    memtables: BTreeMap<u32, MemTable>,
+    comparator: Arc<dyn Comparator>,
     batch: VecDeque<MemTableAction>,
     log: File,
+    // Bytes appended to the log since opened.
+    log_bytes_written: u64,
 }
 
-
 impl PartialEq for MemTableKeeper {
     fn eq(&self, other: &Self) -> bool {
-        self.memtable == other.memtable
+        self.memtables == other.memtables
     }
 }
 
 impl Eq for MemTableKeeper {}
 
 impl MemTableKeeper {
-    pub fn new(store_dir: &Path) -> Result<MemTableKeeper> {
+    pub fn new(store_dir: &Path, comparator: Arc<dyn Comparator>) -> Result<MemTableKeeper> {
         Ok(MemTableKeeper {
-            memtable: MemTable::new(),
+            memtables: BTreeMap::new(),
+            comparator,
             batch: VecDeque::new(),
             log: File::options()
                 .create(true)
                 .write(true)
                 .open(store_dir.join(MEMTABLE_LOG_FILENAME))?,
+            log_bytes_written: 0,
         })
     }
 
-    pub fn recover(store_dir: &Path) -> Result<MemTableKeeper> {
+    pub fn recover(store_dir: &Path, comparator: Arc<dyn Comparator>) -> Result<MemTableKeeper> {
         let mut log = File::options()
             .read(true)
             .write(true)
@@ -65,39 +197,84 @@ impl MemTableKeeper {
         let mut buf = Vec::new();
         log.read_to_end(&mut buf)?;
 
-        let mut memtable = MemTable::new();
+        let mut memtables = BTreeMap::new();
         let mut batch = VecDeque::new();
 
-        let mut cur = 0;
-        while cur < buf.len() {
-            if let Ok((action, size)) =
-                bincode::decode_from_slice(&buf[cur..], bincode::config::standard())
-            {
-                cur += size;
-                match action {
-                    MemTableAction::Commit => {
-                        while let Some(action) = batch.pop_front() {
-                            memtable.execute_action(action);
-                        }
+        let (actions, end) = Self::decode_log(&buf);
+        for action in actions {
+            match action {
+                MemTableAction::Commit => {
+                    while let Some(action) = batch.pop_front() {
+                        Self::execute_action(&mut memtables, &comparator, action);
                     }
-                    _ => {
-                        batch.push_back(action);
-                    }
-                };
-            } else {
-                // Meets half written batch.
-                // Rollback by delete them.
-                log.set_len(cur as u64)?;
-                break;
-            }
+                }
+                _ => {
+                    batch.push_back(action);
+                }
+            };
+        }
+        if end < buf.len() {
+            // Meets half written batch.
+            // Rollback by delete them.
+            log.set_len(end as u64)?;
         }
         Ok(MemTableKeeper {
-            memtable,
+            memtables,
+            comparator,
             batch: VecDeque::new(),
             log,
+            log_bytes_written: 0,
         })
     }
 
+    // Decode actions of a log up to the first one that doesn't decode.
+    // Return them and the offset where decoding stopped.
+    pub fn decode_log(buf: &[u8]) -> (Vec<MemTableAction>, usize) {
+        let mut actions = Vec::new();
+        let mut cur = 0;
+        while cur < buf.len() {
+            match bincode::decode_from_slice(&buf[cur..], bincode::config::standard()) {
+                Ok((action, size)) => {
+                    actions.push(action);
+                    cur += size;
+                }
+                Err(_) => break,
+            }
+        }
+        (actions, cur)
+    }
+
+    fn execute_action(
+        memtables: &mut BTreeMap<u32, MemTable>,
+        comparator: &Arc<dyn Comparator>,
+        action: MemTableAction,
+    ) {
+        match action {
+            MemTableAction::Insert((cf, key, update)) => {
+                Self::memtable_mut(memtables, comparator, cf).insert(key, update);
+            }
+            MemTableAction::DeleteRange((cf, tombstone)) => {
+                Self::memtable_mut(memtables, comparator, cf).delete_range(tombstone);
+            }
+            MemTableAction::Commit => {}
+        }
+    }
+
+    fn memtable_mut<'a>(
+        memtables: &'a mut BTreeMap<u32, MemTable>,
+        comparator: &Arc<dyn Comparator>,
+        cf: u32,
+    ) -> &'a mut MemTable {
+        memtables
+            .entry(cf)
+            .or_insert_with(|| MemTable::new(comparator.clone()))
+    }
+
+    // Make sure `cf` has a memtable, even if nothing is written to it yet.
+    pub fn add_family(&mut self, cf: u32) {
+        Self::memtable_mut(&mut self.memtables, &self.comparator, cf);
+    }
+
     pub fn add_action(&mut self, action: MemTableAction) {
         self.batch.push_back(action);
     }
@@ -112,128 +289,186 @@ impl MemTableKeeper {
         buf.extend(bincode::encode_to_vec(MemTableAction::Commit, bincode::config::standard())?);
         self.log.write_all(&buf)?;
         self.log.sync_all()?;
+        self.log_bytes_written += buf.len() as u64;
 
         // Apply changes to in-memory manifest.
         while let Some(action) = self.batch.pop_front() {
-            self.memtable.execute_action(action);
+            Self::execute_action(&mut self.memtables, &self.comparator, action);
         }
         Ok(())
     }
 
-    pub fn insert(&mut self, key: Vec<u8>, update: ValueUpdate) {
-        self.batch.push_back(MemTableAction::Insert((key, update)));
+    pub fn log_bytes_written(&self) -> u64 {
+        self.log_bytes_written
+    }
+
+    pub fn insert(&mut self, cf: u32, key: Vec<u8>, seqno: u64, update: ValueUpdate) {
+        let key = InternalKey::new(key, seqno, update.kind());
+        self.batch
+            .push_back(MemTableAction::Insert((cf, key, update)));
+    }
+
+    pub fn delete_range(&mut self, cf: u32, start: Vec<u8>, end: Vec<u8>, seqno: u64) {
+        self.batch.push_back(MemTableAction::DeleteRange((
+            cf,
+            RangeTombstone { start, end, seqno },
+        )));
     }
 
-    pub fn container(&self) -> &MemTable {
-        &self.memtable
+    // `cf` should be added.
+    pub fn container(&self, cf: u32) -> &MemTable {
+        self.memtables
+            .get(&cf)
+            .expect("The column family should have a memtable")
     }
 
+    pub fn families(&self) -> impl Iterator<Item = (u32, &MemTable)> {
+        self.memtables.iter().map(|(&cf, memtable)| (cf, memtable))
+    }
+
+    // Size of all families, which is roughly the size of the log.
     pub fn approx_size(&self) -> u64 {
-        self.memtable.approx_size()
+        self.memtables.values().map(|m| m.approx_size()).sum()
     }
 
-    pub fn get(&self, key: &Vec<u8>) -> Option<&ValueUpdate> {
-        self.memtable.get(key)
+    pub fn last_seqno(&self) -> u64 {
+        self.memtables
+            .values()
+            .map(|m| m.last_seqno())
+            .max()
+            .unwrap_or(0)
     }
 
-    pub fn front(&self) -> Option<(&Vec<u8>, &ValueUpdate)> {
-        self.memtable.front()
+    pub fn get(&self, cf: u32, key: &[u8], seqno: u64) -> Option<(&InternalKey, &ValueUpdate)> {
+        self.container(cf).get(key, seqno)
     }
 
-    pub fn back(&self) -> Option<(&Vec<u8>, &ValueUpdate)> {
-        self.memtable.back()
+    pub fn range_tombstones(&self, cf: u32) -> &[RangeTombstone] {
+        self.container(cf).range_tombstones()
     }
 
-    pub fn iter(&self) -> skiplist::skipmap::Iter<Vec<u8>, ValueUpdate> {
-        self.memtable.iter()
+    pub fn iter(&self, cf: u32) -> skiplist::ordered_skiplist::Iter<(InternalKey, ValueUpdate)> {
+        self.container(cf).iter()
     }
 
     pub fn reset(&mut self) -> Result<()> {
-        self.memtable.clear();
+        for memtable in self.memtables.values_mut() {
+            memtable.clear();
+        }
         self.batch.clear();
         self.log.set_len(0)?;
         Ok(())
     }
 
     pub fn len(&self) -> usize {
-        self.memtable.len()
+        self.memtables.values().map(|m| m.len()).sum()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.len() == 0
+        self.memtables.values().all(|m| m.is_empty())
     }
 
     pub fn should_flush(&self) -> bool {
-        self.memtable.should_flush()
+        self.approx_size() >= MEMTABLE_FLUSH_SIZE
     }
 }
 
+// Keeps every version of a key. Old versions are dropped in compaction.
+// Range tombstones are few, so they are kept in a plain list.
 #[derive(PartialEq, Eq)]
 pub struct MemTable {
-    container: SkipMap<Vec<u8>, ValueUpdate>,
+    container: OrderedSkipList<(InternalKey, ValueUpdate)>,
+    range_tombstones: Vec<RangeTombstone>,
     approx_size: u64,
-}
-
-impl Default for MemTable {
-    fn default() -> Self {
-        Self::new()
-    }
+    last_seqno: u64,
 }
 
 impl MemTable {
-
-    pub fn new() -> MemTable {
+    pub fn new(comparator: Arc<dyn Comparator>) -> MemTable {
+        // Safe as long as the comparator is a total order, which Comparator requires.
+        let container = unsafe {
+            OrderedSkipList::with_comp(
+                move |(a, _): &(InternalKey, ValueUpdate), (b, _): &(InternalKey, ValueUpdate)| {
+                    comparator.compare_internal(a, b)
+                },
+            )
+        };
         MemTable {
-            container: SkipMap::new(),
+            container,
+            range_tombstones: Vec::new(),
             approx_size: 0,
+            last_seqno: 0,
         }
     }
 
-    pub fn execute_action(&mut self, action: MemTableAction) {
-        if let MemTableAction::Insert((key, update)) = action {
-            self.insert(key, update);
-        }
+    pub fn delete_range(&mut self, tombstone: RangeTombstone) {
+        self.approx_size += (tombstone.start.len() + tombstone.end.len()) as u64 + 24;
+        self.last_seqno = u64::max(self.last_seqno, tombstone.seqno);
+        self.range_tombstones.push(tombstone);
     }
 
-    pub fn insert(&mut self, key: Vec<u8>, update: ValueUpdate) -> Option<ValueUpdate> {
-        let key_len = key.len();
-        match &update {
-            ValueUpdate::Value(v) => self.approx_size += key_len as u64 + v.len() as u64 + 20, // two varstring + enum tag. let length of varstring be u64.
-            ValueUpdate::Tombstone => self.approx_size += key_len as u64 + 12,
-        }
-        let old_value = self.container.insert(key, update);
-        if let Some(old) = old_value.clone() {
-            match old {
-                ValueUpdate::Tombstone => self.approx_size -= key_len as u64 + 12,
-                ValueUpdate::Value(v) => self.approx_size -= key_len as u64 + v.len() as u64 + 20,
-            }
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    pub fn insert(&mut self, key: InternalKey, update: ValueUpdate) -> Option<ValueUpdate> {
+        let key_len = key.user_key.len() + 9; // seqno + kind tag.
+        self.approx_size += Self::record_size(key_len, &update);
+        self.last_seqno = u64::max(self.last_seqno, key.seqno);
+        // Replace the same version, entries are compared by key only.
+        let entry = (key, update);
+        let old_value = self.container.remove(&entry).map(|(_, old)| old);
+        if let Some(old) = &old_value {
+            self.approx_size -= Self::record_size(key_len, old);
         }
+        self.container.insert(entry);
         old_value
     }
 
+    fn record_size(key_len: usize, update: &ValueUpdate) -> u64 {
+        (match update {
+            // two varstring + enum tag. let length of varstring be u64.
+            ValueUpdate::Value(v) | ValueUpdate::Merge(v) => key_len + v.len() + 20,
+            ValueUpdate::Expiring(v, _) => key_len + v.len() + 28,
+            ValueUpdate::Tombstone => key_len + 12,
+        }) as u64
+    }
+
     pub fn approx_size(&self) -> u64 {
         self.approx_size
     }
 
-    pub fn get(&self, key: &Vec<u8>) -> Option<&ValueUpdate> {
-        self.container.get(key)
+    // Largest seqno ever inserted.
+    pub fn last_seqno(&self) -> u64 {
+        self.last_seqno
+    }
+
+    // Get the newest version of `key` visible at `seqno`.
+    pub fn get(&self, key: &[u8], seqno: u64) -> Option<(&InternalKey, &ValueUpdate)> {
+        let seek = (InternalKey::seek(key, seqno), ValueUpdate::Tombstone);
+        self.container
+            .lower_bound(Bound::Included(&seek))
+            .map(|(k, v)| (k, v))
+            .filter(|(k, _)| k.user_key == key)
     }
 
-    pub fn front(&self) -> Option<(&Vec<u8>, &ValueUpdate)> {
-        self.container.front()
+    pub fn front(&self) -> Option<(&InternalKey, &ValueUpdate)> {
+        self.container.front().map(|(k, v)| (k, v))
     }
 
-    pub fn back(&self) -> Option<(&Vec<u8>, &ValueUpdate)> {
-        self.container.back()
+    pub fn back(&self) -> Option<(&InternalKey, &ValueUpdate)> {
+        self.container.back().map(|(k, v)| (k, v))
     }
 
-    pub fn iter(&self) -> skiplist::skipmap::Iter<Vec<u8>, ValueUpdate> {
+    pub fn iter(&self) -> skiplist::ordered_skiplist::Iter<(InternalKey, ValueUpdate)> {
         self.container.iter()
     }
 
     pub fn clear(&mut self) {
         self.container.clear();
+        self.range_tombstones.clear();
         self.approx_size = 0;
+        self.last_seqno = 0;
     }
 
     pub fn len(&self) -> usize {
@@ -241,11 +476,11 @@ impl MemTable {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.len() == 0
+        self.len() == 0 && self.range_tombstones.is_empty()
     }
 
     pub fn should_flush(&self) -> bool {
-        self.approx_size() >= u64::pow(2, 20) // 1MB
+        self.approx_size() >= MEMTABLE_FLUSH_SIZE
     }
 }
 
@@ -256,6 +491,7 @@ mod tests {
     use std::thread;
     use std::thread::JoinHandle;
 
+    use crate::comparator::BytewiseComparator;
     use crate::memtable::*;
     use crate::test_util::*;
 
@@ -272,7 +508,7 @@ mod tests {
         {
             let test_dir = test_dir0.clone();
             thread_handle = thread::spawn(move || -> Result<()> {
-                let mut keeper = MemTableKeeper::new(&test_dir)?;
+                let mut keeper = MemTableKeeper::new(&test_dir, Arc::new(BytewiseComparator))?;
                 for (action, killed) in rx {
                     if killed {
                         break;
@@ -288,7 +524,7 @@ mod tests {
         }
 
         let test_dir = create_test_dir()?;
-        let mut keeper = MemTableKeeper::new(&test_dir)?;
+        let mut keeper = MemTableKeeper::new(&test_dir, Arc::new(BytewiseComparator))?;
         for i in 0..1024 {
             let key = get_random_bytes(1, 10);
             let update = if rand::thread_rng().gen::<f64>() > 0.8 {
@@ -296,8 +532,10 @@ mod tests {
             } else {
                 ValueUpdate::Value(get_random_bytes(1, usize::pow(2, 10)))
             };
-            keeper.insert(key.clone(), update.clone());
-            tx.send((MemTableAction::Insert((key, update)), false))?;
+            let cf = i as u32 % 2;
+            keeper.insert(cf, key.clone(), i, update.clone());
+            let key = InternalKey::new(key, i, update.kind());
+            tx.send((MemTableAction::Insert((cf, key, update)), false))?;
             if i % 16 == 0 {
                 keeper.commit()?;
                 tx.send((MemTableAction::Commit, false))?;
@@ -310,7 +548,7 @@ mod tests {
 
         thread_handle.join().unwrap()?;
 
-        let recovered_keeper = MemTableKeeper::recover(&test_dir0)?;
+        let recovered_keeper = MemTableKeeper::recover(&test_dir0, Arc::new(BytewiseComparator))?;
         ensure!(!keeper.is_empty(), "Memtable shouldn't be empty");
         ensure!(
             keeper == recovered_keeper,
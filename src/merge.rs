@@ -0,0 +1,300 @@
+// A merge writes an operand instead of a value, so read-modify-write needs no read.
+// Reads fold operands onto the newest value or tombstone below them.
+// Compaction folds them ahead of time:
+// fully if the base is in the same stripe or nothing is below,
+// otherwise partially by combining adjacent operands.
+// Operands above an expiring value are only combined, since they fold onto None once it expires.
+use crate::memtable::{InternalKey, ValueKind, ValueUpdate};
+
+use anyhow::{anyhow, Result};
+
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Fold `operands` (oldest first) onto `existing`. None means the key is absent or deleted.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>>;
+
+    // Combine two adjacent operands into one.
+    // None if they can only be folded onto a value.
+    fn partial_merge(&self, _key: &[u8], _older: &[u8], _newer: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+// Treat values as little-endian u64 counters.
+pub struct U64AddOperator;
+
+impl U64AddOperator {
+    fn decode(bytes: &[u8]) -> Result<u64> {
+        Ok(u64::from_le_bytes(bytes.try_into().map_err(|_| {
+            anyhow!("Counter should be 8 bytes, got {}", bytes.len())
+        })?))
+    }
+}
+
+impl MergeOperator for U64AddOperator {
+    fn name(&self) -> &str {
+        "u64_add"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>> {
+        let mut sum = match existing {
+            Some(bytes) => Self::decode(bytes)?,
+            None => 0,
+        };
+        for operand in operands {
+            sum = sum.wrapping_add(Self::decode(operand)?);
+        }
+        Ok(sum.to_le_bytes().to_vec())
+    }
+
+    fn partial_merge(&self, _key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        let sum = Self::decode(older)
+            .ok()?
+            .wrapping_add(Self::decode(newer).ok()?);
+        Some(sum.to_le_bytes().to_vec())
+    }
+}
+
+fn operator_or_err<'a>(
+    operator: Option<&'a dyn MergeOperator>,
+    key: &[u8],
+) -> Result<&'a dyn MergeOperator> {
+    operator
+        .ok_or_else(|| anyhow!("Found merge operands of key {key:?} but no merge operator is set"))
+}
+
+// `operands` are collected newest first.
+pub fn full_merge(
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    operands: &[Vec<u8>],
+    existing: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+    let operator = operator_or_err(operator, key)?;
+    let operands = operands.iter().rev().cloned().collect::<Vec<_>>();
+    operator.full_merge(key, existing, &operands)
+}
+
+// Collapse versions of a key in one snapshot stripe, newest first.
+// `is_bottom` means nothing older than `versions` exists.
+pub fn collapse(
+    operator: Option<&dyn MergeOperator>,
+    versions: &[(InternalKey, ValueUpdate)],
+    is_bottom: bool,
+) -> Result<Vec<(InternalKey, ValueUpdate)>> {
+    let (newest_key, newest_value) = &versions[0];
+    if newest_value.kind() != ValueKind::Merge {
+        // Older versions in the stripe are never observed.
+        return Ok(vec![versions[0].clone()]);
+    }
+    let merges = versions
+        .iter()
+        .take_while(|(_, v)| v.kind() == ValueKind::Merge)
+        .collect::<Vec<_>>();
+    let key = &newest_key.user_key;
+    let operator = operator_or_err(operator, key)?;
+
+    let base = versions.get(merges.len());
+    let is_expiring = matches!(base, Some((_, ValueUpdate::Expiring(..))));
+    if (base.is_some() || is_bottom) && !is_expiring {
+        let operands = merges
+            .iter()
+            .map(|(_, v)| match v {
+                ValueUpdate::Merge(operand) => operand.clone(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        let existing = match base {
+            Some((_, ValueUpdate::Value(v))) => Some(&v[..]),
+            _ => None,
+        };
+        let merged = full_merge(Some(operator), key, &operands, existing)?;
+        let merged_key = InternalKey::new(key.clone(), newest_key.seqno, ValueKind::Value);
+        return Ok(vec![(merged_key, ValueUpdate::Value(merged))]);
+    }
+
+    // Combine from the oldest. A combined operand takes the seqno of its newest part.
+    let mut collapsed: Vec<(InternalKey, ValueUpdate)> = Vec::new();
+    for (k, v) in merges.into_iter().rev() {
+        let combined = match (collapsed.last(), v) {
+            (Some((_, ValueUpdate::Merge(older))), ValueUpdate::Merge(newer)) => {
+                operator.partial_merge(key, older, newer)
+            }
+            _ => None,
+        };
+        match combined {
+            Some(operand) => {
+                *collapsed.last_mut().unwrap() = (k.clone(), ValueUpdate::Merge(operand));
+            }
+            None => collapsed.push((k.clone(), v.clone())),
+        }
+    }
+    collapsed.reverse();
+    // The expiring base is kept below them.
+    collapsed.extend(base.cloned());
+    Ok(collapsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::memtable::*;
+    use crate::merge::*;
+    use crate::options::Options;
+    use crate::store::Store;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    // Comma separated list. No partial merge.
+    struct AppendOperator;
+
+    impl MergeOperator for AppendOperator {
+        fn name(&self) -> &str {
+            "append"
+        }
+
+        fn full_merge(
+            &self,
+            _key: &[u8],
+            existing: Option<&[u8]>,
+            operands: &[Vec<u8>],
+        ) -> Result<Vec<u8>> {
+            let mut parts = existing.into_iter().map(|v| v.to_vec()).collect::<Vec<_>>();
+            parts.extend(operands.iter().cloned());
+            Ok(parts.join(&b","[..]))
+        }
+    }
+
+    fn counter(n: u64) -> Vec<u8> {
+        n.to_le_bytes().to_vec()
+    }
+
+    fn merge_at(seqno: u64, operand: Vec<u8>) -> (InternalKey, ValueUpdate) {
+        (
+            InternalKey::new(b"k".to_vec(), seqno, ValueKind::Merge),
+            ValueUpdate::Merge(operand),
+        )
+    }
+
+    #[test]
+    fn test_collapse() -> Result<()> {
+        let add: Option<&dyn MergeOperator> = Some(&U64AddOperator);
+        let merges = vec![merge_at(3, counter(1)), merge_at(2, counter(2))];
+
+        // Partial merge keeps the newest seqno.
+        ensure!(collapse(add, &merges, false)? == vec![merge_at(3, counter(3))]);
+        ensure!(
+            collapse(Some(&AppendOperator), &merges, false)? == merges,
+            "Operands are combined without partial merge"
+        );
+
+        // Full merge onto the base in the stripe.
+        let mut versions = merges.clone();
+        versions.push((
+            InternalKey::new(b"k".to_vec(), 1, ValueKind::Value),
+            ValueUpdate::Value(counter(10)),
+        ));
+        let collapsed = collapse(add, &versions, false)?;
+        ensure!(collapsed.len() == 1 && collapsed[0].0.seqno == 3);
+        ensure!(collapsed[0].1 == ValueUpdate::Value(counter(13)));
+
+        // Full merge at the bottom.
+        ensure!(collapse(add, &merges, true)?[0].1 == ValueUpdate::Value(counter(3)));
+        ensure!(collapse(None, &merges, true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let options = Options {
+            merge_operator: Some(Arc::new(AppendOperator)),
+            ..Options::default()
+        };
+        let mut store = Store::new_with_options(&test_dir, options.clone())?;
+
+        store.merge(b"list".to_vec(), b"a".to_vec())?;
+        store.merge(b"list".to_vec(), b"b".to_vec())?;
+        ensure!(store.get(b"list")? == Some(b"a,b".to_vec()));
+        let snapshot = store.snapshot();
+        store.insert(b"list".to_vec(), b"x".to_vec())?;
+        store.merge(b"list".to_vec(), b"y".to_vec())?;
+        ensure!(store.get(b"list")? == Some(b"x,y".to_vec()));
+        ensure!(store.get_at(b"list", &snapshot)? == Some(b"a,b".to_vec()));
+        store.remove(b"list")?;
+        store.merge(b"list".to_vec(), b"z".to_vec())?;
+        ensure!(store.get(b"list")? == Some(b"z".to_vec()));
+
+        store.merge(b"other".to_vec(), b"1".to_vec())?;
+        let pairs = store.scan(None, None)?.collect::<Result<Vec<_>>>()?;
+        ensure!(
+            pairs
+                == vec![
+                    (b"list".to_vec(), b"z".to_vec()),
+                    (b"other".to_vec(), b"1".to_vec())
+                ]
+        );
+        let pairs = store
+            .scan_at(None, None, &snapshot)?
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(pairs == vec![(b"list".to_vec(), b"a,b".to_vec())]);
+
+        // Operands survive recovery, flushes and compactions.
+        drop(snapshot);
+        drop(store);
+        let mut store = Store::recover_with_options(&test_dir, options)?;
+        ensure!(store.get(b"list")? == Some(b"z".to_vec()));
+        for i in 0..4096_u64 {
+            store.merge(
+                format!("key{}", i % 64).into_bytes(),
+                i.to_string().into_bytes(),
+            )?;
+            store.insert(format!("pad{i}").into_bytes(), get_random_bytes(1024, 1025))?;
+        }
+        ensure!(store.get(b"list")? == Some(b"z".to_vec()));
+        for i in 0..64_u64 {
+            let expected = (i..4096)
+                .step_by(64)
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            ensure!(
+                store.get(format!("key{i}").as_bytes())? == Some(expected.into_bytes()),
+                "Merged value of key{i} is wrong"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_counter() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let options = Options {
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..Options::default()
+        };
+        let mut store = Store::new_with_options(&test_dir, options)?;
+        for _ in 0..10 {
+            store.merge(b"hits".to_vec(), counter(2))?;
+        }
+        ensure!(store.get(b"hits")? == Some(counter(20)));
+
+        // Merging requires an operator.
+        let mut store = Store::new(&create_test_dir()?)?;
+        ensure!(store.merge(b"hits".to_vec(), counter(1)).is_err());
+        Ok(())
+    }
+}
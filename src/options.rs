@@ -0,0 +1,28 @@
+// Settings fixed when a store is opened.
+use std::sync::Arc;
+
+use crate::clock::{Clock, SystemClock};
+use crate::compaction_filter::CompactionFilter;
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::merge::MergeOperator;
+
+#[derive(Clone)]
+pub struct Options {
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    pub clock: Arc<dyn Clock>, // Decides when entries with ttl expire.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    // Orders keys of every column family, so it should be the same for all of them.
+    // Persisted by name, and the store must be reopened with it.
+    pub comparator: Arc<dyn Comparator>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            merge_operator: None,
+            clock: Arc::new(SystemClock),
+            compaction_filter: None,
+            comparator: Arc::new(BytewiseComparator),
+        }
+    }
+}
@@ -0,0 +1,428 @@
+// Rebuild the manifest of a store from its sst files, see Store::repair().
+//
+// Records of each sst are read up to the first one that doesn't decode or is out of order.
+// Intact files are kept, damaged ones are rewritten with the readable records,
+// and files with nothing readable are moved to lost/ along with the originals of rewritten ones.
+// Ssts of a level >= 1 that overlap others are moved to level 0, which may overlap.
+// Since versions of a key are ordered by seqno, not by file, this doesn't change reads.
+// Readable writes in the memtable log are flushed to level 0 and the log is emptied.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::column_family::{DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME};
+use crate::comparator::Comparator;
+use crate::manifest::*;
+use crate::memtable::{
+    InternalKey, MemTable, MemTableAction, MemTableKeeper, RangeTombstone, ValueUpdate,
+    MEMTABLE_LOG_FILENAME,
+};
+use crate::sst_dump::SstDump;
+use crate::sstable::*;
+
+use anyhow::{ensure, Result};
+
+pub const LOST_DIR: &str = "lost";
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    // Every sst in the new manifest.
+    pub ssts: Vec<SstId>,
+    // Ssts rewritten with the records that could be read.
+    pub rewritten: Vec<SstId>,
+    // Ssts of level >= 1 moved to level 0 because they overlap, by old and new id.
+    pub moved: Vec<(SstId, SstId)>,
+    // Level 0 ssts holding the writes of the memtable log.
+    pub flushed: Vec<SstId>,
+    // Files moved to lost/, by their new path.
+    pub quarantined: Vec<PathBuf>,
+}
+
+// Readable records and range tombstones of an sst file.
+struct Salvaged {
+    records: Vec<(InternalKey, ValueUpdate)>,
+    tombstones: Vec<RangeTombstone>,
+    // Key range of a file that passes SstDump::verify().
+    intact_range: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Salvaged {
+    fn max_seqno(&self) -> u64 {
+        let records = self.records.iter().map(|(k, _)| k.seqno);
+        let tombstones = self.tombstones.iter().map(|t| t.seqno);
+        records.chain(tombstones).max().unwrap_or(0)
+    }
+}
+
+// Records up to the first one that fails or isn't after the previous one.
+fn sorted_prefix<I>(records: I, cmp: &dyn Comparator) -> Vec<(InternalKey, ValueUpdate)>
+where
+    I: Iterator<Item = Result<(InternalKey, ValueUpdate)>>,
+{
+    let mut sorted: Vec<(InternalKey, ValueUpdate)> = Vec::new();
+    for (k, v) in records.map_while(Result::ok) {
+        if let Some((previous, _)) = sorted.last() {
+            if !cmp.compare_internal(previous, &k).is_lt() {
+                break;
+            }
+        }
+        sorted.push((k, v));
+    }
+    sorted
+}
+
+fn salvage(path: &Path, comparator: Arc<dyn Comparator>) -> Salvaged {
+    let cmp = comparator.clone();
+    if let Ok(dump) = SstDump::open(path, comparator) {
+        let table = dump.table();
+        let metadata = table.metadata();
+        return Salvaged {
+            records: sorted_prefix(table.iter(), cmp.as_ref()),
+            tombstones: table.range_tombstones().to_vec(),
+            intact_range: dump
+                .verify()
+                .ok()
+                .map(|_| (metadata.first_key.to_vec(), metadata.last_key.to_vec())),
+        };
+    }
+    // Without readable block sizes, decode records from the start of the file.
+    let buf = fs::read(path).unwrap_or_default();
+    let mut cur = 0;
+    let records = std::iter::from_fn(|| {
+        let (kv, size): ((InternalKey, ValueUpdate), usize) =
+            bincode::decode_from_slice(&buf[cur..], bincode::config::standard()).ok()?;
+        cur += size;
+        Some(Ok(kv))
+    });
+    Salvaged {
+        records: sorted_prefix(records, cmp.as_ref()),
+        tombstones: Vec::new(),
+        intact_range: None,
+    }
+}
+
+// Move `path` to the same place under lost/.
+fn quarantine(store_dir: &Path, path: &Path, report: &mut RepairReport) -> Result<()> {
+    let lost = store_dir.join(LOST_DIR).join(path.strip_prefix(store_dir)?);
+    fs::create_dir_all(lost.parent().unwrap())?;
+    fs::rename(path, &lost)?;
+    report.quarantined.push(lost);
+    Ok(())
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+// Write the salvaged records to `path` and return their key range.
+fn rewrite(
+    path: &Path,
+    comparator: Arc<dyn Comparator>,
+    salvaged: Salvaged,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut writer = SSTableWriter::new(File::create(path)?, comparator);
+    for (k, v) in &salvaged.records {
+        writer.add(k, v)?;
+    }
+    for tombstone in salvaged.tombstones {
+        writer.add_range_tombstone(tombstone);
+    }
+    writer.finish()
+}
+
+pub(crate) fn repair(store_dir: &Path, comparator: Arc<dyn Comparator>) -> Result<RepairReport> {
+    let cmp = comparator.as_ref();
+    let mut report = RepairReport::default();
+
+    // Family names are only recorded in manifest, so keep those that can be read.
+    // Others are named after their id.
+    let mut names =
+        BTreeMap::from([(DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME.to_owned())]);
+    if let Ok(dump) = ManifestDump::read(store_dir) {
+        ensure!(
+            dump.snapshot.comparator_name() == cmp.name(),
+            "Store is created with comparator {}, but repaired with {}",
+            dump.snapshot.comparator_name(),
+            cmp.name()
+        );
+        for (name, cf) in dump.snapshot.family_names() {
+            names.insert(cf, name.to_owned());
+        }
+        for (_, action) in &dump.actions {
+            if let ManifestAction::CreateFamily((cf, name)) = action {
+                names.insert(*cf, name.clone());
+            }
+        }
+    }
+
+    // Salvage ssts of every family, and quarantine files not named like ssts.
+    let mut tables: BTreeMap<SstId, (Vec<u8>, Vec<u8>)> = BTreeMap::new();
+    let mut last_seqno = 0;
+    for family_path in sorted_entries(store_dir)? {
+        let cf = match family_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(parse_family_dir)
+        {
+            Some(cf) if family_path.is_dir() => cf,
+            _ => continue,
+        };
+        names.entry(cf).or_insert_with(|| format!("cf_{cf}"));
+        for level_path in sorted_entries(&family_path)? {
+            let level = level_path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.parse().ok());
+            let level = match level {
+                Some(level) if level_path.is_dir() => level,
+                _ => {
+                    quarantine(store_dir, &level_path, &mut report)?;
+                    continue;
+                }
+            };
+            for path in sorted_entries(&level_path)? {
+                let id = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.parse().ok());
+                let sst_id = match id {
+                    Some(id) if path.is_file() => SstId { cf, level, id },
+                    _ => {
+                        quarantine(store_dir, &path, &mut report)?;
+                        continue;
+                    }
+                };
+                let salvaged = salvage(&path, comparator.clone());
+                if salvaged.records.is_empty() && salvaged.tombstones.is_empty() {
+                    quarantine(store_dir, &path, &mut report)?;
+                    continue;
+                }
+                last_seqno = u64::max(last_seqno, salvaged.max_seqno());
+                let range = match salvaged.intact_range.clone() {
+                    Some(range) => range,
+                    None => {
+                        quarantine(store_dir, &path, &mut report)?;
+                        report.rewritten.push(sst_id);
+                        rewrite(&path, comparator.clone(), salvaged)?
+                    }
+                };
+                tables.insert(sst_id, range);
+            }
+        }
+    }
+
+    // Keep ssts of a level >= 1 that don't overlap the previous one in key order.
+    // Ranges may touch, since the exclusive end of a range tombstone counts as the last key.
+    let mut next_level0_ids = BTreeMap::new();
+    for id in tables.keys() {
+        if id.level == 0 {
+            let next = next_level0_ids.entry(id.cf).or_default();
+            *next = u64::max(*next, id.id + 1);
+        }
+    }
+    let mut by_level: BTreeMap<(u32, u64), Vec<SstId>> = BTreeMap::new();
+    for id in tables.keys().filter(|id| id.level >= 1) {
+        by_level.entry((id.cf, id.level)).or_default().push(*id);
+    }
+    for ((cf, _), mut ids) in by_level {
+        ids.sort_by(|a, b| cmp.compare(&tables[a].0, &tables[b].0));
+        let mut last_key: Option<Vec<u8>> = None;
+        for id in ids {
+            let (first, last) = tables[&id].clone();
+            if let Some(previous) = &last_key {
+                if cmp.compare(previous, &first).is_gt() {
+                    let next = next_level0_ids.entry(cf).or_insert(0);
+                    let new_id = SstId {
+                        cf,
+                        level: 0,
+                        id: *next,
+                    };
+                    *next += 1;
+                    fs::create_dir_all(new_id.path(store_dir).parent().unwrap())?;
+                    fs::rename(id.path(store_dir), new_id.path(store_dir))?;
+                    let range = tables.remove(&id).unwrap();
+                    tables.insert(new_id, range);
+                    report.moved.push((id, new_id));
+                    continue;
+                }
+            }
+            last_key = Some(last);
+        }
+    }
+
+    // Flush committed writes of the memtable log.
+    let log_path = store_dir.join(MEMTABLE_LOG_FILENAME);
+    if log_path.exists() {
+        let buf = fs::read(&log_path)?;
+        let (actions, end) = MemTableKeeper::decode_log(&buf);
+        let committed = actions
+            .iter()
+            .rposition(|action| *action == MemTableAction::Commit)
+            .map_or(0, |i| i + 1);
+        let torn = end < buf.len() || committed < actions.len();
+        let mut memtables: BTreeMap<u32, MemTable> = BTreeMap::new();
+        for action in actions.into_iter().take(committed) {
+            match action {
+                MemTableAction::Insert((cf, key, update)) => {
+                    last_seqno = u64::max(last_seqno, key.seqno);
+                    let memtable = memtables
+                        .entry(cf)
+                        .or_insert_with(|| MemTable::new(comparator.clone()));
+                    memtable.insert(key, update);
+                }
+                MemTableAction::DeleteRange((cf, tombstone)) => {
+                    last_seqno = u64::max(last_seqno, tombstone.seqno);
+                    let memtable = memtables
+                        .entry(cf)
+                        .or_insert_with(|| MemTable::new(comparator.clone()));
+                    memtable.delete_range(tombstone);
+                }
+                MemTableAction::Commit => {}
+            }
+        }
+        for (cf, memtable) in &memtables {
+            names.entry(*cf).or_insert_with(|| format!("cf_{cf}"));
+            let next = next_level0_ids.entry(*cf).or_insert(0);
+            let sst_id = SstId {
+                cf: *cf,
+                level: 0,
+                id: *next,
+            };
+            *next += 1;
+            let range = SSTable::flush_to_level0_without_manifest(
+                memtable,
+                store_dir,
+                sst_id,
+                comparator.clone(),
+            )?;
+            tables.insert(sst_id, range);
+            report.flushed.push(sst_id);
+        }
+        if torn {
+            quarantine(store_dir, &log_path, &mut report)?;
+        }
+        fs::write(&log_path, b"")?;
+    }
+
+    // Replace manifest files, keeping the old ones in lost/.
+    for path in sorted_entries(store_dir)? {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if path.is_file() && name.starts_with("MANIFEST") {
+            quarantine(store_dir, &path, &mut report)?;
+        }
+    }
+    let mut manifest = Manifest::new(cmp.name());
+    for (cf, name) in &names {
+        manifest.restore_family(*cf, name);
+    }
+    for (id, (first, last)) in &tables {
+        manifest.restore_sst(*id, first, last, cmp);
+    }
+    manifest.execute_action(ManifestAction::LastSeqno((last_seqno,)), cmp);
+    ManifestKeeper::create(store_dir, manifest, comparator.clone())?;
+    report.ssts = tables.into_keys().collect();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::memtable::MEMTABLE_LOG_FILENAME;
+    use crate::repair::*;
+    use crate::sstable::family_dir;
+    use crate::store::Store;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    #[test]
+    fn test_repair() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        for i in 0..3_u8 {
+            store.insert(vec![i], vec![i])?;
+            store.flush()?;
+        }
+        store.insert(b"unflushed".to_vec(), b"1".to_vec())?;
+        drop(store);
+
+        // Lose the manifest, break an sst and leave a stray file.
+        remove_manifest(&test_dir)?;
+        let level0 = family_dir(&test_dir, 0).join("0");
+        fs::write(level0.join("2"), b"junk")?;
+        // Without the end of the footer, records are decoded from the start.
+        let mut bytes = fs::read(level0.join("1"))?;
+        bytes.truncate(bytes.len() - 1);
+        fs::write(level0.join("1"), bytes)?;
+        fs::write(level0.join("notes"), b"stray")?;
+
+        let report = Store::repair(&test_dir)?;
+        let sst = |id| SstId {
+            cf: 0,
+            level: 0,
+            id,
+        };
+        ensure!(report.ssts == [sst(2), sst(1), sst(0)], "{report:?}");
+        ensure!(report.flushed == [sst(2)] && report.rewritten == [sst(1)]);
+        let lost = test_dir.join(LOST_DIR);
+        ensure!(report
+            .quarantined
+            .contains(&lost.join(level0.strip_prefix(&test_dir)?).join("2")));
+        ensure!(lost
+            .join(level0.strip_prefix(&test_dir)?)
+            .join("notes")
+            .exists());
+        ensure!(fs::read(test_dir.join(MEMTABLE_LOG_FILENAME))?.is_empty());
+
+        let report = Store::verify(&test_dir)?;
+        ensure!(report.is_ok(), "{:?}", report.problems);
+        let store = Store::open(&test_dir)?;
+        ensure!(store.get(&[0])? == Some(vec![0]) && store.get(&[1])? == Some(vec![1]));
+        ensure!(store.get(&[2])?.is_none());
+        ensure!(store.get(b"unflushed")? == Some(b"1".to_vec()));
+        ensure!(store.last_seqno() >= 4);
+
+        // Level 1 ssts whose ranges touch, as a range tombstone clipped by compaction leaves them.
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        store.insert(b"a".to_vec(), b"1".to_vec())?;
+        store.delete_range(b"b", b"d")?;
+        store.flush()?;
+        store.insert(b"d".to_vec(), b"1".to_vec())?;
+        store.flush()?;
+        drop(store);
+        remove_manifest(&test_dir)?;
+        let level1 = family_dir(&test_dir, 0).join("1");
+        fs::rename(family_dir(&test_dir, 0).join("0"), &level1)?;
+        let report = Store::repair(&test_dir)?;
+        ensure!(report.moved.is_empty(), "{report:?}");
+        ensure!(report.ssts.iter().all(|id| id.level == 1));
+        let report = Store::verify(&test_dir)?;
+        ensure!(report.is_ok(), "{:?}", report.problems);
+        let store = Store::open(&test_dir)?;
+        ensure!(store.scan(None, None)?.count() == 2);
+        Ok(())
+    }
+
+    fn remove_manifest(store_dir: &Path) -> Result<()> {
+        for entry in fs::read_dir(store_dir)? {
+            let path = entry?.path();
+            if path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("MANIFEST")
+            {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+// Interactive mode of the qikv binary, which opens the store once and reads commands
+// from a line editor with history and completion of command names.
+// Lines are split into words like a shell does, and options of `qikv shell`
+// such as --encoding apply to every line unless the line sets its own.
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::cli::{self, Args};
+use crate::column_family::WriteBatch;
+use crate::store::Store;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+
+pub const SHELL_USAGE: &str = "\
+Commands:
+    put <key> <value>     Insert or overwrite a pair
+    get <key>             Print the value of a key
+    del <key>             Delete a key
+    scan [start] [end]    Print pairs with keys in [start, end), or use --prefix and --limit
+    batch begin           Collect puts and dels until commit
+    batch commit          Write the collected puts and dels atomically
+    batch abort           Discard the collected puts and dels
+    stats                 Print sizes of memtable and levels
+    flush                 Flush memtable to level 0
+    compact               Compact every level into the bottom one
+    help                  Print this message
+    exit                  Leave the shell
+";
+
+const COMMANDS: &[&str] = &[
+    "put", "get", "del", "scan", "batch", "stats", "flush", "compact", "help", "exit",
+];
+const BATCH_COMMANDS: &[&str] = &["begin", "commit", "abort"];
+
+// Split `line` into words separated by whitespace.
+// Quotes keep whitespace in a word, and backslash escapes the next character
+// except in single quotes.
+pub fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated ' in {line:?}"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => word.push(c),
+                            None => bail!("Unterminated \" in {line:?}"),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated \" in {line:?}"),
+                    }
+                }
+            }
+            '\\' => {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| anyhow!("Trailing \\ in {line:?}"))?;
+                word.get_or_insert_with(String::new).push(c);
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+pub struct Shell {
+    store: Store,
+    options: Args,
+    batch: Option<WriteBatch>,
+}
+
+impl Shell {
+    // `options` are those given to `qikv shell`.
+    pub(crate) fn new(store: Store, options: &Args) -> Shell {
+        Shell {
+            store,
+            options: options.clone(),
+            batch: None,
+        }
+    }
+
+    pub fn in_batch(&self) -> bool {
+        self.batch.is_some()
+    }
+
+    // Run the command in `line`. Returns false if the shell should exit.
+    pub fn execute<W: Write>(&mut self, line: &str, out: &mut W) -> Result<bool> {
+        let args = self.options.parse_onto(&split_words(line)?)?;
+        let command = match args.positional.first() {
+            Some(command) => command.as_str(),
+            None => return Ok(true),
+        };
+        match command {
+            "exit" | "quit" => {
+                args.operands(0, 0)?;
+                if self.batch.is_some() {
+                    writeln!(out, "Discarding uncommitted batch")?;
+                }
+                return Ok(false);
+            }
+            "help" => write!(out, "{SHELL_USAGE}")?,
+            "batch" => match &args.operands(1, 1)?[0][..] {
+                "begin" => {
+                    ensure!(self.batch.is_none(), "A batch is already open");
+                    self.batch = Some(WriteBatch::new());
+                }
+                "commit" => {
+                    let batch = self.batch.take().ok_or_else(|| anyhow!("No open batch"))?;
+                    let writes = batch.len();
+                    self.store.write(batch)?;
+                    writeln!(out, "Committed {writes} writes")?;
+                }
+                "abort" => {
+                    self.batch.take().ok_or_else(|| anyhow!("No open batch"))?;
+                }
+                other => bail!("Unknown batch command {other}, expected begin, commit or abort"),
+            },
+            "put" | "del" | "rm" if self.batch.is_some() => {
+                let cf = self.store.default_column_family();
+                let batch = self.batch.as_mut().unwrap();
+                if command == "put" {
+                    let operands = args.operands(2, 2)?;
+                    let value = args.value_encoding.decode(&operands[1])?;
+                    batch.insert(&cf, args.key(&operands[0])?, value);
+                } else {
+                    batch.remove(&cf, &args.key(&args.operands(1, 1)?[0])?);
+                }
+            }
+            "stats" => {
+                args.operands(0, 0)?;
+                writeln!(out, "last seqno {}", self.store.last_seqno())?;
+                for family in self.store.stats()? {
+                    writeln!(
+                        out,
+                        "{}: memtable {} bytes",
+                        family.name, family.memtable_bytes
+                    )?;
+                    for (level, ssts, bytes) in family.levels {
+                        writeln!(out, "    level {level}: {ssts} ssts, {bytes} bytes")?;
+                    }
+                }
+            }
+            "flush" => {
+                args.operands(0, 0)?;
+                self.store.flush()?;
+            }
+            "compact" => {
+                args.operands(0, 0)?;
+                self.store.compact()?;
+            }
+            "put" | "get" | "del" | "rm" | "scan" => cli::execute(&args, &mut self.store, out)?,
+            _ => bail!("Unknown command {command}, try help"),
+        }
+        Ok(true)
+    }
+}
+
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    // Complete the command name, or the subcommand of batch.
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before.rfind(' ').map_or(0, |i| i + 1);
+        let (typed, previous) = (&before[start..], before[..start].split_whitespace());
+        let candidates = match previous.collect::<Vec<_>>()[..] {
+            [] => COMMANDS,
+            ["batch"] => BATCH_COMMANDS,
+            _ => &[],
+        };
+        let matches = candidates
+            .iter()
+            .filter(|c| c.starts_with(typed))
+            .map(|c| c.to_string())
+            .collect();
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+// History is kept across sessions in ~/.qikv_history.
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".qikv_history"))
+}
+
+// Read commands from the terminal until exit or end of input.
+pub(crate) fn run(store: Store, options: &Args) -> Result<()> {
+    let mut shell = Shell::new(store, options);
+    let mut editor = Editor::<ShellHelper>::new()?;
+    editor.set_helper(Some(ShellHelper));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history on first use.
+        editor.load_history(path).ok();
+    }
+    let stdout = std::io::stdout();
+    loop {
+        let prompt = if shell.in_batch() {
+            "qikv (batch)> "
+        } else {
+            "qikv> "
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C discards the line, Ctrl-D leaves.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str());
+        }
+        match shell.execute(&line, &mut stdout.lock()) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => eprintln!("Error: {err:#}"),
+        }
+    }
+    if let Some(path) = &history {
+        editor
+            .save_history(path)
+            .map_err(|err| anyhow!("Failed to save history to {path:?}: {err}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Args;
+    use crate::shell::*;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    #[test]
+    fn test_split_words() -> Result<()> {
+        ensure!(split_words("  put a  'b c' ")? == ["put", "a", "b c"]);
+        ensure!(split_words(r#"put "a \"b\"" x\ y ''"#)? == ["put", "a \"b\"", "x y", ""]);
+        ensure!(split_words("put 'a").is_err() && split_words("put a\\").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shell() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut shell = Shell::new(Store::new(&test_dir)?, &Args::parse(&[])?);
+        let mut run = |line: &str| -> Result<String> {
+            let mut out = Vec::new();
+            shell.execute(line, &mut out)?;
+            Ok(String::from_utf8(out)?)
+        };
+        run("put apple red")?;
+        run("put 'green apple' green")?;
+        ensure!(run("get 'green apple'")? == "green\n");
+
+        // Batched writes are invisible until commit.
+        run("batch begin")?;
+        ensure!(run("batch begin").is_err());
+        run("put banana yellow")?;
+        run("del apple")?;
+        ensure!(run("get apple")? == "red\n");
+        ensure!(run("batch commit")? == "Committed 2 writes\n");
+        ensure!(run("get apple").is_err());
+        run("batch begin")?;
+        run("put cherry red")?;
+        run("batch abort")?;
+        ensure!(run("batch commit").is_err());
+        ensure!(run("--json get cherry")? == "{\"key\":\"cherry\",\"value\":null}\n");
+
+        // Four flushes trigger compaction into level 1, the other two are left in level 0.
+        for i in 0..6 {
+            run(&format!("put key{i} {i}"))?;
+            run("flush")?;
+        }
+        ensure!(run("stats")?.contains("level 0: 2 ssts"));
+        run("compact")?;
+        ensure!(run("stats")?.contains("default: memtable 0 bytes\n    level 1: 2 ssts"));
+        ensure!(!run("stats")?.contains("level 0"));
+        ensure!(run("scan --prefix key --limit 2")? == "key0\t0\nkey1\t1\n");
+        ensure!(run("frobnicate").is_err());
+        ensure!(run("")?.is_empty());
+        ensure!(!shell.execute("exit", &mut Vec::new())?);
+        Ok(())
+    }
+}
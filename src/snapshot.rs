@@ -0,0 +1,65 @@
+// A snapshot is just a seqno.
+// Reads through it ignore writes with larger seqnos.
+// Live snapshots are tracked so that compaction keeps the versions they can observe.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// Seqnos of live snapshots and how many handles refer to each.
+#[derive(Clone, Default)]
+pub struct SnapshotList {
+    live: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> SnapshotList {
+        SnapshotList::default()
+    }
+
+    pub fn acquire(&self, seqno: u64) -> Snapshot {
+        *self.live.lock().unwrap().entry(seqno).or_insert(0) += 1;
+        Snapshot {
+            seqno,
+            list: self.clone(),
+        }
+    }
+
+    // Seqnos of live snapshots in ascending order.
+    pub fn seqnos(&self) -> Vec<u64> {
+        self.live.lock().unwrap().keys().copied().collect()
+    }
+
+    fn release(&self, seqno: u64) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&seqno) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&seqno);
+            }
+        }
+    }
+}
+
+// Released on drop.
+pub struct Snapshot {
+    seqno: u64,
+    list: SnapshotList,
+}
+
+impl Snapshot {
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seqno);
+    }
+}
+
+// Snapshots split versions of a key into stripes.
+// A version belongs to the oldest snapshot that can see it, or None if only the latest view can.
+// Only the newest version in each stripe is observable.
+pub fn stripe(snapshots: &[u64], seqno: u64) -> Option<u64> {
+    snapshots.iter().find(|&&s| s >= seqno).copied()
+}
@@ -0,0 +1,181 @@
+// Inspect a single sst file, e.g. SST/<level>/<id>, without opening its store.
+// The file doesn't record its comparator, so the caller passes the one of the store.
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::column_family::DEFAULT_COLUMN_FAMILY;
+use crate::comparator::Comparator;
+use crate::memtable::{InternalKey, ValueUpdate};
+use crate::sstable::{SSTable, SstId};
+
+use anyhow::{bail, ensure, Context, Result};
+
+// Number of records by kind.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecordCounts {
+    pub values: u64,
+    pub tombstones: u64,
+    pub merges: u64,
+    pub expiring: u64,
+}
+
+impl RecordCounts {
+    pub fn total(&self) -> u64 {
+        self.values + self.tombstones + self.merges + self.expiring
+    }
+}
+
+pub struct SstDump {
+    path: PathBuf,
+    table: SSTable,
+    comparator: Arc<dyn Comparator>,
+    file_size: u64,
+    range_tombstones_size: u64,
+    index_size: u64,
+}
+
+impl SstDump {
+    // Fails if the blocks after the records can't be read.
+    pub fn open(path: &Path, comparator: Arc<dyn Comparator>) -> Result<SstDump> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        let (range_tombstones_size, index_size) = SSTable::read_block_sizes(&mut file, path)?;
+        // Ids only order tables, and ones outside of a store don't need any.
+        let sst_id = SstId::parse_path(path).unwrap_or(SstId {
+            cf: DEFAULT_COLUMN_FAMILY,
+            level: 0,
+            id: 0,
+        });
+        Ok(SstDump {
+            path: path.to_path_buf(),
+            table: SSTable::load_from_path(path, &sst_id, comparator.as_ref())?,
+            comparator,
+            file_size: file.metadata()?.len(),
+            range_tombstones_size,
+            index_size,
+        })
+    }
+
+    pub fn table(&self) -> &SSTable {
+        &self.table
+    }
+
+    // Sizes of the record, range tombstone and index blocks, which leave 16 bytes for the sizes.
+    pub fn block_sizes(&self) -> (u64, u64, u64) {
+        (
+            self.table.records_size() as u64,
+            self.range_tombstones_size,
+            self.index_size,
+        )
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    // Check that every record decodes, keys are strictly sorted and
+    // each index entry points at the record with its key.
+    pub fn verify(&self) -> Result<RecordCounts> {
+        let cmp = self.comparator.as_ref();
+        let mut counts = RecordCounts::default();
+        let mut previous: Option<InternalKey> = None;
+        for (i, wrapped_kv) in self.table.iter().enumerate() {
+            let (k, v) = wrapped_kv.with_context(|| format!("Record {i} doesn't decode"))?;
+            if let Some(previous) = &previous {
+                ensure!(
+                    cmp.compare_internal(previous, &k).is_lt(),
+                    "Record {i} {} is not after record {} {}",
+                    describe_key(&k),
+                    i - 1,
+                    describe_key(previous)
+                );
+            }
+            match v {
+                ValueUpdate::Value(_) => counts.values += 1,
+                ValueUpdate::Tombstone => counts.tombstones += 1,
+                ValueUpdate::Merge(_) => counts.merges += 1,
+                ValueUpdate::Expiring(..) => counts.expiring += 1,
+            }
+            previous = Some(k);
+        }
+        for (key, offset) in self.table.index() {
+            let record = self.table.iter_at(*offset).next();
+            let indexed = match record {
+                Some(Ok((k, _))) => k,
+                _ => bail!("Index entry at offset {offset} doesn't point at a record"),
+            };
+            ensure!(
+                indexed == *key,
+                "Index entry {} points at record {}",
+                describe_key(key),
+                describe_key(&indexed)
+            );
+        }
+        self.table
+            .validate(cmp)
+            .with_context(|| format!("Failed to verify {:?}", self.path))?;
+        Ok(counts)
+    }
+}
+
+// Internal key with its user key escaped, for messages.
+fn describe_key(key: &InternalKey) -> String {
+    format!("{}@{}", key.user_key.escape_ascii(), key.seqno)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use crate::comparator::BytewiseComparator;
+    use crate::memtable::ValueUpdate;
+    use crate::sst_dump::*;
+    use crate::sstable::{family_dir, SSTable};
+    use crate::store::Store;
+    use crate::test_util::*;
+
+    use anyhow::{ensure, Result};
+
+    #[test]
+    fn test_sst_dump() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        for i in 0..40_u32 {
+            store.insert(format!("key{i:02}").into_bytes(), vec![0; 10])?;
+        }
+        store.remove(b"key05")?;
+        store.delete_range(b"key30", b"key35")?;
+        store.flush()?;
+
+        let path = family_dir(&test_dir, 0).join("0").join("0");
+        let id = SstId::parse_path(Path::new("db/SST_2/1/7")).unwrap();
+        ensure!((id.cf, id.level, id.id) == (2, 1, 7));
+        ensure!(SstId::parse_path(Path::new("db/other/1/7")).is_none());
+        let dump = SstDump::open(&path, Arc::new(BytewiseComparator))?;
+        let counts = dump.verify()?;
+        ensure!((counts.total(), counts.values, counts.tombstones) == (41, 40, 1));
+        let (records, tombstones, index) = dump.block_sizes();
+        ensure!(records + tombstones + index + 16 == dump.file_size());
+        ensure!(dump.table().range_tombstones().len() == 1);
+        // Every 16th record and the last one are indexed.
+        ensure!(dump.table().index().len() == 4);
+
+        // Records out of order are reported, renaming a to c in a file of a and b.
+        let broken = test_dir.join("broken");
+        let pairs = [b"a", b"b"].map(|k| (k.to_vec(), ValueUpdate::Value(b"1".to_vec())));
+        SSTable::write_external(&broken, Arc::new(BytewiseComparator), pairs)?;
+        let mut bytes = fs::read(&broken)?;
+        let record = bytes.iter().position(|&b| b == b'a').unwrap();
+        bytes[record] = b'c';
+        fs::write(&broken, &bytes)?;
+        let dump = SstDump::open(&broken, Arc::new(BytewiseComparator))?;
+        ensure!(format!("{:#}", dump.verify().unwrap_err()).contains("is not after"));
+
+        // A truncated file can't be opened.
+        fs::write(&broken, &bytes[..8])?;
+        ensure!(SstDump::open(&broken, Arc::new(BytewiseComparator)).is_err());
+        Ok(())
+    }
+}
@@ -1,22 +1,39 @@
 // Use a very simple format.
 // Since main purpose of SStable is to speed up query access, the only additional data we store is sparse index.
 // [ Record * N ]
+// [ Range tombstones ]
 // [ Index * M ]
+// [ Size of range tombstones ]
 // [ Size of index ]
 //
+// Record format :=
+//      bincode::serialize((internal_key, value_update))
+//
+// Range tombstones format :=
+//      bincode::serialize(vec<range_tombstone>)
+//
 // Index format :=
-//      bincode::serialize(map<key, offset>)
+//      bincode::serialize(vec<(internal_key, offset)>), sorted by the comparator
 use core::iter::{Iterator, Peekable};
 use std::cmp::Ordering;
-use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
 
+use crate::column_family::DEFAULT_COLUMN_FAMILY;
+use crate::compaction_filter;
+use crate::comparator::Comparator;
 use crate::manifest::*;
-use crate::memtable::{MemTable, MemTableKeeper, ValueUpdate};
+use crate::memtable::{
+    self, InternalKey, MemTable, MemTableKeeper, RangeTombstone, ValueKind, ValueUpdate,
+};
+use crate::merge;
+use crate::options::Options;
+use crate::snapshot;
+use crate::version::Version;
 
 use anyhow::{anyhow, ensure, Result};
 use bincode::{config, Decode, Encode};
@@ -26,11 +43,12 @@ pub const SSTABLE_DIR: &str = "SST";
 pub const SPARSE_INDEX_INTERVAL: u64 = 16;
 pub const SSTABLE_FILE_SIZE: u64 = u64::pow(2, 21);
 
-pub type SparseIndex = BTreeMap<Vec<u8>, usize>;
-pub type BoxedIter = Box<dyn Iterator<Item = (Vec<u8>, ValueUpdate)>>;
+pub type SparseIndex = Vec<(InternalKey, usize)>;
+pub type BoxedIter = Box<dyn Iterator<Item = (InternalKey, ValueUpdate)>>;
 
 #[derive(Encode, Decode, PartialEq, Eq, Copy, Clone, Debug)]
 pub struct SstId {
+    pub cf: u32, // Column family.
     pub level: u64,
     pub id: u64,
 }
@@ -40,7 +58,7 @@ impl Ord for SstId {
     fn cmp(&self, other: &Self) -> Ordering {
         let order = self.level.cmp(&other.level);
         match order {
-            Ordering::Equal => other.id.cmp(&self.id),
+            Ordering::Equal => other.id.cmp(&self.id).then(self.cf.cmp(&other.cf)),
             _ => order,
         }
     }
@@ -52,15 +70,165 @@ impl PartialOrd for SstId {
     }
 }
 
+// Ssts of the default family are in SST/, those of family n in SST_n/.
+pub fn family_dir(db_dir: &Path, cf: u32) -> PathBuf {
+    if cf == DEFAULT_COLUMN_FAMILY {
+        db_dir.join(SSTABLE_DIR)
+    } else {
+        db_dir.join(format!("{SSTABLE_DIR}_{cf}"))
+    }
+}
+
+// Inverse of family_dir(). None if `name` isn't a family directory.
+pub fn parse_family_dir(name: &str) -> Option<u32> {
+    if name == SSTABLE_DIR {
+        Some(DEFAULT_COLUMN_FAMILY)
+    } else {
+        name.strip_prefix(SSTABLE_DIR)?
+            .strip_prefix('_')?
+            .parse()
+            .ok()
+    }
+}
+
 impl SstId {
+    // Inverse of path(), e.g. for .../SST_2/1/7. None if `path` isn't named like an sst.
+    pub fn parse_path(path: &Path) -> Option<SstId> {
+        let id = path.file_name()?.to_str()?.parse().ok()?;
+        let level_dir = path.parent()?;
+        let level = level_dir.file_name()?.to_str()?.parse().ok()?;
+        let cf = parse_family_dir(level_dir.parent()?.file_name()?.to_str()?)?;
+        Some(SstId { cf, level, id })
+    }
+
+    pub fn path(&self, db_dir: &Path) -> PathBuf {
+        family_dir(db_dir, self.cf)
+            .join(self.level.to_string())
+            .join(self.id.to_string())
+    }
+
     pub fn create_file(&self, db_dir: &Path) -> Result<File> {
-        let sst_dir = db_dir.join(SSTABLE_DIR).join(self.level.to_string());
+        let sst_dir = family_dir(db_dir, self.cf).join(self.level.to_string());
         fs::create_dir_all(&sst_dir)?;
         let sst_path = sst_dir.join(self.id.to_string());
-        Ok(File::options().write(true).create(true).open(sst_path)?)
+        Ok(File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(sst_path)?)
     }
 }
 
+// Write records sorted by internal key to a table file and build its sparse index.
+pub struct SSTableWriter {
+    file: File,
+    index: SparseIndex,
+    num_count: u64,
+    offset: usize,
+    previous_size: usize,
+    first_key: Option<InternalKey>,
+    previous_key: Option<InternalKey>,
+    range_tombstones: Vec<RangeTombstone>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl SSTableWriter {
+    pub fn new(file: File, comparator: Arc<dyn Comparator>) -> SSTableWriter {
+        SSTableWriter {
+            file,
+            index: SparseIndex::new(),
+            num_count: 0,
+            offset: 0,
+            previous_size: 0,
+            first_key: None,
+            previous_key: None,
+            range_tombstones: Vec::new(),
+            comparator,
+        }
+    }
+
+    // Written to the range tombstone block on finish().
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.range_tombstones.push(tombstone);
+    }
+
+    pub fn add(&mut self, key: &InternalKey, update: &ValueUpdate) -> Result<()> {
+        if let Some(previous) = &self.previous_key {
+            ensure!(
+                self.comparator.compare_internal(previous, key).is_lt(),
+                "Records of SST must be strictly sorted"
+            );
+        } else {
+            self.first_key = Some(key.clone());
+        }
+        let encoded = bincode::encode_to_vec((key, update), config::standard())?;
+        self.file.write_all(&encoded)?;
+        if self.num_count % SPARSE_INDEX_INTERVAL == 0 {
+            self.index.push((key.clone(), self.offset));
+        }
+        self.num_count += 1;
+        self.offset += encoded.len();
+        self.previous_size = encoded.len();
+        self.previous_key = Some(key.clone());
+        Ok(())
+    }
+
+    // Size of records written so far.
+    pub fn size(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_count == 0 && self.range_tombstones.is_empty()
+    }
+
+    // Write range tombstones and sparse index and sync.
+    // Return the range of user keys in the file, including those covered by range tombstones.
+    pub fn finish(mut self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut range = None;
+        if let Some(first_key) = self.first_key.take() {
+            let last_key = self.previous_key.take().unwrap();
+            range = Some((first_key.user_key, last_key.user_key.clone()));
+            // Add the last key to index, unless it's indexed already.
+            if self.index.last().map(|(k, _)| k) != Some(&last_key) {
+                self.index
+                    .push((last_key, self.offset - self.previous_size));
+            }
+        }
+        let range = key_range(self.comparator.as_ref(), range, &self.range_tombstones)
+            .ok_or_else(|| anyhow!("Tried to finish empty SST"))?;
+
+        // Write range tombstones and sparse index.
+        let encoded_tombstones =
+            bincode::encode_to_vec(&self.range_tombstones, config::standard())?;
+        self.file.write_all(&encoded_tombstones)?;
+        let encoded = bincode::encode_to_vec(&self.index, config::standard())?;
+        self.file.write_all(&encoded)?;
+        self.file
+            .write_all(&u64::to_be_bytes(encoded_tombstones.len() as u64))?;
+        self.file
+            .write_all(&u64::to_be_bytes(encoded.len() as u64))?;
+        self.file.sync_all()?;
+        Ok(range)
+    }
+}
+
+// Extend the range of records to cover range tombstones.
+// The exclusive end of a tombstone is used as an inclusive bound, which is conservative.
+fn key_range(
+    cmp: &dyn Comparator,
+    records: Option<(Vec<u8>, Vec<u8>)>,
+    tombstones: &[RangeTombstone],
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    tombstones.iter().fold(records, |range, t| match range {
+        Some((first, last)) => Some((
+            std::cmp::min_by(first, t.start.clone(), |a, b| cmp.compare(a, b)),
+            std::cmp::max_by(last, t.end.clone(), |a, b| cmp.compare(a, b)),
+        )),
+        None => Some((t.start.clone(), t.end.clone())),
+    })
+}
+
 // Used for sorting.
 #[derive(PartialEq, Eq)]
 pub struct SSTMetadata<'a> {
@@ -70,19 +238,19 @@ pub struct SSTMetadata<'a> {
     pub last_key: &'a [u8],
 }
 
-// For level 0, ordered by create time.
-// For level >= 1, Ordered by level and first key and last key.
-impl Ord for SSTMetadata<'_> {
-    fn cmp(&self, other: &Self) -> Ordering {
+impl SSTMetadata<'_> {
+    // For level 0, ordered by create time.
+    // For level >= 1, Ordered by level and first key and last key.
+    pub fn compare(&self, other: &Self, cmp: &dyn Comparator) -> Ordering {
         if self.level == 0 && other.level == 0 {
             other.id.cmp(&self.id)
         } else {
             let level_cmp = self.level.cmp(&other.level);
             match level_cmp {
                 Ordering::Equal => {
-                    let first_key_cmp = self.first_key.cmp(other.first_key);
+                    let first_key_cmp = cmp.compare(self.first_key, other.first_key);
                     match first_key_cmp {
-                        Ordering::Equal => self.last_key.cmp(other.last_key),
+                        Ordering::Equal => cmp.compare(self.last_key, other.last_key),
                         _ => first_key_cmp,
                     }
                 }
@@ -92,32 +260,14 @@ impl Ord for SSTMetadata<'_> {
     }
 }
 
-impl PartialOrd for SSTMetadata<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
 // In-memory SSTable used for query and compaction.
 #[derive(PartialEq, Eq, Clone)]
 pub struct SSTable {
     buf: Vec<u8>,       // Store kv pairs only.
     index: SparseIndex, // Sparse index: key -> offset
     id: SstId,          // Used for sorting.
-}
-
-// For level 0, ordered by create time.
-// For level >= 1, Ordered by level and first key and last key.
-impl Ord for SSTable {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.metadata().cmp(&other.metadata())
-    }
-}
-
-impl PartialOrd for SSTable {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+    range_tombstones: Vec<RangeTombstone>,
+    range: (Vec<u8>, Vec<u8>), // User keys covered by records and range tombstones.
 }
 
 impl SSTable {
@@ -125,43 +275,127 @@ impl SSTable {
         &self.id
     }
     // Load SSTable from disk.
-    // SSTable is named as db_dir/SSTABLE_DIR/level/id.
-    pub fn load_by_id(sst_id: &SstId, db_dir: &Path) -> Result<SSTable> {
-        dbg!(format!("load sst by id = {sst_id:#?}"));
-        let sst_path = db_dir
-            .join(SSTABLE_DIR)
-            .join(sst_id.level.to_string())
-            .join(sst_id.id.to_string());
-        let mut file = File::open(sst_path)?;
+    // SSTable is named as family_dir/level/id.
+    pub fn load_by_id(sst_id: &SstId, db_dir: &Path, cmp: &dyn Comparator) -> Result<SSTable> {
+        Self::load_from_path(&sst_id.path(db_dir), sst_id, cmp)
+    }
 
-        // Read index size and then index.
-        let index_size_offset = file.seek(SeekFrom::End(-8))?;
-        let mut index_size_buf = [0_u8; 8];
-        file.read_exact(&mut index_size_buf)?;
-        let index_size = u64::from_be_bytes(index_size_buf);
+    // Load a table file that is not necessarily placed under db_dir, e.g. an external one.
+    // `sst_id` is only used for sorting.
+    pub fn load_from_path(
+        sst_path: &Path,
+        sst_id: &SstId,
+        cmp: &dyn Comparator,
+    ) -> Result<SSTable> {
+        let mut file = File::open(sst_path)?;
+        // Read block sizes, then index and range tombstones.
+        let (tombstones_size, index_size) = Self::read_block_sizes(&mut file, sst_path)?;
+        let sizes_offset = file.metadata()?.len() - 16;
         let mut index_buf = vec![0_u8; index_size as usize];
-        let index_offset = index_size_offset - index_size;
+        let index_offset = sizes_offset - index_size;
         file.seek(SeekFrom::Start(index_offset))?;
         file.read_exact(&mut index_buf)?;
         let index: SparseIndex = bincode::decode_from_slice(&index_buf[..], config::standard())?.0;
 
-        let mut record_buf = vec![0_u8; index_offset as usize];
+        let mut tombstones_buf = vec![0_u8; tombstones_size as usize];
+        let tombstones_offset = index_offset - tombstones_size;
+        file.seek(SeekFrom::Start(tombstones_offset))?;
+        file.read_exact(&mut tombstones_buf)?;
+        let range_tombstones: Vec<RangeTombstone> =
+            bincode::decode_from_slice(&tombstones_buf[..], config::standard())?.0;
+
+        let records_range = match (index.first(), index.last()) {
+            (Some((first, _)), Some((last, _))) => {
+                Some((first.user_key.clone(), last.user_key.clone()))
+            }
+            _ => None,
+        };
+        let range = key_range(cmp, records_range, &range_tombstones)
+            .ok_or_else(|| anyhow!("SST file {sst_path:?} is empty"))?;
+
+        let mut record_buf = vec![0_u8; tombstones_offset as usize];
         file.seek(SeekFrom::Start(0))?;
         file.read_exact(&mut record_buf)?;
         Ok(SSTable {
             buf: record_buf,
             index,
             id: *sst_id,
+            range_tombstones,
+            range,
         })
     }
 
+    // Sizes of the range tombstone block and the index block, read from the end of `file`.
+    pub fn read_block_sizes(file: &mut File, sst_path: &Path) -> Result<(u64, u64)> {
+        ensure!(
+            file.metadata()?.len() >= 16,
+            "SST file {sst_path:?} is too short to contain an index"
+        );
+        let sizes_offset = file.seek(SeekFrom::End(-16))?;
+        let mut size_buf = [0_u8; 8];
+        file.read_exact(&mut size_buf)?;
+        let tombstones_size = u64::from_be_bytes(size_buf);
+        file.read_exact(&mut size_buf)?;
+        let index_size = u64::from_be_bytes(size_buf);
+        ensure!(
+            index_size
+                .checked_add(tombstones_size)
+                .map_or(false, |size| size <= sizes_offset),
+            "SST file {sst_path:?} has invalid block sizes {tombstones_size} and {index_size}"
+        );
+        Ok((tombstones_size, index_size))
+    }
+
+    // Check that every record decodes, keys are strictly increasing and
+    // the sparse index agrees with the records.
+    pub fn validate(&self, cmp: &dyn Comparator) -> Result<()> {
+        ensure!(
+            !self.index.is_empty() || !self.range_tombstones.is_empty(),
+            "SST is empty"
+        );
+        let mut first_key = None;
+        let mut previous_key: Option<InternalKey> = None;
+        for wrapped_kv in self.iter() {
+            let (k, _) = wrapped_kv?;
+            if let Some(previous) = &previous_key {
+                ensure!(
+                    cmp.compare_internal(previous, &k).is_lt(),
+                    "Keys in SST are not strictly sorted"
+                );
+            } else {
+                first_key = Some(k.clone());
+            }
+            previous_key = Some(k);
+        }
+        ensure!(
+            first_key.as_ref() == self.index.first().map(|(k, _)| k)
+                && previous_key.as_ref() == self.index.last().map(|(k, _)| k),
+            "Key range of SST records doesn't match its index"
+        );
+        Ok(())
+    }
+
+    // Build a standalone table file from pairs sorted by `comparator`.
+    // Used to prepare files for Store::ingest_external_files(), which assigns their seqno.
+    pub fn write_external<I>(path: &Path, comparator: Arc<dyn Comparator>, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, ValueUpdate)>,
+    {
+        let file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = SSTableWriter::new(file, comparator);
+        for (k, v) in pairs {
+            writer.add(&InternalKey::new(k, 0, v.kind()), &v)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
     pub fn remove(store_dir: &Path, sst_id: &SstId) -> Result<()> {
-        fs::remove_file(
-            store_dir
-                .join(SSTABLE_DIR)
-                .join(sst_id.level.to_string())
-                .join(sst_id.id.to_string()),
-        )?;
+        fs::remove_file(sst_id.path(store_dir))?;
         Ok(())
     }
 
@@ -179,124 +413,123 @@ impl SSTable {
     // })
     // }
 
-    fn flush_to_level0_without_manifest(memtable: &MemTable, db_dir: &Path, id: u64) -> Result<()> {
+    // Return the range of user keys in the flushed file.
+    pub(crate) fn flush_to_level0_without_manifest(
+        memtable: &MemTable,
+        db_dir: &Path,
+        sst_id: SstId,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
         // Flush memtable to bytes by chunks(records).
         // And generate sparse index.
         // Write to disk.
         ensure!(!memtable.is_empty(), "Tried to flush empty memtable");
 
-        let sst_dir = db_dir.join(SSTABLE_DIR).join("0");
-        fs::create_dir_all(&sst_dir)?;
-        let sst_path = sst_dir.join(id.to_string());
-        let mut file = File::options().write(true).create(true).open(sst_path)?;
-
-        let mut index = SparseIndex::new();
-        index.insert(memtable.front().unwrap().0.clone(), 0);
-        let mut offset: usize = 0;
-        let mut previous_size = 0;
-        for (i, pair) in memtable.iter().enumerate() {
-            if i as u64 % SPARSE_INDEX_INTERVAL == 0 {
-                index.insert(pair.0.clone(), offset);
-            }
-
-            let encoded = bincode::encode_to_vec(pair, config::standard())?;
-            file.write_all(&encoded)?;
-            offset += encoded.len();
-            previous_size = encoded.len();
+        let mut writer = SSTableWriter::new(sst_id.create_file(db_dir)?, comparator);
+        for (k, v) in memtable.iter() {
+            writer.add(k, v)?;
         }
-
-        // Add the last key to index.
-        index.insert(memtable.back().unwrap().0.clone(), offset - previous_size);
-
-        // Write sparse index.
-        let encoded = bincode::encode_to_vec(&index, config::standard())?;
-        file.write_all(&encoded)?;
-        file.write_all(&u64::to_be_bytes(encoded.len() as u64))?;
-        file.sync_all()?;
-
-        Ok(())
+        for tombstone in memtable.range_tombstones() {
+            writer.add_range_tombstone(tombstone.clone());
+        }
+        writer.finish()
     }
 
+    // Flush memtables of all column families, since they share the log.
+    // Every family gets at most one new level 0 sst.
     pub fn flush_to_level0(
         memtable: &mut MemTableKeeper,
         db_dir: &Path,
         manifest: &mut ManifestKeeper,
-    ) -> Result<SstId> {
+    ) -> Result<Vec<SstId>> {
         manifest.batch_start();
-        let sst_id = manifest.latest_sst_id(0);
-        dbg!(format!("Flush memtable to sst {sst_id:#?}"));
-        manifest.new_id(0);
-
-        Self::flush_to_level0_without_manifest(memtable.container(), db_dir, sst_id.id)?;
+        let mut sst_ids = Vec::new();
+        for (cf, container) in memtable.families() {
+            if container.is_empty() {
+                continue;
+            }
+            let sst_id = manifest.family(cf).latest_sst_id(0);
+            manifest.new_id(cf, 0);
+
+            let (first_key, last_key) = Self::flush_to_level0_without_manifest(
+                container,
+                db_dir,
+                sst_id,
+                manifest.comparator().clone(),
+            )?;
+            manifest.add(sst_id, &first_key, &last_key);
+            sst_ids.push(sst_id);
+        }
 
-        // Add new sst to manifest and commit to disk.
-        manifest.add(
-            sst_id,
-            memtable.front().unwrap().0,
-            memtable.back().unwrap().0,
-        );
+        // Add new ssts to manifest and commit to disk.
+        manifest.set_last_seqno(memtable.last_seqno());
         manifest.commit()?;
         memtable.reset()?;
-        Ok(sst_id)
+        Ok(sst_ids)
     }
 
     pub fn metadata(&self) -> SSTMetadata {
         SSTMetadata {
             level: self.id.level,
             id: self.id.id,
-            first_key: self.index.first_key_value().unwrap().0, // index is granteed to be non-empty.
-            last_key: self.index.last_key_value().unwrap().0,
+            first_key: &self.range.0,
+            last_key: &self.range.1,
         }
     }
 
-    pub fn get(&self, key: &[u8]) -> Result<Option<ValueUpdate>> {
-        // Query sparse index to find the left iterator where left <= key < right.
-        //
-        // Manifest ensures that key is in the range of this SSTable.
-
-        let mut index_iter = self.index.iter().peekable();
-        let mut offset = 0;
-        let mut offset_end = self.buf.len();
-        while let Some((k, v)) = index_iter.next() {
-            if let Some(&(next_k, next_v)) = index_iter.peek() {
-                if &k[..] <= key && key < next_k {
-                    offset = *v;
-                    offset_end = *next_v;
-                    break;
+    // Get the newest version of `key` visible at `seqno`.
+    pub fn get(
+        &self,
+        key: &[u8],
+        seqno: u64,
+        cmp: &dyn Comparator,
+    ) -> Result<Option<(InternalKey, ValueUpdate)>> {
+        // Query sparse index to find the last indexed key before the wanted version of `key`.
+        // Versions of a key may span several index intervals, so scan until passing it.
+        let seek = InternalKey::seek(key, seqno);
+        let indexed = self
+            .index
+            .partition_point(|(k, _)| cmp.compare_internal(k, &seek).is_le());
+        let offset = match indexed {
+            0 => 0,
+            n => self.index[n - 1].1,
+        };
+
+        for wrapped_kv in self.iter_at(offset) {
+            let (k, v) = wrapped_kv?;
+            if cmp.compare_internal(&k, &seek).is_ge() {
+                if k.user_key == key {
+                    return Ok(Some((k, v)));
                 }
-            } else if &k[..] <= key {
-                offset = *v;
                 break;
             }
         }
-
-        // Iterate from offset.
-        let mut iter = self.iter_range(offset, offset_end);
-        let wrapped_kv = iter.try_find(|wrapped_kv| match wrapped_kv {
-            Ok((k, _,)) => Ok(k == key),
-            Err(_) => Err(anyhow!("Failed to decode entry in SSTable"))
-        });
-        wrapped_kv.map(|contained_kv| contained_kv.map(|wrapped| wrapped.unwrap().1))
+        Ok(None)
     }
 
     pub fn iter(&self) -> SSTableIter {
         self.iter_at(0)
     }
 
-    fn iter_at(&self, start: usize) -> SSTableIter<'_> {
-        SSTableIter {
-            buf: &self.buf,
-            cur: start,
-            end: self.buf.len(),
-            done: false,
-        }
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    pub fn index(&self) -> &SparseIndex {
+        &self.index
+    }
+
+    // Size of the record block.
+    pub fn records_size(&self) -> usize {
+        self.buf.len()
     }
 
-    fn iter_range(&self, start: usize, end: usize) -> SSTableIter<'_> {
+    // Iterate from the record at byte `start` of the record block, e.g. an indexed offset.
+    pub fn iter_at(&self, start: usize) -> SSTableIter<'_> {
         SSTableIter {
             buf: &self.buf,
             cur: start,
-            end,
+            end: self.buf.len(),
             done: false,
         }
     }
@@ -313,7 +546,7 @@ pub struct SSTableIter<'a> {
 // And save encoding/decoding here.
 // Access bytes directly like in log.
 impl<'a> Iterator for SSTableIter<'a> {
-    type Item = Result<(Vec<u8>, ValueUpdate)>;
+    type Item = Result<(InternalKey, ValueUpdate)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.cur >= self.end {
@@ -344,25 +577,32 @@ impl<'a> Iterator for SSTableIter<'a> {
 pub struct SSTLevelGroup {
     ids: Vec<SstId>,
     store_dir: PathBuf,
+    _version: Arc<Version>, // Pinned so that ssts are still there when lazily loaded.
+    comparator: Arc<dyn Comparator>,
 }
 
 impl SSTLevelGroup {
     pub fn new(
+        cf: u32,
         level: u64,
         ids: &[u64],
         store_dir: &Path,
-        manifest: &Manifest,
+        version: Arc<Version>,
+        comparator: Arc<dyn Comparator>,
     ) -> Result<SSTLevelGroup> {
         assert!(!ids.is_empty());
         assert!(level >= 1);
-        let ids = manifest.sort(
+        let ids = version.sort(
             &ids.iter()
-                .map(|&id| SstId { level, id })
+                .map(|&id| SstId { cf, level, id })
                 .collect::<Vec<_>>(),
+            comparator.as_ref(),
         );
         Ok(SSTLevelGroup {
             ids,
             store_dir: store_dir.to_path_buf(),
+            _version: version,
+            comparator,
         })
     }
 
@@ -370,6 +610,7 @@ impl SSTLevelGroup {
         SSTLevelGroupIter {
             id_iter: self.ids.iter(),
             store_dir: &self.store_dir,
+            comparator: self.comparator.as_ref(),
             sst_iter: None,
             done: false,
         }
@@ -387,12 +628,13 @@ struct OwnedSSTIter {
 pub struct SSTLevelGroupIter<'a> {
     id_iter: std::slice::Iter<'a, SstId>,
     store_dir: &'a Path,
+    comparator: &'a dyn Comparator,
     sst_iter: Option<OwnedSSTIter>,
     done: bool,
 }
 
 impl<'a> Iterator for SSTLevelGroupIter<'a> {
-    type Item = Result<(Vec<u8>, ValueUpdate)>;
+    type Item = Result<(InternalKey, ValueUpdate)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -406,7 +648,7 @@ impl<'a> Iterator for SSTLevelGroupIter<'a> {
                     self.sst_iter = None;
                 }
             } else if let Some(id) = self.id_iter.next() {
-                let wrapped_sst = SSTable::load_by_id(id, self.store_dir);
+                let wrapped_sst = SSTable::load_by_id(id, self.store_dir, self.comparator);
                 match wrapped_sst {
                     Ok(sst) => {
                         self.sst_iter = Some(
@@ -434,121 +676,218 @@ impl<'a> Iterator for SSTLevelGroupIter<'a> {
 // The smaller the higher.
 pub struct SSTGroup {
     sstables: Vec<SSTable>,
+    comparator: Arc<dyn Comparator>,
 }
 
 impl SSTGroup {
-    pub fn new(sst_ids: &[SstId], store_dir: &Path) -> Result<SSTGroup> {
+    pub fn new(
+        sst_ids: &[SstId],
+        store_dir: &Path,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<SSTGroup> {
         let mut sstables = sst_ids
             .iter()
-            .map(|id| SSTable::load_by_id(id, store_dir))
+            .map(|id| SSTable::load_by_id(id, store_dir, comparator.as_ref()))
             .collect::<Result<Vec<_>>>()?;
-        sstables.sort();
-        Ok(SSTGroup { sstables })
+        sstables.sort_by(|a, b| a.metadata().compare(&b.metadata(), comparator.as_ref()));
+        Ok(SSTGroup {
+            sstables,
+            comparator,
+        })
     }
 
-    // Return the first found value which is also the latest value.
-    pub fn get(&self, key: &[u8]) -> Result<Option<ValueUpdate>> {
+    // Return the version with the largest seqno visible at `seqno` among all sstables.
+    pub fn get(&self, key: &[u8], seqno: u64) -> Result<Option<(InternalKey, ValueUpdate)>> {
+        let mut newest: Option<(InternalKey, ValueUpdate)> = None;
         for s in &self.sstables {
-            if let Some(update) = s.get(key)? {
-                return Ok(Some(update));
+            if let Some((k, v)) = s.get(key, seqno, self.comparator.as_ref())? {
+                let is_newer = match &newest {
+                    Some((newest_k, _)) => k.seqno > newest_k.seqno,
+                    None => true,
+                };
+                if is_newer {
+                    newest = Some((k, v));
+                }
             }
         }
-        Ok(None)
+        Ok(newest)
+    }
+
+    pub fn range_tombstones(&self) -> impl Iterator<Item = &RangeTombstone> {
+        self.sstables.iter().flat_map(|s| s.range_tombstones())
+    }
+
+    // See memtable::range_deleted_seqno().
+    pub fn range_deleted_seqno(&self, key: &[u8], seqno: u64) -> u64 {
+        memtable::range_deleted_seqno(
+            self.range_tombstones(),
+            self.comparator.as_ref(),
+            key,
+            seqno,
+        )
     }
 
     pub fn iter(&self) -> SSTGroupIter {
         SSTGroupIter {
             iter_list: self.sstables.iter().map(|s| s.iter().peekable()).collect(),
-            previous_key: Vec::new(),
+            previous_key: None,
+            comparator: self.comparator.as_ref(),
         }
     }
 
+    // `snapshots` are seqnos of live snapshots in ascending order.
     pub fn compact(
         &mut self,
         dest_level: u64,
         db_dir: &Path,
         manifest: &mut ManifestKeeper,
+        snapshots: &[u64],
+        options: &Options,
     ) -> Result<()> {
-        //  Requires: SSTables are ordered by timestamp. Younger ones are at the beginning.
-        //
         // Open all iterators.
-        // Compare with last key, ignore duplicate.
-        // Produce a single minimum key (young key preferred)
-        // Collect current items and filter out None.
+        // Merge them by internal key so that versions of a key come newest first.
+        // Keep the newest version in each snapshot stripe and filter out purgeable tombstones.
+        // Merge operands in a stripe are folded as far as possible.
+        // Versions covered by a range tombstone in the same stripe are dropped.
+        // Expired values are turned into tombstones, since they still hide older versions.
+        // The compaction filter sees values newer than every snapshot.
+        // Range tombstones are split at file boundaries so that each file only covers its own keys.
         //
         // Prepare the dest file.
         let ids = self.sstables.iter().map(|s| s.get_id()).collect::<Vec<_>>();
-        dbg!(format!("Compact ssts {ids:#?}"));
-        let mut sst_id = manifest.latest_sst_id(dest_level);
-        manifest.new_id(dest_level);
-        let mut file = sst_id.create_file(db_dir)?;
-
-        let mut index = SparseIndex::new();
-
-        let mut num_count = 0;
-        let mut offset = 0;
-        let mut previous_size = 0;
-        let mut previous_key = Vec::new();
-        let should_purge_tombstone = dest_level >= manifest.max_level();
-
-        for wrapped_kv in self.iter() {
-            let (k, v) = wrapped_kv?;
-            if v == ValueUpdate::Tombstone && should_purge_tombstone {
-                continue;
-            }
-            let encoded = bincode::encode_to_vec((&k, &v), config::standard())?;
-            // Check whether we should write to a new sstable file.
-            if offset + encoded.len() > SSTABLE_FILE_SIZE as usize {
-                // Write sparse index.
-                index.insert(previous_key, offset - previous_size);
-                let encoded = bincode::encode_to_vec(&index, config::standard())?;
-                file.write_all(&encoded)?;
-                file.write_all(&u64::to_be_bytes(encoded.len() as u64))?;
-                file.sync_all()?;
-                // Add it to manifest.
-                manifest.add(
-                    sst_id,
-                    index.first_key_value().unwrap().0,
-                    index.last_key_value().unwrap().0,
-                );
-                //
-                // Create a new sstable file.
-                // Reset per file variables.
-                sst_id = SstId {
-                    level: dest_level,
-                    id: sst_id.id + 1,
-                };
-                manifest.new_id(dest_level);
-                file = sst_id.create_file(db_dir)?;
-                index = SparseIndex::new();
-                num_count = 0;
-                offset = 0;
+        let cf = ids[0].cf;
+        ensure!(
+            ids.iter().all(|id| id.cf == cf),
+            "Compacted ssts should be in the same column family"
+        );
+        let mut sst_id = manifest.family(cf).latest_sst_id(dest_level);
+        manifest.new_id(cf, dest_level);
+        let comparator = self.comparator.clone();
+        let mut writer = SSTableWriter::new(sst_id.create_file(db_dir)?, comparator.clone());
+
+        // User key of the last written version.
+        let mut previous_key: Option<Vec<u8>> = None;
+        let should_purge_tombstone = dest_level >= manifest.family(cf).max_level();
+        // Tombstones in the oldest stripe hide nothing any snapshot could see.
+        let oldest_stripe = snapshot::stripe(snapshots, 0);
+        let merge_operator = options.merge_operator.as_deref();
+        let now = options.clock.now_millis();
+        let compaction_filter = options.compaction_filter.as_deref();
+        let (purged, range_tombstones): (Vec<_>, Vec<_>) =
+            self.range_tombstones().cloned().partition(|t| {
+                should_purge_tombstone && snapshot::stripe(snapshots, t.seqno) == oldest_stripe
+            });
+        let is_range_deleted = |k: &InternalKey| {
+            let stripe = snapshot::stripe(snapshots, k.seqno);
+            purged.iter().chain(&range_tombstones).any(|t| {
+                t.covers(comparator.as_ref(), &k.user_key, k.seqno)
+                    && snapshot::stripe(snapshots, t.seqno) == stripe
+            })
+        };
+        // Range tombstones in the current file are clipped to [lower_bound, next file's first key).
+        let mut lower_bound: Option<Vec<u8>> = None;
+
+        // Versions of a key in the same stripe, newest first.
+        let mut stripe_versions: Vec<(InternalKey, ValueUpdate)> = Vec::new();
+        let mut iter = self.iter();
+        loop {
+            let next = iter.next().transpose()?;
+            let stripe_ends = match (&stripe_versions.first(), &next) {
+                (Some((first, _)), Some((k, _))) => {
+                    first.user_key != k.user_key
+                        || snapshot::stripe(snapshots, first.seqno)
+                            != snapshot::stripe(snapshots, k.seqno)
+                }
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if stripe_ends {
+                let first = &stripe_versions[0].0;
+                let has_older = matches!(&next, Some((k, _)) if k.user_key == first.user_key);
+                let stripe = snapshot::stripe(snapshots, first.seqno);
+                let is_oldest_stripe = stripe == oldest_stripe;
+                let collapsed = merge::collapse(
+                    merge_operator,
+                    &stripe_versions,
+                    should_purge_tombstone && !has_older,
+                )?;
+                for (k, v) in collapsed {
+                    let (k, v) = match compaction_filter {
+                        Some(filter) if stripe.is_none() => compaction_filter::apply(
+                            filter,
+                            dest_level,
+                            should_purge_tombstone,
+                            k,
+                            v,
+                        ),
+                        _ => (k, v),
+                    };
+                    if v == ValueUpdate::Tombstone && should_purge_tombstone && is_oldest_stripe {
+                        continue;
+                    }
+                    // Check whether we should write to a new sstable file.
+                    // Versions of a key stay in the same file.
+                    let is_new_key = previous_key.as_ref() != Some(&k.user_key);
+                    if is_new_key && writer.size() >= SSTABLE_FILE_SIZE as usize {
+                        add_clipped(
+                            comparator.as_ref(),
+                            &mut writer,
+                            &range_tombstones,
+                            lower_bound.as_deref(),
+                            Some(&k.user_key),
+                        );
+                        lower_bound = Some(k.user_key.clone());
+                        let (first_key, last_key) = writer.finish()?;
+                        // Add it to manifest.
+                        manifest.add(sst_id, &first_key, &last_key);
+                        //
+                        // Create a new sstable file.
+                        sst_id = SstId {
+                            id: sst_id.id + 1,
+                            ..sst_id
+                        };
+                        manifest.new_id(cf, dest_level);
+                        writer =
+                            SSTableWriter::new(sst_id.create_file(db_dir)?, comparator.clone());
+                    }
+                    writer.add(&k, &v)?;
+                    previous_key = Some(k.user_key);
+                }
+                stripe_versions.clear();
             }
-            file.write_all(&encoded)?;
-            if num_count % SPARSE_INDEX_INTERVAL == 0 {
-                index.insert(k.clone(), offset);
+            match next {
+                Some((k, _)) if is_range_deleted(&k) => {}
+                Some((mut k, v)) if v.is_expired(now) => {
+                    k.kind = ValueKind::Tombstone;
+                    stripe_versions.push((k, ValueUpdate::Tombstone));
+                }
+                Some(kv) => stripe_versions.push(kv),
+                None => break,
             }
-            num_count += 1;
-            offset += encoded.len();
-            previous_size = encoded.len();
-            previous_key = k.clone();
-        }
-
-        // Add the last key to index.
-        index.insert(previous_key, offset - previous_size);
-
-        // Write sparse index.
-        let encoded = bincode::encode_to_vec(&index, config::standard())?;
-        file.write_all(&encoded)?;
-        file.write_all(&u64::to_be_bytes(encoded.len() as u64))?;
-        file.sync_all()?;
-        // Add it to manifest.
-        manifest.add(
-            sst_id,
-            index.first_key_value().unwrap().0,
-            index.last_key_value().unwrap().0,
+        }
+        add_clipped(
+            comparator.as_ref(),
+            &mut writer,
+            &range_tombstones,
+            lower_bound.as_deref(),
+            None,
         );
 
+        if writer.is_empty() {
+            // Everything is purged.
+            drop(writer);
+            SSTable::remove(db_dir, &sst_id)?;
+        } else {
+            let (first_key, last_key) = writer.finish()?;
+            // Add it to manifest.
+            manifest.add(sst_id, &first_key, &last_key);
+        }
+
+        // Retire inputs in the same commit, so outputs replace them atomically.
+        for id in &ids {
+            manifest.remove(id);
+        }
+
         // Finishing compaction.
         manifest.commit()?;
 
@@ -556,13 +895,42 @@ impl SSTGroup {
     }
 }
 
+// Add parts of `tombstones` in [lower, upper). None means unbounded.
+fn add_clipped(
+    cmp: &dyn Comparator,
+    writer: &mut SSTableWriter,
+    tombstones: &[RangeTombstone],
+    lower: Option<&[u8]>,
+    upper: Option<&[u8]>,
+) {
+    for t in tombstones {
+        let start = match lower {
+            Some(lower) if cmp.compare(lower, &t.start).is_gt() => lower.to_vec(),
+            _ => t.start.clone(),
+        };
+        let end = match upper {
+            Some(upper) if cmp.compare(upper, &t.end).is_lt() => upper.to_vec(),
+            _ => t.end.clone(),
+        };
+        if cmp.compare(&start, &end).is_lt() {
+            writer.add_range_tombstone(RangeTombstone {
+                start,
+                end,
+                seqno: t.seqno,
+            });
+        }
+    }
+}
+
+// Merge sstables by internal key.
 pub struct SSTGroupIter<'a> {
     iter_list: Vec<Peekable<SSTableIter<'a>>>,
-    previous_key: Vec<u8>,
+    previous_key: Option<InternalKey>,
+    comparator: &'a dyn Comparator,
 }
 
 impl<'a> Iterator for SSTGroupIter<'a> {
-    type Item = Result<(Vec<u8>, ValueUpdate)>;
+    type Item = Result<(InternalKey, ValueUpdate)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -578,15 +946,18 @@ impl<'a> Iterator for SSTGroupIter<'a> {
                     return Some(Err(anyhow!("Failed to decode entry in SSTable")));
                 }
                 kvs.iter()
-                    .min_by_key(|(_, res)| &res.as_ref().unwrap().0)
+                    .min_by(|(_, a), (_, b)| {
+                        self.comparator
+                            .compare_internal(&a.as_ref().unwrap().0, &b.as_ref().unwrap().0)
+                    })
                     .map(|(i, _)| *i)
             };
             if let Some(i) = min_index {
                 let item = self.iter_list[i].next().unwrap(); // Have peeked.
                 match item {
                     Ok((k, v)) => {
-                        if self.previous_key != k {
-                            self.previous_key = k.clone();
+                        if self.previous_key.as_ref() != Some(&k) {
+                            self.previous_key = Some(k.clone());
                             return Some(Ok((k, v)));
                         }
                     }
@@ -636,20 +1007,25 @@ impl<'a> Iterator for SSTGroupIter<'a> {
 
 pub struct GeneralCombinedIter {
     iter_list: Vec<Peekable<BoxedIter>>,
-    previous_key: Vec<u8>,
+    previous_key: Option<InternalKey>,
+    comparator: Arc<dyn Comparator>,
 }
 
 impl GeneralCombinedIter {
-    pub fn new(iters: Vec<BoxedIter>) -> Result<GeneralCombinedIter> {
+    pub fn new(
+        iters: Vec<BoxedIter>,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<GeneralCombinedIter> {
         Ok(GeneralCombinedIter {
             iter_list: iters.into_iter().map(|it| it.peekable()).collect(),
-            previous_key: Vec::new(),
+            previous_key: None,
+            comparator,
         })
     }
 }
 
 impl Iterator for GeneralCombinedIter {
-    type Item = (Vec<u8>, ValueUpdate);
+    type Item = (InternalKey, ValueUpdate);
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -659,12 +1035,14 @@ impl Iterator for GeneralCombinedIter {
                     .iter_mut()
                     .enumerate()
                     .filter_map(|(i, it)| it.peek().map(|peeked| (i, peeked)));
-                items.min_by_key(|(_, (k, _))| k).map(|(i, _)| i)
+                items
+                    .min_by(|(_, (a, _)), (_, (b, _))| self.comparator.compare_internal(a, b))
+                    .map(|(i, _)| i)
             };
             if let Some(i) = min_index {
                 let (k, v) = self.iter_list[i].next().unwrap();
-                if self.previous_key != k {
-                    self.previous_key = k.clone();
+                if self.previous_key.as_ref() != Some(&k) {
+                    self.previous_key = Some(k.clone());
                     return Some((k, v));
                 }
             } else {
@@ -678,19 +1056,21 @@ impl Iterator for GeneralCombinedIter {
 #[cfg(test)]
 mod tests {
 
+    use crate::comparator::BytewiseComparator;
     use crate::manifest::*;
     use crate::memtable::ValueUpdate;
     use crate::memtable::*;
     use crate::sstable::*;
     use crate::test_util::*;
 
-    use anyhow::{anyhow, bail, Result};
+    use anyhow::{anyhow, bail, ensure, Result};
     use rand::Rng;
 
-    fn new_random_memtable() -> MemTable {
-        let mut memtable = MemTable::new();
+    // Seqnos start from `first_seqno`.
+    fn new_random_memtable(first_seqno: u64) -> MemTable {
+        let mut memtable = MemTable::new(Arc::new(BytewiseComparator));
         // 512
-        for _ in 0..512 {
+        for seqno in first_seqno..first_seqno + 512 {
             // 10
             let key = get_random_bytes(1, 10);
             let update = if rand::thread_rng().gen::<f64>() > 0.5 {
@@ -699,7 +1079,7 @@ mod tests {
                 // 10
                 ValueUpdate::Value(get_random_bytes(1, usize::pow(2, 10)))
             };
-            memtable.insert(key, update);
+            memtable.insert(InternalKey::new(key, seqno, update.kind()), update);
         }
         memtable
     }
@@ -708,15 +1088,24 @@ mod tests {
     #[test]
     fn write_flush_read() -> Result<()> {
         // Prepare data to flush.
-        let memtable = new_random_memtable();
+        let memtable = new_random_memtable(1);
 
         // Flush memtable to level 0 SStable file.
         let test_dir_path = create_test_dir()?;
-        SSTable::flush_to_level0_without_manifest(&memtable, &test_dir_path, 0)?;
+        let sst_id = SstId {
+            cf: 0,
+            level: 0,
+            id: 0,
+        };
+        SSTable::flush_to_level0_without_manifest(
+            &memtable,
+            &test_dir_path,
+            sst_id,
+            Arc::new(BytewiseComparator),
+        )?;
 
         // Load SStable file and check data.
-        let sst_id = SstId { level: 0, id: 0 };
-        let sst = SSTable::load_by_id(&sst_id, &test_dir_path)?;
+        let sst = SSTable::load_by_id(&sst_id, &test_dir_path, &BytewiseComparator)?;
         if !sst
             .iter()
             .map(|wrapped| wrapped.unwrap())
@@ -725,13 +1114,14 @@ mod tests {
             bail!("Loaded SST file has different iterator than MemTable's: \nSSTable={:#?}\nMemTable={:#?}", sst.iter().collect::<Vec<_>>(), memtable.iter().collect::<Vec<_>>());
         }
 
-        // Compare using SSTable::get().
+        // Compare every version using SSTable::get().
         for (k, v) in memtable.iter() {
-            if &sst
-                .get(k)?
-                .ok_or_else(|| anyhow!("No requested key in SSTable according to SSTable::get()"))?
-                != v
-            {
+            let (sk, sv) = sst
+                .get(&k.user_key, k.seqno, &BytewiseComparator)?
+                .ok_or_else(|| {
+                    anyhow!("No requested key in SSTable according to SSTable::get()")
+                })?;
+            if (&sk, &sv) != (k, v) || memtable.get(&k.user_key, k.seqno) != Some((k, v)) {
                 bail!("Some pair is missing in the loaded SST file according to SSTable::get()");
             }
         }
@@ -787,39 +1177,116 @@ mod tests {
         //
         let test_dir_path = create_test_dir()?;
 
-        let mut manifest = ManifestKeeper::new(&test_dir_path)?;
+        let mut manifest = ManifestKeeper::new(&test_dir_path, Arc::new(BytewiseComparator))?;
         // Compact 4 level 0 SSTables.
-        for _ in 0..4 {
-            let memtable = new_random_memtable();
-            let sst_id = manifest.latest_sst_id(0);
-            manifest.new_id(0);
-            SSTable::flush_to_level0_without_manifest(&memtable, &test_dir_path, sst_id.id)?;
-            manifest.commit()?;
+        flush_random_level0(&mut manifest, &test_dir_path, 4, 1)?;
+        let old_sst_ids = manifest.active_sst_ids();
+        // Keep old sstable files for comparison.
+        let pinned = manifest.current_version();
+        let comparator = manifest.comparator().clone();
+        SSTGroup::new(
+            &manifest.family(0).get_sst_by_level(0),
+            &test_dir_path,
+            comparator.clone(),
+        )?
+        .compact(1, &test_dir_path, &mut manifest, &[], &Options::default())?;
+        check_compacted(&manifest, &old_sst_ids, &test_dir_path)?;
+        drop(pinned);
+        for id in &old_sst_ids {
+            ensure!(
+                !id.path(&test_dir_path).exists(),
+                "Retired sstable file {id:#?} is not deleted"
+            );
         }
-        // Will change active sstables.
+
+        // Compact 4 level 0 SSTables and their overlapping level 1 SSTables.
+        flush_random_level0(&mut manifest, &test_dir_path, 4, 4 * 512 + 1)?;
         let old_sst_ids = manifest.active_sst_ids();
-        SSTGroup::new(&manifest.get_sst_by_level(0), &test_dir_path)?.compact(
+        let _pinned = manifest.current_version();
+        let mut inputs = manifest.family(0).get_sst_by_level(0);
+        for id in manifest.family(0).get_sst_by_level(0) {
+            inputs.extend(
+                manifest
+                    .family(0)
+                    .get_overlappings(&id, comparator.as_ref()),
+            );
+        }
+        inputs.sort();
+        inputs.dedup();
+        SSTGroup::new(&inputs, &test_dir_path, comparator)?.compact(
             1,
             &test_dir_path,
             &mut manifest,
+            &[],
+            &Options::default(),
         )?;
+        check_compacted(&manifest, &old_sst_ids, &test_dir_path)?;
+        Ok(())
+    }
 
-        // Load previous sstable files.
-        let old_group = SSTGroup::new(&old_sst_ids, &test_dir_path)?;
-        let old_combined_iter = old_group.iter();
-        // Load current active sstable files.
-        let sst_ids = manifest.active_sst_ids();
-        let new_group = SSTGroup::new(&sst_ids, &test_dir_path)?;
-        let combined_iter = new_group.iter();
+    fn flush_random_level0(
+        manifest: &mut ManifestKeeper,
+        test_dir_path: &Path,
+        count: u64,
+        first_seqno: u64,
+    ) -> Result<()> {
+        for i in 0..count {
+            let memtable = new_random_memtable(first_seqno + i * 512);
+            manifest.batch_start();
+            let sst_id = manifest.family(0).latest_sst_id(0);
+            manifest.new_id(0, 0);
+            let (first_key, last_key) = SSTable::flush_to_level0_without_manifest(
+                &memtable,
+                test_dir_path,
+                sst_id,
+                manifest.comparator().clone(),
+            )?;
+            manifest.add(sst_id, &first_key, &last_key);
+            manifest.commit()?;
+        }
+        Ok(())
+    }
 
-        if !old_combined_iter.eq_by(combined_iter, |kv1, kv2| kv1.unwrap() == kv2.unwrap()) {
-            bail!("SSTables files not equal after compaction");
+    // All of `old_sst_ids` are compacted into level 1.
+    fn check_compacted(
+        manifest: &ManifestKeeper,
+        old_sst_ids: &[SstId],
+        test_dir_path: &Path,
+    ) -> Result<()> {
+        ensure!(
+            manifest.family(0).get_sst_by_level(0).is_empty(),
+            "Level 0 is not empty after compaction"
+        );
+        ensure!(
+            !manifest.family(0).get_sst_by_level(1).is_empty(),
+            "Level 1 is empty after compaction"
+        );
+        let sst_ids = manifest.active_sst_ids();
+        for id in old_sst_ids {
+            ensure!(
+                !sst_ids.contains(id),
+                "Input sstable {id:#?} is still active"
+            );
         }
 
-        //
-        // Compact 4 level 0 SSTables and 10 level 1 SSTables.
-        //
-        // Compact 1 level 1 and its overlapping level 2 SSTables.
+        // Older versions and tombstones may be dropped,
+        // so compare the latest value of every key.
+        let comparator = manifest.comparator();
+        let old_group = SSTGroup::new(old_sst_ids, test_dir_path, comparator.clone())?;
+        let new_group = SSTGroup::new(&sst_ids, test_dir_path, comparator.clone())?;
+        let latest = |group: &SSTGroup, key: &[u8]| -> Result<Option<Vec<u8>>> {
+            Ok(match group.get(key, u64::MAX)? {
+                Some((_, ValueUpdate::Value(v))) => Some(v),
+                _ => None,
+            })
+        };
+        for kv in old_group.iter() {
+            let (k, _) = kv?;
+            ensure!(
+                latest(&old_group, &k.user_key)? == latest(&new_group, &k.user_key)?,
+                "SSTables files not equal after compaction"
+            );
+        }
         Ok(())
     }
 
@@ -831,33 +1298,52 @@ mod tests {
     fn test_level_iterator() -> Result<()> {
         let test_dir_path = create_test_dir()?;
 
-        let mut manifest = ManifestKeeper::new(&test_dir_path)?;
+        let mut manifest = ManifestKeeper::new(&test_dir_path, Arc::new(BytewiseComparator))?;
         // Compact 4 level 0 SSTables.
         let mut sst_ids = Vec::new();
-        for _ in 0..4 {
-            let memtable = new_random_memtable();
-            let sst_id = manifest.latest_sst_id(0);
-            manifest.new_id(0);
-            SSTable::flush_to_level0_without_manifest(&memtable, &test_dir_path, sst_id.id)?;
+        for i in 0..4 {
+            let memtable = new_random_memtable(i * 512 + 1);
+            let sst_id = manifest.family(0).latest_sst_id(0);
+            manifest.new_id(0, 0);
+            SSTable::flush_to_level0_without_manifest(
+                &memtable,
+                &test_dir_path,
+                sst_id,
+                manifest.comparator().clone(),
+            )?;
             sst_ids.push(sst_id);
             manifest.commit()?;
         }
         // Will change active sstables.
-        SSTGroup::new(&sst_ids, &test_dir_path)?.compact(1, &test_dir_path, &mut manifest)?;
+        let comparator = manifest.comparator().clone();
+        SSTGroup::new(&sst_ids, &test_dir_path, comparator.clone())?.compact(
+            1,
+            &test_dir_path,
+            &mut manifest,
+            &[],
+            &Options::default(),
+        )?;
 
         // Compare data with/out lazy loading.
-        let sst_group = SSTGroup::new(&manifest.get_sst_by_level(1), &test_dir_path)?;
+        let sst_group = SSTGroup::new(
+            &manifest.family(0).get_sst_by_level(1),
+            &test_dir_path,
+            comparator.clone(),
+        )?;
         let non_lazy_iter = sst_group.iter();
 
         let sst_level_group = SSTLevelGroup::new(
+            0,
             1,
             &manifest
+                .family(0)
                 .get_sst_by_level(1)
                 .iter()
                 .map(|si| si.id)
                 .collect::<Vec<_>>(),
             &test_dir_path,
-            &manifest,
+            manifest.current_version(),
+            comparator,
         )?;
         let lazy_iter = sst_level_group.iter();
 
@@ -1,76 +1,807 @@
 // For simplcity, we flush memtable if it contains more than certain number of items.
+use crate::column_family::*;
+use crate::comparator::{builtin_comparator, Comparator};
+use crate::lock::LockManager;
 use crate::manifest::*;
 use crate::memtable::*;
+use crate::merge::{self, MergeOperator};
+use crate::options::Options;
+use crate::repair::{self, RepairReport};
+use crate::snapshot::*;
 use crate::sstable::*;
+use crate::transaction::*;
+use crate::verify::{self, VerifyReport};
+use crate::version::Version;
+use std::collections::BTreeMap;
 use std::fs;
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, ensure, Context, Result};
 use growable_bloom_filter::GrowableBloom;
-use skiplist::skipmap;
+use ouroboros::self_referencing;
+use skiplist::ordered_skiplist;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CasResult {
+    Swapped,
+    // Nothing is written. Holds the current value.
+    Mismatch(Option<Vec<u8>>),
+}
+
+// Sizes of a column family, as reported by Store::stats().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FamilyStats {
+    pub name: String,
+    pub memtable_bytes: u64,
+    // (level, number of ssts, bytes) of non-empty levels.
+    pub levels: Vec<(u64, usize, u64)>,
+}
+
+// Counters since the store is opened, as reported by Store::io_stats().
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoStats {
+    // Bytes of keys and values in writes.
+    pub user_bytes_written: u64,
+    // Bytes appended to the memtable log.
+    pub log_bytes_written: u64,
+    // Bytes of ssts added by flushes, compactions and ingestion.
+    pub sst_bytes_written: u64,
+    pub gets: u64,
+    // Ssts searched by gets that the bloom filter and memtable don't answer.
+    pub ssts_read: u64,
+}
+
+impl IoStats {
+    // Bytes written to the memtable log and ssts per byte written by users.
+    pub fn write_amplification(&self) -> f64 {
+        (self.log_bytes_written + self.sst_bytes_written) as f64
+            / u64::max(self.user_bytes_written, 1) as f64
+    }
+
+    // Ssts searched per get.
+    pub fn read_amplification(&self) -> f64 {
+        self.ssts_read as f64 / u64::max(self.gets, 1) as f64
+    }
+
+    // Counters accumulated after `earlier`.
+    pub fn since(&self, earlier: &IoStats) -> IoStats {
+        IoStats {
+            user_bytes_written: self.user_bytes_written - earlier.user_bytes_written,
+            log_bytes_written: self.log_bytes_written - earlier.log_bytes_written,
+            sst_bytes_written: self.sst_bytes_written - earlier.sst_bytes_written,
+            gets: self.gets - earlier.gets,
+            ssts_read: self.ssts_read - earlier.ssts_read,
+        }
+    }
+}
 
 pub struct Store {
     memtable: MemTableKeeper,
     manifest: ManifestKeeper,
-    bloom: GrowableBloom,
+    families: BTreeMap<u32, Family>,
     dir: PathBuf,
+    last_seqno: u64, // seqno of the latest write.
+    snapshots: SnapshotList,
+    locks: Arc<LockManager>,
+    user_bytes_written: u64,
+    // Updated by reads, which only borrow the store.
+    gets: AtomicU64,
+    ssts_read: AtomicU64,
+}
+
+// In-memory state of a column family.
+struct Family {
+    options: Options,
+    // False for a family recovered without its options, e.g. its merge operator,
+    // which isn't compacted until open_column_family() gives them.
+    options_set: bool,
+    bloom: GrowableBloom,
+}
+
+impl Family {
+    fn new(options: Options) -> Family {
+        Family {
+            options,
+            options_set: true,
+            bloom: GrowableBloom::new(0.05, 4096),
+        }
+    }
 }
 
 impl Store {
     pub fn new(store_dir: &Path) -> Result<Store> {
+        Self::new_with_options(store_dir, Options::default())
+    }
+
+    // `options` are for the default column family.
+    pub fn new_with_options(store_dir: &Path, options: Options) -> Result<Store> {
         fs::create_dir_all(store_dir)?;
+        let mut memtable = MemTableKeeper::new(store_dir, options.comparator.clone())?;
+        memtable.add_family(DEFAULT_COLUMN_FAMILY);
         Ok(Store {
-            memtable: MemTableKeeper::new(store_dir)?,
-            manifest: ManifestKeeper::new(store_dir)?,
-            bloom: GrowableBloom::new(0.05, 4096),
+            memtable,
+            manifest: ManifestKeeper::new(store_dir, options.comparator.clone())?,
+            families: BTreeMap::from([(DEFAULT_COLUMN_FAMILY, Family::new(options))]),
             dir: store_dir.to_path_buf(),
+            last_seqno: 0,
+            snapshots: SnapshotList::new(),
+            locks: Arc::new(LockManager::new()),
+            user_bytes_written: 0,
+            gets: AtomicU64::new(0),
+            ssts_read: AtomicU64::new(0),
         })
     }
 
     pub fn recover(store_dir: &Path) -> Result<Store> {
-        todo!()
+        Self::recover_with_options(store_dir, Options::default())
+    }
+
+    // Recover the store in `store_dir` if there is one, otherwise create it.
+    pub fn open(store_dir: &Path) -> Result<Store> {
+        Self::open_with_options(store_dir, Options::default())
+    }
+
+    pub fn open_with_options(store_dir: &Path, options: Options) -> Result<Store> {
+        if ManifestKeeper::exists(store_dir) {
+            Self::recover_with_options(store_dir, options)
+        } else {
+            Self::new_with_options(store_dir, options)
+        }
+    }
+
+    // `options` should match those the store is created with, and its comparator must.
+    // Other column families get default options and aren't compacted until open_column_family()
+    // gives their own, since merging and filtering depend on them.
+    pub fn recover_with_options(store_dir: &Path, options: Options) -> Result<Store> {
+        // Check the comparator in manifest before replaying the log with it.
+        let manifest = ManifestKeeper::recover(store_dir, options.comparator.clone())?;
+        let mut memtable = MemTableKeeper::recover(store_dir, options.comparator.clone())?;
+        // Flushed writes are recorded in manifest, the others are still in memtable.
+        let last_seqno = u64::max(manifest.last_seqno(), memtable.last_seqno());
+
+        let mut families = BTreeMap::new();
+        let mut options = Some(options);
+        for (_, cf) in manifest.family_names() {
+            memtable.add_family(cf);
+            let mut family = if cf == DEFAULT_COLUMN_FAMILY {
+                Family::new(options.take().unwrap())
+            } else {
+                Family {
+                    options_set: false,
+                    ..Family::new(Options {
+                        comparator: manifest.comparator().clone(),
+                        ..Options::default()
+                    })
+                }
+            };
+
+            // Bloom filter isn't persisted. Rebuild it from all keys.
+            for (k, _) in memtable.iter(cf) {
+                family.bloom.insert(&k.user_key);
+            }
+            let group = SSTGroup::new(
+                &manifest.family(cf).active_sst_ids(),
+                store_dir,
+                manifest.comparator().clone(),
+            )?;
+            for wrapped_kv in group.iter() {
+                family.bloom.insert(&wrapped_kv?.0.user_key);
+            }
+            families.insert(cf, family);
+        }
+
+        Ok(Store {
+            memtable,
+            manifest,
+            families,
+            dir: store_dir.to_path_buf(),
+            last_seqno,
+            snapshots: SnapshotList::new(),
+            locks: Arc::new(LockManager::new()),
+            user_bytes_written: 0,
+            gets: AtomicU64::new(0),
+            ssts_read: AtomicU64::new(0),
+        })
+    }
+
+    // Check the files of a closed store without changing them.
+    // The comparator is the built-in one named in manifest.
+    pub fn verify(store_dir: &Path) -> Result<VerifyReport> {
+        let name = ManifestDump::read(store_dir)?
+            .snapshot
+            .comparator_name()
+            .to_owned();
+        let comparator = builtin_comparator(&name)
+            .ok_or_else(|| anyhow!("Store uses comparator {name}, verify it with its options"))?;
+        verify::verify(store_dir, comparator)
+    }
+
+    // Only the comparator of `options` is used.
+    pub fn verify_with_options(store_dir: &Path, options: Options) -> Result<VerifyReport> {
+        let name = ManifestDump::read(store_dir)?
+            .snapshot
+            .comparator_name()
+            .to_owned();
+        ensure!(
+            name == options.comparator.name(),
+            "Store is created with comparator {name}, but verified with {}",
+            options.comparator.name()
+        );
+        verify::verify(store_dir, options.comparator)
+    }
+
+    // Rebuild manifest from the sst files and memtable log of a closed store.
+    // Keys are ordered by the comparator of default options.
+    pub fn repair(store_dir: &Path) -> Result<RepairReport> {
+        Self::repair_with_options(store_dir, Options::default())
+    }
+
+    // Only the comparator of `options` is used.
+    pub fn repair_with_options(store_dir: &Path, options: Options) -> Result<RepairReport> {
+        repair::repair(store_dir, options.comparator)
     }
 
     pub fn workdir(&self) -> PathBuf {
         self.dir.clone()
     }
 
+    pub fn last_seqno(&self) -> u64 {
+        self.last_seqno
+    }
+
+    fn next_seqno(&mut self) -> u64 {
+        self.last_seqno += 1;
+        self.last_seqno
+    }
+
+    // Reads at the returned snapshot see the store as of now until it's dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshots.acquire(self.last_seqno)
+    }
+
+    pub fn default_column_family(&self) -> ColumnFamily {
+        ColumnFamily::new(DEFAULT_COLUMN_FAMILY)
+    }
+
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamily> {
+        self.manifest.family_id(name).map(ColumnFamily::new)
+    }
+
+    // Create the family if it doesn't exist, otherwise replace its options.
+    // Options aren't persisted, so reopen families with their options after recovery.
+    // The comparator is shared by all families, so it should be the store's.
+    pub fn open_column_family(&mut self, name: &str, options: Options) -> Result<ColumnFamily> {
+        ensure!(
+            options.comparator.name() == self.manifest.comparator_name(),
+            "Column family {name} uses comparator {}, but the store uses {}",
+            options.comparator.name(),
+            self.manifest.comparator_name()
+        );
+        if let Some(cf) = self.manifest.family_id(name) {
+            let family = self.families.get_mut(&cf).unwrap();
+            family.options = options;
+            family.options_set = true;
+            return Ok(ColumnFamily::new(cf));
+        }
+        let cf = self.manifest.next_family_id();
+        self.manifest.batch_start();
+        self.manifest.create_family(cf, name);
+        self.manifest.commit()?;
+        self.memtable.add_family(cf);
+        self.families.insert(cf, Family::new(options));
+        Ok(ColumnFamily::new(cf))
+    }
+
+    pub fn comparator(&self) -> &dyn Comparator {
+        self.manifest.comparator().as_ref()
+    }
+
+    // For building tables to ingest, e.g. with SSTable::write_external().
+    pub fn shared_comparator(&self) -> Arc<dyn Comparator> {
+        self.manifest.comparator().clone()
+    }
+
+    fn family(&self, cf: u32) -> Result<&Family> {
+        self.families
+            .get(&cf)
+            .ok_or_else(|| anyhow!("Column family {cf} doesn't exist"))
+    }
+
     pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        self.bloom.insert(&key);
-        self.memtable.insert(key, ValueUpdate::Value(value));
-        self.memtable.commit()?;
-        self.checked_flush()?;
-        Ok(())
+        self.insert_cf(&self.default_column_family(), key, value)
+    }
+
+    pub fn insert_cf(&mut self, cf: &ColumnFamily, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.insert(cf, key, value);
+        self.write(batch)
+    }
+
+    // Blind write folded onto the current value by the merge operator.
+    pub fn merge(&mut self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        self.merge_cf(&self.default_column_family(), key, operand)
+    }
+
+    pub fn merge_cf(&mut self, cf: &ColumnFamily, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge(cf, key, operand);
+        self.write(batch)
+    }
+
+    // The entry reads as deleted once `ttl` passes by the clock in options.
+    // Ttls too long to represent never expire.
+    pub fn insert_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let clock = &self.family(DEFAULT_COLUMN_FAMILY)?.options.clock;
+        let expire_at = i64::try_from(ttl.as_millis())
+            .ok()
+            .and_then(|ttl| clock.now_millis().checked_add(ttl))
+            .unwrap_or(i64::MAX);
+        self.write_batch(vec![(key, ValueUpdate::Expiring(value, expire_at))])
     }
 
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        if !self.bloom.contains(key) {
+        self.get_at_seqno(DEFAULT_COLUMN_FAMILY, key, self.last_seqno)
+    }
+
+    pub fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_at_seqno(cf.id(), key, self.last_seqno)
+    }
+
+    pub fn get_at(&self, key: &[u8], snapshot: &Snapshot) -> Result<Option<Vec<u8>>> {
+        self.get_at_seqno(DEFAULT_COLUMN_FAMILY, key, snapshot.seqno())
+    }
+
+    fn get_at_seqno(&self, cf: u32, key: &[u8], seqno: u64) -> Result<Option<Vec<u8>>> {
+        self.gets.fetch_add(1, atomic::Ordering::Relaxed);
+        match self.get_entry(cf, key, seqno)? {
+            Some((_, ValueUpdate::Value(v))) => Ok(Some(v)),
+            // get_entry() only returns values and tombstones.
+            _ => Ok(None),
+        }
+    }
+
+    // The newest version of `key` visible at `seqno`, tombstones included.
+    // Merge operands are folded and expired values are tombstones,
+    // so the result is either a value or a tombstone.
+    fn get_entry(&self, cf: u32, key: &[u8], seqno: u64) -> Result<Option<(u64, ValueUpdate)>> {
+        let family = self.family(cf)?;
+        if !family.bloom.contains(key) {
             return Ok(None);
         }
 
         // The key possibly exists.
-        // Check memtable and then sstables.
-        match self.memtable.get(&key.to_vec()) {
-            Some(update) => match update {
-                ValueUpdate::Value(v) => Ok(Some(v.clone())),
-                ValueUpdate::Tombstone => Ok(None),
-            },
-            None => {
-                let group = SSTGroup::new(&self.manifest.get_sst_by_key(key), &self.dir)?;
-                match group.get(key)? {
-                    Some(ValueUpdate::Tombstone) | None => Ok(None),
-                    Some(ValueUpdate::Value(v)) => Ok(Some(v)),
+        // Check memtable and then sstables, walking down while versions are merges.
+        // A version older than a range tombstone containing the key is a tombstone.
+        // Range tombstones in sstables are older than anything in memtable.
+        let version = self.manifest.current_version();
+        let read_seqno = seqno;
+        let now = family.options.clock.now_millis();
+        let mut range_deleted = range_deleted_seqno(
+            self.memtable.range_tombstones(cf),
+            self.comparator(),
+            key,
+            read_seqno,
+        );
+        let mut group = None;
+        let mut operands = Vec::new();
+        let mut newest_seqno = None;
+        let mut seqno = seqno;
+        let base = loop {
+            let entry = match self.memtable.get(cf, key, seqno) {
+                Some((k, update)) => Some((k.seqno, update.clone())),
+                None => {
+                    if group.is_none() {
+                        let ids = version.family(cf).get_sst_by_key(key, self.comparator());
+                        self.ssts_read
+                            .fetch_add(ids.len() as u64, atomic::Ordering::Relaxed);
+                        let loaded =
+                            SSTGroup::new(&ids, &self.dir, self.manifest.comparator().clone())?;
+                        range_deleted =
+                            range_deleted.max(loaded.range_deleted_seqno(key, read_seqno));
+                        group = Some(loaded);
+                    }
+                    let group = group.as_ref().unwrap();
+                    group.get(key, seqno)?.map(|(k, v)| (k.seqno, v))
+                }
+            };
+            let entry = match entry {
+                Some((entry_seqno, _)) if entry_seqno < range_deleted => {
+                    Some((range_deleted, ValueUpdate::Tombstone))
                 }
+                entry => entry.map(|(entry_seqno, update)| (entry_seqno, update.resolve_ttl(now))),
+            };
+            match entry {
+                Some((entry_seqno, ValueUpdate::Merge(operand))) => {
+                    newest_seqno.get_or_insert(entry_seqno);
+                    operands.push(operand);
+                    if entry_seqno == 0 {
+                        break None;
+                    }
+                    seqno = entry_seqno - 1;
+                }
+                Some((entry_seqno, update)) if operands.is_empty() => {
+                    return Ok(Some((entry_seqno, update)));
+                }
+                None if operands.is_empty() => return Ok(None),
+                entry => break entry.map(|(_, update)| update),
             }
+        };
+        let existing = match &base {
+            Some(ValueUpdate::Value(v)) => Some(&v[..]),
+            _ => None,
+        };
+        let merge_operator = family.options.merge_operator.as_deref();
+        let merged = merge::full_merge(merge_operator, key, &operands, existing)?;
+        Ok(Some((newest_seqno.unwrap(), ValueUpdate::Value(merged))))
+    }
+
+    // Whether `key` is written after `seqno`.
+    pub(crate) fn changed_since(&self, key: &[u8], seqno: u64) -> Result<bool> {
+        Ok(
+            match self.get_entry(DEFAULT_COLUMN_FAMILY, key, u64::MAX)? {
+                Some((latest, _)) => latest > seqno,
+                None => false,
+            },
+        )
+    }
+
+    // Apply writes in a single memtable commit, so they are recovered all or nothing,
+    // even if they span column families.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        let writes = batch.into_writes();
+        for (cf, _, update) in &writes {
+            let family = self.family(*cf)?;
+            ensure!(
+                update.kind() != ValueKind::Merge || family.options.merge_operator.is_some(),
+                "Merge requires a merge operator in options of column family {cf}"
+            );
         }
+        if writes.is_empty() {
+            return Ok(());
+        }
+        for (cf, key, update) in writes {
+            self.user_bytes_written += (key.len() + update.value_len()) as u64;
+            if update != ValueUpdate::Tombstone {
+                self.families.get_mut(&cf).unwrap().bloom.insert(&key);
+            }
+            let seqno = self.next_seqno();
+            self.memtable.insert(cf, key, seqno, update);
+        }
+        self.memtable.commit()?;
+        self.checked_flush()?;
+        Ok(())
     }
 
-    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
-        self.memtable.insert(key.to_vec(), ValueUpdate::Tombstone);
+    // Write to the default column family. See write().
+    pub(crate) fn write_batch(&mut self, writes: Vec<(Vec<u8>, ValueUpdate)>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, update) in writes {
+            batch.push(DEFAULT_COLUMN_FAMILY, key, update);
+        }
+        self.write(batch)
+    }
+
+    // Write `new` only if the current value equals `expected`. None means absent.
+    // Reads and writes happen under &mut self, so nothing can interleave.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasResult> {
+        let current = self.get(key)?;
+        if current.as_deref() != expected {
+            return Ok(CasResult::Mismatch(current));
+        }
+        let update = match new {
+            Some(value) => ValueUpdate::Value(value),
+            None => ValueUpdate::Tombstone,
+        };
+        self.write_batch(vec![(key.to_vec(), update)])?;
+        Ok(CasResult::Swapped)
+    }
+
+    pub fn put_if_absent(&mut self, key: &[u8], value: Vec<u8>) -> Result<CasResult> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    // Start a transaction reading at the current seqno.
+    pub fn begin_optimistic(&self) -> OptimisticTransaction {
+        OptimisticTransaction::new(self.snapshot())
+    }
+
+    // Start a transaction locking keys it touches.
+    pub fn begin_pessimistic(&self) -> PessimisticTransaction {
+        PessimisticTransaction::new(self.locks.clone())
+    }
+
+    // Delete every key in [start, end) with a single range tombstone.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> Result<()> {
+        ensure!(
+            self.comparator().compare(start, end).is_lt(),
+            "delete_range requires start < end"
+        );
+        let seqno = self.next_seqno();
+        self.user_bytes_written += (start.len() + end.len()) as u64;
+        self.memtable
+            .delete_range(DEFAULT_COLUMN_FAMILY, start.to_vec(), end.to_vec(), seqno);
+        self.memtable.commit()?;
         self.checked_flush()?;
         Ok(())
     }
 
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.remove_cf(&self.default_column_family(), key)
+    }
+
+    pub fn remove_cf(&mut self, cf: &ColumnFamily, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.remove(cf, key);
+        self.write(batch)
+    }
+
+    // Bulk load table files built by SSTable::write_external().
+    // Each file goes to the deepest level that neither it nor any level above overlaps.
+    // Files overlapping level 0 or each other fall back to level 0, after flushing the memtable if it overlaps too.
+    // Every file gets a new seqno so that it shadows older versions of its keys.
+    // All files are added in a single manifest commit, to the default column family.
+    // Once committed they are ingested, even if the compaction that follows fails,
+    // which the next flush or compact() retries.
+    pub fn ingest_external_files(&mut self, paths: &[PathBuf]) -> Result<Vec<SstId>> {
+        // Validate every file before touching the store.
+        let mut files = Vec::new();
+        for path in paths {
+            let sst = SSTable::load_from_path(
+                path,
+                &SstId {
+                    cf: DEFAULT_COLUMN_FAMILY,
+                    level: 0,
+                    id: 0,
+                },
+                self.comparator(),
+            )
+            .with_context(|| format!("Failed to load external SST {path:?}"))?;
+            sst.validate(self.comparator())
+                .with_context(|| format!("External SST {path:?} is invalid"))?;
+            let first_key = sst.metadata().first_key.to_vec();
+            let last_key = sst.metadata().last_key.to_vec();
+            files.push((sst, first_key, last_key));
+        }
+
+        // Only level 0 keeps the relative order of overlapping files.
+        let cmp = self.comparator();
+        let overlap_each_other = files.iter().enumerate().any(|(i, (_, s1, e1))| {
+            files[i + 1..]
+                .iter()
+                .any(|(_, s2, e2)| cmp.compare(e1, s2).is_ge() && cmp.compare(s1, e2).is_le())
+        });
+        // Ingested data is newer than the memtable, so the memtable goes to level 0 first.
+        let memtable_overlaps = files.iter().any(|(_, start, end)| {
+            self.memtable.iter(DEFAULT_COLUMN_FAMILY).any(|(k, _)| {
+                cmp.compare(&k.user_key, start).is_ge() && cmp.compare(&k.user_key, end).is_le()
+            })
+        });
+        if memtable_overlaps {
+            SSTable::flush_to_level0(&mut self.memtable, &self.dir, &mut self.manifest)?;
+        }
+
+        self.manifest.batch_start();
+        let mut next_ids: BTreeMap<u64, SstId> = BTreeMap::new();
+        let mut ingested = Vec::new();
+        for (sst, first_key, last_key) in &files {
+            let level = if overlap_each_other {
+                0
+            } else {
+                self.ingest_level(first_key, last_key)
+            };
+            let sst_id = *next_ids
+                .entry(level)
+                .and_modify(|id| id.id += 1)
+                .or_insert_with(|| {
+                    self.manifest
+                        .family(DEFAULT_COLUMN_FAMILY)
+                        .latest_sst_id(level)
+                });
+            self.manifest.new_id(DEFAULT_COLUMN_FAMILY, level);
+
+            // Rewrite records with the assigned seqno.
+            let seqno = self.next_seqno();
+            let bloom = &mut self.families.get_mut(&DEFAULT_COLUMN_FAMILY).unwrap().bloom;
+            let mut writer = SSTableWriter::new(
+                sst_id.create_file(&self.dir)?,
+                self.manifest.comparator().clone(),
+            );
+            for wrapped_kv in sst.iter() {
+                let (mut k, v) = wrapped_kv?;
+                bloom.insert(&k.user_key);
+                k.seqno = seqno;
+                writer.add(&k, &v)?;
+            }
+            for tombstone in sst.range_tombstones() {
+                writer.add_range_tombstone(RangeTombstone {
+                    seqno,
+                    ..tombstone.clone()
+                });
+            }
+            writer.finish()?;
+
+            self.manifest.add(sst_id, first_key, last_key);
+            ingested.push(sst_id);
+        }
+        self.manifest.set_last_seqno(self.last_seqno);
+        self.manifest.commit()?;
+
+        // A failure is left to the retry, which returns it.
+        let _ = self.try_compact();
+        Ok(ingested)
+    }
+
+    // Deepest level where [start, end] overlaps no sst in it or above.
+    fn ingest_level(&self, start: &[u8], end: &[u8]) -> u64 {
+        let family = self.manifest.family(DEFAULT_COLUMN_FAMILY);
+        let cmp = self.comparator();
+        if family.level_overlaps(0, start, end, cmp) {
+            return 0;
+        }
+        let mut level = 0;
+        for l in 1..=u64::max(family.max_level(), 1) {
+            if family.level_overlaps(l, start, end, cmp) {
+                break;
+            }
+            level = l;
+        }
+        level
+    }
+
+    // Flush memtable to level 0 regardless of its size.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.memtable.is_empty() {
+            SSTable::flush_to_level0(&mut self.memtable, &self.dir, &mut self.manifest)?;
+            self.try_compact()?;
+        }
+        Ok(())
+    }
+
+    // Families whose options are known, which compaction needs for merging and filtering.
+    fn compactable_families(&self) -> Vec<u32> {
+        let families = self.families.iter();
+        families
+            .filter(|(_, family)| family.options_set)
+            .map(|(&cf, _)| cf)
+            .collect()
+    }
+
+    // Flush memtable and compact every column family into its bottom level,
+    // dropping versions and tombstones that no snapshot can see.
+    // Families recovered but not reopened are only flushed.
+    pub fn compact(&mut self) -> Result<()> {
+        self.flush()?;
+        let comparator = self.manifest.comparator().clone();
+        let families = self.compactable_families();
+        for cf in families {
+            let bottom = u64::max(self.manifest.family(cf).max_level(), 1);
+            for level in 0..bottom {
+                let family = self.manifest.family(cf);
+                let mut ids = family.get_sst_by_level(level);
+                if ids.is_empty() {
+                    continue;
+                }
+                let overlappings = ids
+                    .iter()
+                    .flat_map(|id| family.get_overlappings(id, comparator.as_ref()))
+                    .collect::<Vec<_>>();
+                ids.extend(overlappings);
+                ids.sort();
+                ids.dedup();
+                self.manifest.batch_start();
+                SSTGroup::new(&ids, &self.dir, comparator.clone())?.compact(
+                    level + 1,
+                    &self.dir,
+                    &mut self.manifest,
+                    &self.snapshots.seqnos(),
+                    &self.families[&cf].options,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Column families ordered by id.
+    pub fn stats(&self) -> Result<Vec<FamilyStats>> {
+        let mut names = self.manifest.family_names().collect::<Vec<_>>();
+        names.sort_by_key(|&(_, cf)| cf);
+        names
+            .into_iter()
+            .map(|(name, cf)| {
+                let family = self.manifest.family(cf);
+                let mut levels = Vec::new();
+                for level in 0..=family.max_level() {
+                    let ssts = family.get_sst_by_level(level).len();
+                    if ssts > 0 {
+                        levels.push((level, ssts, family.level_byte_size(level, &self.dir)?));
+                    }
+                }
+                Ok(FamilyStats {
+                    name: name.to_owned(),
+                    memtable_bytes: self.memtable.container(cf).approx_size(),
+                    levels,
+                })
+            })
+            .collect()
+    }
+
+    // Write a consistent copy of the store to `dest_dir`, which opens as an independent store.
+    // Live ssts are hard linked, or copied if that fails, e.g. across file systems,
+    // and the memtable is written to new level 0 ssts there, so the store isn't flushed.
+    pub fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        ensure!(
+            !dest_dir.exists() || fs::read_dir(dest_dir)?.next().is_none(),
+            "Checkpoint directory {dest_dir:?} isn't empty"
+        );
+        fs::create_dir_all(dest_dir)?;
+        // The pinned version keeps its ssts on disk until they are linked.
+        let version = self.current_version();
+        for id in version.active_sst_ids() {
+            let (src, dest) = (id.path(&self.dir), id.path(dest_dir));
+            fs::create_dir_all(dest.parent().unwrap())?;
+            if fs::hard_link(&src, &dest).is_err() {
+                fs::copy(&src, &dest).with_context(|| format!("Failed to copy {src:?}"))?;
+            }
+        }
+        self.checkpoint_memtable(dest_dir, &version)
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Pin the current version, whose ssts stay on disk until it's dropped.
+    pub(crate) fn current_version(&self) -> Arc<Version> {
+        self.manifest.current_version()
+    }
+
+    // Write the memtable as level 0 ssts, an empty memtable log, and a manifest of `version`
+    // with those ssts to `dest_dir`. It opens as a store once the ssts of `version` are added.
+    pub(crate) fn checkpoint_memtable(&self, dest_dir: &Path, version: &Version) -> Result<()> {
+        fs::create_dir_all(dest_dir)?;
+        let mut manifest = Manifest::clone(version);
+        let cmp = self.manifest.comparator().clone();
+        for (cf, container) in self.memtable.families() {
+            fs::create_dir_all(family_dir(dest_dir, cf))?;
+            if container.is_empty() {
+                continue;
+            }
+            let sst_id = manifest.family(cf).latest_sst_id(0);
+            manifest.execute_action(ManifestAction::NewId((cf, 0)), cmp.as_ref());
+            let (first_key, last_key) = SSTable::flush_to_level0_without_manifest(
+                container,
+                dest_dir,
+                sst_id,
+                cmp.clone(),
+            )?;
+            let add = ManifestAction::Add((sst_id, first_key, last_key));
+            manifest.execute_action(add, cmp.as_ref());
+        }
+        manifest.execute_action(ManifestAction::LastSeqno((self.last_seqno,)), cmp.as_ref());
+        fs::write(dest_dir.join(MEMTABLE_LOG_FILENAME), b"")?;
+        ManifestKeeper::create(dest_dir, manifest, cmp)?;
+        Ok(())
+    }
+
+    pub fn io_stats(&self) -> IoStats {
+        IoStats {
+            user_bytes_written: self.user_bytes_written,
+            log_bytes_written: self.memtable.log_bytes_written(),
+            sst_bytes_written: self.manifest.sst_bytes_written(),
+            gets: self.gets.load(atomic::Ordering::Relaxed),
+            ssts_read: self.ssts_read.load(atomic::Ordering::Relaxed),
+        }
+    }
+
     fn checked_flush(&mut self) -> Result<bool> {
         // Check whether to flush to level 0 sstable.
         if self.memtable.should_flush() {
@@ -88,114 +819,284 @@ impl Store {
     // ...
     // Rotate the random chosen key to span whole key space.
     fn try_compact(&mut self) -> Result<()> {
-        self.try_level_compact(0)
+        let families = self.compactable_families();
+        for cf in families {
+            self.try_level_compact(cf, 0)?;
+        }
+        Ok(())
     }
 
-    fn try_level_compact(&mut self, level: u64) -> Result<()> {
-        let level_ids = self.manifest.get_sst_by_level(level);
+    fn try_level_compact(&mut self, cf: u32, level: u64) -> Result<()> {
+        let comparator = self.manifest.comparator().clone();
+        let family = self.manifest.family(cf);
+        let level_ids = family.get_sst_by_level(level);
         if level_ids.is_empty() {
             Ok(())
         } else {
             if level == 0 {
                 if level_ids.len() >= 4 {
-                    self.manifest.batch_start();
                     let mut overlappings = Vec::new();
                     for id in &level_ids {
-                        overlappings.extend(self.manifest.get_overlappings(id));
+                        overlappings.extend(family.get_overlappings(id, comparator.as_ref()));
                     }
                     overlappings.extend(level_ids);
-                    SSTGroup::new(&overlappings, &self.dir)?.compact(
+                    // L0 ssts may share overlapping L1 ssts.
+                    overlappings.sort();
+                    overlappings.dedup();
+                    self.manifest.batch_start();
+                    SSTGroup::new(&overlappings, &self.dir, comparator)?.compact(
                         1,
                         &self.dir,
                         &mut self.manifest,
+                        &self.snapshots.seqnos(),
+                        &self.families[&cf].options,
                     )?;
-                    self.try_level_compact(1)?;
+                    self.try_level_compact(cf, 1)?;
                 }
-            } else if self.manifest.level_byte_size(level, &self.dir)?
+            } else if family.level_byte_size(level, &self.dir)?
                 > u64::pow(10, level as u32) * u64::pow(2, 20)
             {
-                self.manifest.batch_start();
-                let rotate_sst = self.manifest.latest_compact_sst(level); // level is smaller than max_level.
-                self.manifest.next_compact(level);
+                // level is smaller than max_level.
+                let rotate_sst = family.latest_compact_sst(level, comparator.as_ref());
                 let mut overlappings = Vec::new();
-                overlappings.extend(self.manifest.get_overlappings(&rotate_sst));
+                overlappings.extend(family.get_overlappings(&rotate_sst, comparator.as_ref()));
                 overlappings.push(rotate_sst);
-                SSTGroup::new(&overlappings, &self.dir)?.compact(
+                self.manifest.batch_start();
+                self.manifest.next_compact(cf, level);
+                SSTGroup::new(&overlappings, &self.dir, comparator)?.compact(
                     level + 1,
                     &self.dir,
                     &mut self.manifest,
+                    &self.snapshots.seqnos(),
+                    &self.families[&cf].options,
                 )?;
-                self.try_level_compact(level + 1)?;
+                self.try_level_compact(cf, level + 1)?;
             }
 
             Ok(())
         }
     }
 
-    // pub fn iter_range(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> StoreIter<'a> {
-    // }
+    // Iterate over pairs in [start, end). None means unbounded.
+    pub fn scan(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<StoreIter> {
+        self.scan_at_seqno(DEFAULT_COLUMN_FAMILY, start, end, self.last_seqno)
+    }
+
+    pub fn scan_cf(
+        &self,
+        cf: &ColumnFamily,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<StoreIter> {
+        self.scan_at_seqno(cf.id(), start, end, self.last_seqno)
+    }
+
+    pub fn scan_at(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        snapshot: &Snapshot,
+    ) -> Result<StoreIter> {
+        self.scan_at_seqno(DEFAULT_COLUMN_FAMILY, start, end, snapshot.seqno())
+    }
 
-    // pub fn iter(&self) -> Result<StoreIter> {
-    // StoreIter::new(self)
-    // }
+    fn scan_at_seqno(
+        &self,
+        cf: u32,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        seqno: u64,
+    ) -> Result<StoreIter> {
+        let options = &self.family(cf)?.options;
+        let comparator = self.manifest.comparator().clone();
+        let cmp = comparator.as_ref();
+        // Copy the memtable part since the memtable keeps changing.
+        let memtable_pairs: Vec<_> = self
+            .memtable
+            .iter(cf)
+            .filter(|(k, _)| {
+                start.map_or(true, |start| cmp.compare(&k.user_key, start).is_ge())
+                    && end.map_or(true, |end| cmp.compare(&k.user_key, end).is_lt())
+            })
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let version = self.manifest.current_version();
+        let group = SSTGroup::new(
+            &version.family(cf).get_sst_by_range(start, end, cmp),
+            &self.dir,
+            comparator.clone(),
+        )?;
+        let range_tombstones = self
+            .memtable
+            .range_tombstones(cf)
+            .iter()
+            .chain(group.range_tombstones())
+            .filter(|t| t.seqno <= seqno && t.overlaps(cmp, start, end))
+            .cloned()
+            .collect();
+        Ok(StoreIter {
+            memtable_iter: memtable_pairs.into_iter().peekable(),
+            sst_iter: OwnedGroupIterBuilder {
+                group,
+                iter_builder: |group: &SSTGroup| group.iter().peekable(),
+            }
+            .build(),
+            start: start.map(|start| start.to_vec()),
+            end: end.map(|end| end.to_vec()),
+            seqno,
+            previous_key: None,
+            pending: None,
+            range_tombstones,
+            now: options.clock.now_millis(),
+            merge_operator: options.merge_operator.clone(),
+            comparator,
+            _version: version,
+        })
+    }
 }
 
 // Transform references into values.
 pub struct MemTableIter<'a> {
-    iter: skipmap::Iter<'a, Vec<u8>, ValueUpdate>,
+    iter: ordered_skiplist::Iter<'a, (InternalKey, ValueUpdate)>,
 }
 
 impl<'a> Iterator for MemTableIter<'a> {
-    type Item = (Vec<u8>, ValueUpdate);
+    type Item = (InternalKey, ValueUpdate);
 
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.next().map(|(k, v)| (k.clone(), v.clone()))
     }
 }
 
-// pub struct StoreIter {
-// ssts: SSTGroup,
-// whole_iter: GeneralCombinedIter,
-// deleted: HashSet<Vec<u8>>,
-// }
-
-// impl StoreIter {
-// pub fn new(store: &Store) -> Result<StoreIter> {
-// let sst_ids = store.manifest.active_sst_ids();
-// let ssts = SSTGroup::new(&sst_ids[..], &store.workdir())?;
-// let iters: Vec<BoxedIter> = Vec::new();
-// iters.push(Box::new(ssts.iter()));
-// iters.push(Box::new(MemTableIter { iter: store.memtable.iter(), }));
-// Ok(StoreIter {
-// ssts,
-// whole_iter: GeneralCombinedIter::new(iters)?,
-// deleted: HashSet::new(),
-// })
-// }
-// }
-
-// impl Iterator for StoreIter {
-// type Item = (Vec<u8>, Vec<u8>);
-
-// fn next(&mut self) -> Option<Self::Item> {
-// loop {
-// match self.whole_iter.next() {
-// Some((k, ValueUpdate::Value(v))) => {
-// if !self.deleted.contains(&k) {
-// return Some((k.clone(), v.clone()));
-// }
-// },
-// Some((k, ValueUpdate::Tombstone)) => {
-// self.deleted.insert(k.clone());
-// },
-// None => return None,
-// }
-// }
-// }
-// }
+#[self_referencing]
+struct OwnedGroupIter {
+    group: SSTGroup,
+    #[borrows(group)]
+    #[not_covariant]
+    iter: Peekable<SSTGroupIter<'this>>,
+}
+
+// Merge memtable and sstables, yielding the newest pair visible at `seqno` for each key.
+pub struct StoreIter {
+    memtable_iter: Peekable<std::vec::IntoIter<(InternalKey, ValueUpdate)>>,
+    sst_iter: OwnedGroupIter,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    seqno: u64,
+    previous_key: Option<Vec<u8>>,
+    pending: Option<(InternalKey, ValueUpdate)>, // Read ahead when folding merges.
+    range_tombstones: Vec<RangeTombstone>,       // Visible ones overlapping the range.
+    now: i64,                                    // Entries expired by then are hidden.
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    comparator: Arc<dyn Comparator>,
+    _version: Arc<Version>, // Pinned while iterating.
+}
+
+impl StoreIter {
+    // Pop the smaller internal key of memtable and sstables.
+    fn next_entry(&mut self) -> Option<Result<(InternalKey, ValueUpdate)>> {
+        if let Some(kv) = self.pending.take() {
+            return Some(Ok(kv));
+        }
+        let sst_key = self.sst_iter.with_iter_mut(|iter| match iter.peek() {
+            Some(Ok((k, _))) => Some(Ok(k.clone())),
+            Some(Err(_)) => Some(Err(())),
+            None => None,
+        });
+        let from_memtable = match (self.memtable_iter.peek(), sst_key) {
+            (_, Some(Err(()))) => false,
+            (Some((memtable_key, _)), Some(Ok(sst_key))) => self
+                .comparator
+                .compare_internal(memtable_key, &sst_key)
+                .is_le(),
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return None,
+        };
+        if from_memtable {
+            self.memtable_iter.next().map(Ok)
+        } else {
+            self.sst_iter.with_iter_mut(|iter| iter.next())
+        }
+    }
+
+    fn is_range_deleted(&self, k: &InternalKey) -> bool {
+        self.range_tombstones
+            .iter()
+            .any(|t| t.covers(self.comparator.as_ref(), &k.user_key, k.seqno))
+    }
+
+    // Fold older versions of `key` onto the newest visible operand.
+    fn fold_merges(&mut self, key: Vec<u8>, operand: Vec<u8>) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut operands = vec![operand];
+        let existing = loop {
+            match self.next_entry().transpose()? {
+                Some((k, _)) if k.user_key == key && self.is_range_deleted(&k) => break None,
+                Some((k, v)) if k.user_key == key => match v.resolve_ttl(self.now) {
+                    ValueUpdate::Merge(operand) => operands.push(operand),
+                    ValueUpdate::Value(v) => break Some(v),
+                    _ => break None,
+                },
+                Some(kv) => {
+                    self.pending = Some(kv);
+                    break None;
+                }
+                None => break None,
+            }
+        };
+        let merged = merge::full_merge(
+            self.merge_operator.as_deref(),
+            &key,
+            &operands,
+            existing.as_deref(),
+        )?;
+        Ok((key, merged))
+    }
+}
+
+impl Iterator for StoreIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (k, v) = match self.next_entry()? {
+                Ok(kv) => kv,
+                Err(err) => return Some(Err(err)),
+            };
+            if k.seqno > self.seqno {
+                continue;
+            }
+            if let Some(start) = &self.start {
+                if self.comparator.compare(&k.user_key, start).is_lt() {
+                    continue;
+                }
+            }
+            if let Some(end) = &self.end {
+                if self.comparator.compare(&k.user_key, end).is_ge() {
+                    return None;
+                }
+            }
+            // Versions come newest first. The first visible one decides.
+            if self.previous_key.as_ref() == Some(&k.user_key) {
+                continue;
+            }
+            self.previous_key = Some(k.user_key.clone());
+            if self.is_range_deleted(&k) {
+                continue;
+            }
+            match v.resolve_ttl(self.now) {
+                ValueUpdate::Value(v) => return Some(Ok((k.user_key, v))),
+                ValueUpdate::Merge(operand) => return Some(self.fold_merges(k.user_key, operand)),
+                // Tombstone. Expiring values are resolved.
+                _ => continue,
+            }
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use crate::clock::ManualClock;
     use crate::store::*;
     use crate::test_util::*;
     use std::collections::BTreeMap;
@@ -253,6 +1154,466 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_checkpoint() -> Result<()> {
+        let store_dir = create_test_dir()?;
+        let mut store = Store::new(&store_dir)?;
+        let cf = store.open_column_family("other", Options::default())?;
+        for i in 0..3_u8 {
+            store.insert(vec![i], vec![i])?;
+            store.flush()?;
+        }
+        store.insert(b"unflushed".to_vec(), b"1".to_vec())?;
+        store.insert_cf(&cf, b"other".to_vec(), b"2".to_vec())?;
+        store.delete_range(&[1], &[2])?;
+
+        let checkpoint_dir = create_test_dir()?.join("checkpoint");
+        store.checkpoint(&checkpoint_dir)?;
+        ensure!(store.checkpoint(&checkpoint_dir).is_err());
+        let sst = SstId {
+            cf: DEFAULT_COLUMN_FAMILY,
+            level: 0,
+            id: 0,
+        };
+        ensure!(fs::read(sst.path(&checkpoint_dir))? == fs::read(sst.path(&store_dir))?);
+
+        // Later changes to the store, which compact away the linked ssts, don't reach it.
+        store.insert(b"later".to_vec(), b"3".to_vec())?;
+        store.compact()?;
+        ensure!(!sst.path(&store_dir).exists() && sst.path(&checkpoint_dir).exists());
+        drop(store);
+
+        let report = Store::verify(&checkpoint_dir)?;
+        ensure!(report.is_ok(), "{:?}", report.problems);
+        ensure!(report.log_writes == 0);
+        let mut checkpoint = Store::open(&checkpoint_dir)?;
+        ensure!(checkpoint.get(&[0])? == Some(vec![0]) && checkpoint.get(&[1])?.is_none());
+        ensure!(checkpoint.get(&[2])? == Some(vec![2]));
+        ensure!(checkpoint.get(b"unflushed")? == Some(b"1".to_vec()));
+        ensure!(checkpoint.get(b"later")?.is_none());
+        let cf = checkpoint.column_family("other").unwrap();
+        ensure!(checkpoint.get_cf(&cf, b"other")? == Some(b"2".to_vec()));
+        ensure!(checkpoint.last_seqno() == 6);
+        checkpoint.insert(b"new".to_vec(), b"4".to_vec())?;
+        ensure!(Store::open(&store_dir)?.get(b"new")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingest_external_files() -> Result<()> {
+        let test_store_dir = create_test_dir()?;
+        let mut store = Store::new(&test_store_dir)?;
+        store.insert(b"b".to_vec(), b"old".to_vec())?;
+
+        // Disjoint from everything, so it lands below level 0.
+        let external_dir = create_test_dir()?;
+        let disjoint = external_dir.join("disjoint");
+        SSTable::write_external(
+            &disjoint,
+            store.manifest.comparator().clone(),
+            (0..64_u8).map(|i| (vec![b'x', i], ValueUpdate::Value(vec![i]))),
+        )?;
+        let ids = store.ingest_external_files(&[disjoint])?;
+        ensure!(
+            ids[0].level == 1,
+            "Disjoint file should be ingested into level 1"
+        );
+        for i in 0..64_u8 {
+            ensure!(
+                store.get(&[b'x', i])? == Some(vec![i]),
+                "Ingested pair is missing"
+            );
+        }
+
+        // Overlaps the memtable, so it shadows the memtable from level 0.
+        let overlapping = external_dir.join("overlapping");
+        SSTable::write_external(
+            &overlapping,
+            store.manifest.comparator().clone(),
+            vec![
+                (b"a".to_vec(), ValueUpdate::Value(b"new".to_vec())),
+                (b"b".to_vec(), ValueUpdate::Value(b"new".to_vec())),
+            ],
+        )?;
+        let ids = store.ingest_external_files(&[overlapping])?;
+        ensure!(
+            ids[0].level == 0,
+            "Overlapping file should fall back to level 0"
+        );
+        ensure!(
+            store.memtable.is_empty(),
+            "Memtable should have been flushed"
+        );
+        ensure!(
+            store.get(b"b")? == Some(b"new".to_vec()),
+            "Ingested value should shadow older one"
+        );
+
+        // Invalid files are rejected without changing the manifest.
+        let garbage = external_dir.join("garbage");
+        fs::write(&garbage, b"not a table")?;
+        let active_ssts = store.manifest.active_sst_ids();
+        ensure!(
+            store.ingest_external_files(&[garbage]).is_err(),
+            "Invalid file should be rejected"
+        );
+        ensure!(
+            store.manifest.active_sst_ids() == active_ssts,
+            "Rejected ingestion changed the manifest"
+        );
+
+        // Merge operands without an operator fail the compaction after the commit,
+        // but the files are ingested.
+        let mut paths = Vec::new();
+        for name in ["merge1", "merge2"] {
+            let path = external_dir.join(name);
+            SSTable::write_external(
+                &path,
+                store.manifest.comparator().clone(),
+                vec![
+                    (b"a".to_vec(), ValueUpdate::Merge(b"1".to_vec())),
+                    (b"b".to_vec(), ValueUpdate::Value(name.as_bytes().to_vec())),
+                ],
+            )?;
+            paths.push(path);
+        }
+        let ids = store.ingest_external_files(&paths)?;
+        ensure!(store.compact().is_err());
+        ensure!(store.manifest.family(0).get_sst_by_level(0).len() == 4);
+        ensure!(ids
+            .iter()
+            .all(|id| store.manifest.active_sst_ids().contains(id)));
+        ensure!(store.get(b"b")? == Some(b"merge2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_seqno() -> Result<()> {
+        let test_store_dir = create_test_dir()?;
+        {
+            let mut store = Store::new(&test_store_dir)?;
+            store.insert(b"a".to_vec(), b"1".to_vec())?;
+            store.insert(b"b".to_vec(), b"1".to_vec())?;
+            // Persist the first writes in an sst and clear the log.
+            SSTable::flush_to_level0(&mut store.memtable, &store.dir, &mut store.manifest)?;
+            store.insert(b"a".to_vec(), b"2".to_vec())?;
+            store.remove(b"b")?;
+            ensure!(store.last_seqno() == 4, "Every write should take a seqno");
+        }
+
+        let mut store = Store::recover(&test_store_dir)?;
+        ensure!(
+            store.last_seqno() == 4,
+            "Recovered store should continue from the last seqno"
+        );
+        ensure!(
+            store.get(b"a")? == Some(b"2".to_vec()),
+            "Newer version in memtable should win"
+        );
+        ensure!(store.get(b"b")?.is_none(), "Deleted key is visible");
+
+        // Newer writes shadow the flushed ones regardless of which file holds them.
+        store.insert(b"b".to_vec(), b"3".to_vec())?;
+        SSTable::flush_to_level0(&mut store.memtable, &store.dir, &mut store.manifest)?;
+        ensure!(
+            store.get(b"b")? == Some(b"3".to_vec()),
+            "Newest version should win"
+        );
+        ensure!(
+            store.manifest.last_seqno() == 5,
+            "Manifest should track seqno"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let test_store_dir = create_test_dir()?;
+        let mut store = Store::new(&test_store_dir)?;
+        store.insert(b"a".to_vec(), b"1".to_vec())?;
+        store.insert(b"b".to_vec(), b"1".to_vec())?;
+        let snapshot = store.snapshot();
+        store.insert(b"a".to_vec(), b"2".to_vec())?;
+        store.remove(b"b")?;
+        store.insert(b"c".to_vec(), b"2".to_vec())?;
+
+        // Flush and compact while the snapshot is alive.
+        for i in 0..4 {
+            store.insert(vec![b'z', i], b"filler".to_vec())?;
+            SSTable::flush_to_level0(&mut store.memtable, &store.dir, &mut store.manifest)?;
+        }
+        store.try_compact()?;
+        let level1 = SSTGroup::new(
+            &store.manifest.family(0).get_sst_by_level(1),
+            &store.dir,
+            store.manifest.comparator().clone(),
+        )?;
+        let versions_of_a = level1
+            .iter()
+            .filter(|wrapped_kv| matches!(wrapped_kv, Ok((k, _)) if k.user_key == b"a"))
+            .count();
+        ensure!(
+            versions_of_a == 2,
+            "Compaction should keep the version seen by snapshot"
+        );
+
+        ensure!(store.get_at(b"a", &snapshot)? == Some(b"1".to_vec()));
+        ensure!(store.get_at(b"b", &snapshot)? == Some(b"1".to_vec()));
+        ensure!(store.get_at(b"c", &snapshot)?.is_none());
+        ensure!(store.get(b"a")? == Some(b"2".to_vec()));
+        ensure!(store.get(b"b")?.is_none());
+
+        let old_pairs = store
+            .scan_at(None, Some(b"z"), &snapshot)?
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(
+            old_pairs
+                == vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"b".to_vec(), b"1".to_vec())
+                ],
+            "Scan at snapshot sees later writes"
+        );
+        let pairs = store
+            .scan(Some(b"a"), Some(b"c"))?
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(
+            pairs == vec![(b"a".to_vec(), b"2".to_vec())],
+            "Scan sees stale pairs"
+        );
+
+        drop(snapshot);
+        ensure!(
+            store.snapshots.seqnos().is_empty(),
+            "Dropped snapshot is still alive"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_swap() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        let key = b"leader";
+
+        ensure!(store.put_if_absent(key, b"a".to_vec())? == CasResult::Swapped);
+        ensure!(
+            store.put_if_absent(key, b"b".to_vec())? == CasResult::Mismatch(Some(b"a".to_vec()))
+        );
+        ensure!(
+            store.compare_and_swap(key, Some(b"b"), Some(b"c".to_vec()))?
+                == CasResult::Mismatch(Some(b"a".to_vec()))
+        );
+        ensure!(store.get(key)? == Some(b"a".to_vec()));
+        ensure!(
+            store.compare_and_swap(key, Some(b"a"), Some(b"b".to_vec()))? == CasResult::Swapped
+        );
+        ensure!(store.get(key)? == Some(b"b".to_vec()));
+
+        // Delete, then the key is absent again.
+        ensure!(store.compare_and_swap(key, Some(b"b"), None)? == CasResult::Swapped);
+        ensure!(store.get(key)?.is_none());
+        ensure!(store.compare_and_swap(key, None, None)? == CasResult::Swapped);
+        ensure!(store.put_if_absent(key, b"c".to_vec())? == CasResult::Swapped);
+
+        // Values in sstables are compared too.
+        SSTable::flush_to_level0(&mut store.memtable, &store.dir, &mut store.manifest)?;
+        ensure!(
+            store.put_if_absent(key, b"d".to_vec())? == CasResult::Mismatch(Some(b"c".to_vec()))
+        );
+        ensure!(
+            store.compare_and_swap(key, Some(b"c"), Some(b"d".to_vec()))? == CasResult::Swapped
+        );
+        ensure!(store.get(key)? == Some(b"d".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_range() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        let key = |i: u32| format!("key{i:03}").into_bytes();
+        for i in 0..100 {
+            store.insert(key(i), b"v1".to_vec())?;
+        }
+        SSTable::flush_to_level0(&mut store.memtable, &store.dir, &mut store.manifest)?;
+        for i in (0..100).step_by(2) {
+            store.insert(key(i), b"v2".to_vec())?;
+        }
+        let before = store.last_seqno();
+        store.delete_range(&key(20), &key(50))?;
+        store.insert(key(30), b"v3".to_vec())?;
+
+        let check = |store: &Store| -> Result<()> {
+            for i in 0..100 {
+                let expected = match i {
+                    30 => Some(b"v3".to_vec()),
+                    20..=49 => None,
+                    _ if i % 2 == 0 => Some(b"v2".to_vec()),
+                    _ => Some(b"v1".to_vec()),
+                };
+                ensure!(store.get(&key(i))? == expected, "Wrong value of key {i}");
+            }
+            let keys = store
+                .scan(Some(&key(10)), Some(&key(60)))?
+                .map(|kv| kv.map(|(k, _)| k))
+                .collect::<Result<Vec<_>>>()?;
+            let expected = (10..60)
+                .filter(|i| !(20..50).contains(i) || *i == 30)
+                .map(key)
+                .collect::<Vec<_>>();
+            ensure!(keys == expected, "Scan doesn't respect range tombstone");
+            Ok(())
+        };
+        check(&store)?;
+
+        // Recovered from memtable log.
+        drop(store);
+        let mut store = Store::recover(&test_dir)?;
+        check(&store)?;
+        let snapshot = store.snapshots.acquire(before);
+        ensure!(store.get_at(&key(20), &snapshot)? == Some(b"v2".to_vec()));
+        ensure!(store.scan_at(None, None, &snapshot)?.count() == 100);
+
+        // Compacted while the snapshot still sees covered keys.
+        SSTable::flush_to_level0(&mut store.memtable, &store.dir, &mut store.manifest)?;
+        check(&store)?;
+        for _ in 0..2 {
+            store.insert(key(999), b"pad".to_vec())?;
+            SSTable::flush_to_level0(&mut store.memtable, &store.dir, &mut store.manifest)?;
+        }
+        store.try_compact()?;
+        ensure!(store.manifest.family(0).get_sst_by_level(0).is_empty());
+        check(&store)?;
+        ensure!(store.get_at(&key(21), &snapshot)? == Some(b"v1".to_vec()));
+        let group = SSTGroup::new(
+            &store.manifest.active_sst_ids(),
+            &test_dir,
+            store.manifest.comparator().clone(),
+        )?;
+        ensure!(group.range_tombstones().count() == 1);
+
+        // Without snapshots, covered keys and the tombstone are dropped at the bottom level.
+        drop(snapshot);
+        SSTGroup::new(
+            &store.manifest.family(0).get_sst_by_level(1),
+            &store.dir,
+            store.manifest.comparator().clone(),
+        )?
+        .compact(
+            1,
+            &store.dir,
+            &mut store.manifest,
+            &[],
+            &store.families[&DEFAULT_COLUMN_FAMILY].options,
+        )?;
+        let group = SSTGroup::new(
+            &store.manifest.active_sst_ids(),
+            &test_dir,
+            store.manifest.comparator().clone(),
+        )?;
+        ensure!(
+            group.range_tombstones().count() == 0,
+            "Range tombstone is not purged"
+        );
+        for wrapped_kv in group.iter() {
+            let (k, _) = wrapped_kv?;
+            ensure!(
+                k.user_key == key(30) || !(key(20)..key(50)).contains(&k.user_key),
+                "Covered key is not dropped"
+            );
+        }
+        check(&store)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_ttl() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let clock = Arc::new(ManualClock::new(0));
+        let options = Options {
+            clock: clock.clone(),
+            merge_operator: Some(Arc::new(merge::U64AddOperator)),
+            ..Options::default()
+        };
+        let mut store = Store::new_with_options(&test_dir, options)?;
+        store.insert(b"session".to_vec(), b"old".to_vec())?;
+        store.insert_with_ttl(
+            b"session".to_vec(),
+            b"new".to_vec(),
+            Duration::from_secs(10),
+        )?;
+        store.insert_with_ttl(b"cache".to_vec(), b"1".to_vec(), Duration::from_secs(30))?;
+        store.insert(b"user".to_vec(), b"1".to_vec())?;
+        let snapshot = store.snapshot();
+
+        clock.advance_millis(9_999);
+        ensure!(store.get(b"session")? == Some(b"new".to_vec()));
+        ensure!(store.scan(None, None)?.count() == 3);
+
+        // Expired values hide older versions too, in every view.
+        clock.advance_millis(1);
+        ensure!(store.get(b"session")?.is_none());
+        ensure!(store.get_at(b"session", &snapshot)?.is_none());
+        let pairs = store.scan(None, None)?.collect::<Result<Vec<_>>>()?;
+        ensure!(
+            pairs
+                == vec![
+                    (b"cache".to_vec(), b"1".to_vec()),
+                    (b"user".to_vec(), b"1".to_vec())
+                ]
+        );
+
+        // Compaction to the bottom level drops expired entries and what they hide.
+        drop(snapshot);
+        for i in 0..4 {
+            store.insert(format!("pad{i}").into_bytes(), b"pad".to_vec())?;
+            SSTable::flush_to_level0(&mut store.memtable, &store.dir, &mut store.manifest)?;
+        }
+        store.try_compact()?;
+        ensure!(store.manifest.family(0).get_sst_by_level(0).is_empty());
+        let group = SSTGroup::new(
+            &store.manifest.active_sst_ids(),
+            &test_dir,
+            store.manifest.comparator().clone(),
+        )?;
+        for wrapped_kv in group.iter() {
+            let (k, v) = wrapped_kv?;
+            ensure!(k.user_key != b"session", "Expired entry is not dropped");
+            if k.user_key == b"cache" {
+                ensure!(
+                    v == ValueUpdate::Expiring(b"1".to_vec(), 30_000),
+                    "Ttl is lost"
+                );
+            }
+        }
+        ensure!(store.get(b"session")?.is_none());
+        ensure!(store.get(b"cache")? == Some(b"1".to_vec()));
+        clock.advance_millis(20_000);
+        ensure!(store.get(b"cache")?.is_none());
+
+        store.insert_with_ttl(b"forever".to_vec(), b"1".to_vec(), Duration::MAX)?;
+        store.insert_with_ttl(
+            b"long".to_vec(),
+            b"1".to_vec(),
+            Duration::from_millis(i64::MAX as u64),
+        )?;
+        clock.advance_millis(1 << 62);
+        ensure!(store.get(b"forever")? == Some(b"1".to_vec()));
+        ensure!(store.get(b"long")? == Some(b"1".to_vec()));
+
+        // Operands fold onto None once their base expires, whether compacted or not.
+        let counter = |n: u64| n.to_le_bytes().to_vec();
+        store.insert_with_ttl(b"counter".to_vec(), counter(1), Duration::from_secs(10))?;
+        store.merge(b"counter".to_vec(), counter(2))?;
+        store.flush()?;
+        store.compact()?;
+        ensure!(store.get(b"counter")? == Some(counter(3)));
+        clock.advance_millis(10_000);
+        ensure!(store.get(b"counter")? == Some(counter(2)));
+        Ok(())
+    }
+
     #[test]
     fn check_sst_size() -> Result<()> {
         // Chunk write and delete
@@ -270,10 +1631,23 @@ mod tests {
                 store.remove(&key)?;
             }
         }
+        // About 8MB in total, so level 1 can hold all compacted data.
         dbg!(store.manifest.active_sst_ids());
-        if dbg!(store.manifest.max_level()) != 2 {
+        if dbg!(store.manifest.family(0).max_level()) != 1 {
             bail!(dbg!("Max level of SSTables is not correct"));
         }
+        ensure!(
+            store.manifest.family(0).get_sst_by_level(0).len() < 4,
+            "Level 0 is not compacted"
+        );
+        ensure!(
+            store
+                .manifest
+                .family(0)
+                .level_byte_size(1, &test_store_dir)?
+                <= 10 * u64::pow(2, 20),
+            "Level 1 exceeds its size limit"
+        );
         Ok(())
     }
 }
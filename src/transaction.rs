@@ -0,0 +1,281 @@
+// Transactions buffer writes and apply them in a single memtable commit.
+//
+// An optimistic transaction reads at the snapshot taken when it begins,
+// and remembers every key it reads.
+// At commit, if any of them is written after the snapshot, the transaction is aborted.
+//
+// A pessimistic transaction locks every key it writes or reads for update,
+// so it never conflicts at commit but may wait, time out or be chosen as a deadlock victim.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::lock::LockManager;
+use crate::memtable::ValueUpdate;
+use crate::snapshot::Snapshot;
+use crate::store::Store;
+
+use anyhow::Result;
+
+// Returned through anyhow::Error. Use `downcast_ref` to tell it from I/O errors.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    Conflict,
+    LockTimeout,
+    Deadlock,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Conflict => write!(f, "Transaction conflicts with a later write"),
+            TransactionError::LockTimeout => write!(f, "Timed out waiting for a key lock"),
+            TransactionError::Deadlock => write!(f, "Transaction aborted to break a deadlock"),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+pub struct OptimisticTransaction {
+    snapshot: Snapshot,
+    read_keys: BTreeSet<Vec<u8>>,
+    writes: BTreeMap<Vec<u8>, ValueUpdate>,
+}
+
+impl OptimisticTransaction {
+    pub(crate) fn new(snapshot: Snapshot) -> OptimisticTransaction {
+        OptimisticTransaction {
+            snapshot,
+            read_keys: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    // Seqno the transaction reads at.
+    pub fn start_seqno(&self) -> u64 {
+        self.snapshot.seqno()
+    }
+
+    // Own writes first, then the snapshot.
+    pub fn get(&mut self, store: &Store, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(key) {
+            Some(ValueUpdate::Value(v)) => Ok(Some(v.clone())),
+            // Transactions don't buffer merges, so it's a tombstone.
+            Some(_) => Ok(None),
+            None => {
+                self.read_keys.insert(key.to_vec());
+                store.get_at(key, &self.snapshot)
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, ValueUpdate::Value(value));
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), ValueUpdate::Tombstone);
+    }
+
+    // Fails with TransactionError::Conflict if any key read is written since the transaction began.
+    // Nothing is written in that case.
+    pub fn commit(self, store: &mut Store) -> Result<()> {
+        for key in &self.read_keys {
+            if store.changed_since(key, self.start_seqno())? {
+                return Err(TransactionError::Conflict.into());
+            }
+        }
+        store.write_batch(self.writes.into_iter().collect())
+    }
+
+    // Discard buffered writes.
+    pub fn rollback(self) {}
+}
+
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Locks are released on commit, rollback or drop.
+pub struct PessimisticTransaction {
+    id: u64,
+    locks: Arc<LockManager>,
+    lock_timeout: Duration,
+    locked_keys: BTreeSet<Vec<u8>>,
+    writes: BTreeMap<Vec<u8>, ValueUpdate>,
+}
+
+impl PessimisticTransaction {
+    pub(crate) fn new(locks: Arc<LockManager>) -> PessimisticTransaction {
+        PessimisticTransaction {
+            id: locks.new_txn_id(),
+            locks,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            locked_keys: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+
+    // Lock without reading.
+    // When the store is shared between threads, lock keys before taking the store,
+    // so that waiting doesn't block the lock holder.
+    pub fn lock(&mut self, key: &[u8]) -> Result<()> {
+        if !self.locked_keys.contains(key) {
+            self.locks.lock(self.id, key, self.lock_timeout)?;
+            self.locked_keys.insert(key.to_vec());
+        }
+        Ok(())
+    }
+
+    // Read the latest value without locking.
+    pub fn get(&self, store: &Store, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(key) {
+            Some(ValueUpdate::Value(v)) => Ok(Some(v.clone())),
+            // Transactions don't buffer merges, so it's a tombstone.
+            Some(_) => Ok(None),
+            None => store.get(key),
+        }
+    }
+
+    // Lock `key` and read it. Nobody else can write it until this transaction ends.
+    pub fn get_for_update(&mut self, store: &Store, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock(key)?;
+        self.get(store, key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.lock(&key)?;
+        self.writes.insert(key, ValueUpdate::Value(value));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.lock(key)?;
+        self.writes.insert(key.to_vec(), ValueUpdate::Tombstone);
+        Ok(())
+    }
+
+    pub fn commit(mut self, store: &mut Store) -> Result<()> {
+        store.write_batch(std::mem::take(&mut self.writes).into_iter().collect())
+    }
+
+    // Discard buffered writes and release locks.
+    pub fn rollback(self) {}
+}
+
+impl Drop for PessimisticTransaction {
+    fn drop(&mut self) {
+        self.locks.unlock_all(self.id, &self.locked_keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::*;
+    use crate::test_util::*;
+    use crate::transaction::*;
+    use std::time::Duration;
+
+    use anyhow::{bail, ensure, Result};
+
+    #[test]
+    fn test_optimistic_transaction() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        store.insert(b"apple".to_vec(), b"1".to_vec())?;
+        store.insert(b"pear".to_vec(), b"1".to_vec())?;
+
+        // Reads its own writes, and nothing is visible before commit.
+        let mut txn = store.begin_optimistic();
+        ensure!(txn.get(&store, b"apple")? == Some(b"1".to_vec()));
+        txn.insert(b"apple".to_vec(), b"2".to_vec());
+        txn.remove(b"pear");
+        ensure!(txn.get(&store, b"apple")? == Some(b"2".to_vec()));
+        ensure!(txn.get(&store, b"pear")?.is_none());
+        ensure!(store.get(b"apple")? == Some(b"1".to_vec()));
+        txn.commit(&mut store)?;
+        ensure!(store.get(b"apple")? == Some(b"2".to_vec()));
+        ensure!(store.get(b"pear")?.is_none());
+
+        // Read-modify-write races. The later commit loses.
+        let mut txn1 = store.begin_optimistic();
+        let mut txn2 = store.begin_optimistic();
+        ensure!(txn1.get(&store, b"apple")? == Some(b"2".to_vec()));
+        ensure!(txn2.get(&store, b"apple")? == Some(b"2".to_vec()));
+        txn1.insert(b"apple".to_vec(), b"3".to_vec());
+        txn2.insert(b"apple".to_vec(), b"4".to_vec());
+        txn2.insert(b"banana".to_vec(), b"4".to_vec());
+        txn1.commit(&mut store)?;
+        match txn2.commit(&mut store) {
+            Err(err) if err.downcast_ref() == Some(&TransactionError::Conflict) => {}
+            Err(err) => return Err(err),
+            Ok(()) => bail!("Conflicting transaction committed"),
+        }
+        ensure!(store.get(b"apple")? == Some(b"3".to_vec()));
+        ensure!(
+            store.get(b"banana")?.is_none(),
+            "Aborted transaction is partially applied"
+        );
+
+        // Deleting a read key conflicts too.
+        let mut txn = store.begin_optimistic();
+        txn.get(&store, b"apple")?;
+        store.remove(b"apple")?;
+        ensure!(txn.commit(&mut store).is_err());
+
+        // Blind writes don't conflict.
+        let mut txn = store.begin_optimistic();
+        txn.insert(b"apple".to_vec(), b"5".to_vec());
+        store.insert(b"apple".to_vec(), b"6".to_vec())?;
+        txn.commit(&mut store)?;
+        ensure!(store.get(b"apple")? == Some(b"5".to_vec()));
+
+        // Committed writes survive recovery.
+        drop(store);
+        let store = Store::recover(&test_dir)?;
+        ensure!(store.get(b"apple")? == Some(b"5".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pessimistic_transaction() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        store.insert(b"apple".to_vec(), b"1".to_vec())?;
+
+        let mut txn1 = store.begin_pessimistic();
+        let mut txn2 = store.begin_pessimistic();
+        txn2.set_lock_timeout(Duration::from_millis(10));
+        ensure!(txn1.get_for_update(&store, b"apple")? == Some(b"1".to_vec()));
+        txn1.insert(b"apple".to_vec(), b"2".to_vec())?;
+        txn1.insert(b"pear".to_vec(), b"2".to_vec())?;
+        // Locked keys can still be read, but not locked by others.
+        ensure!(txn2.get(&store, b"apple")? == Some(b"1".to_vec()));
+        match txn2.get_for_update(&store, b"apple") {
+            Err(err) if err.downcast_ref() == Some(&TransactionError::LockTimeout) => {}
+            Err(err) => return Err(err),
+            Ok(_) => bail!("Locked key is locked twice"),
+        }
+        txn1.commit(&mut store)?;
+        ensure!(store.get(b"apple")? == Some(b"2".to_vec()));
+        ensure!(store.get(b"pear")? == Some(b"2".to_vec()));
+
+        // Released by commit.
+        ensure!(txn2.get_for_update(&store, b"apple")? == Some(b"2".to_vec()));
+        txn2.remove(b"apple")?;
+        txn2.rollback();
+        ensure!(store.get(b"apple")? == Some(b"2".to_vec()));
+
+        // Released by rollback.
+        let mut txn = store.begin_pessimistic();
+        txn.set_lock_timeout(Duration::ZERO);
+        txn.remove(b"apple")?;
+        txn.commit(&mut store)?;
+        ensure!(store.get(b"apple")?.is_none());
+        Ok(())
+    }
+}
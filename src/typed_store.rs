@@ -0,0 +1,342 @@
+// Typed keys and values over Store.
+// Keys are encoded so that bytewise order of encodings is the natural order of keys,
+// so ranges of typed keys are ranges of bytes:
+// integers are big-endian with the sign bit flipped,
+// strings and bytes are escaped and terminated so that prefixes sort first,
+// and tuples are their fields concatenated.
+// Values are encoded by bincode.
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::store::{Store, StoreIter};
+
+use anyhow::{anyhow, ensure, Result};
+use bincode::{config, Decode, Encode};
+
+pub trait KeyCodec: Sized {
+    fn encode_key(&self, buf: &mut Vec<u8>);
+
+    // Decode from the front of `buf` and advance it past the key.
+    fn decode_key(buf: &mut &[u8]) -> Result<Self>;
+}
+
+pub fn encode_key<K: KeyCodec>(key: &K) -> Vec<u8> {
+    let mut buf = Vec::new();
+    key.encode_key(&mut buf);
+    buf
+}
+
+pub fn decode_key<K: KeyCodec>(mut bytes: &[u8]) -> Result<K> {
+    let key = K::decode_key(&mut bytes)?;
+    ensure!(bytes.is_empty(), "Key has {} trailing bytes", bytes.len());
+    Ok(key)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    ensure!(buf.len() >= len, "Key is truncated");
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(taken)
+}
+
+macro_rules! unsigned_key {
+    ($($t:ty),*) => {$(
+        impl KeyCodec for $t {
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_key(buf: &mut &[u8]) -> Result<Self> {
+                let bytes = take(buf, size_of::<$t>())?;
+                Ok(<$t>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+
+// Flipping the sign bit puts negative numbers before positive ones.
+macro_rules! signed_key {
+    ($($t:ty => $u:ty),*) => {$(
+        impl KeyCodec for $t {
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_key(buf);
+            }
+
+            fn decode_key(buf: &mut &[u8]) -> Result<Self> {
+                Ok((<$u>::decode_key(buf)? ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KeyCodec for bool {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode_key(buf: &mut &[u8]) -> Result<Self> {
+        match take(buf, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(anyhow!("Invalid bool {byte} in key")),
+        }
+    }
+}
+
+// 0x00 is written as 0x00 0xff, and the end as 0x00 0x00,
+// which sorts before any byte following a shared prefix.
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    for &byte in bytes {
+        buf.push(byte);
+        if byte == 0 {
+            buf.push(0xff);
+        }
+    }
+    buf.extend_from_slice(&[0, 0]);
+}
+
+fn decode_bytes(buf: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    loop {
+        match take(buf, 1)?[0] {
+            0 => match take(buf, 1)?[0] {
+                0 => return Ok(bytes),
+                0xff => bytes.push(0),
+                byte => return Err(anyhow!("Invalid escape 0x00 {byte:#04x} in key")),
+            },
+            byte => bytes.push(byte),
+        }
+    }
+}
+
+impl KeyCodec for Vec<u8> {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self, buf);
+    }
+
+    fn decode_key(buf: &mut &[u8]) -> Result<Self> {
+        decode_bytes(buf)
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), buf);
+    }
+
+    fn decode_key(buf: &mut &[u8]) -> Result<Self> {
+        Ok(String::from_utf8(decode_bytes(buf)?)?)
+    }
+}
+
+macro_rules! tuple_key {
+    ($($name:ident),+) => {
+        impl<$($name: KeyCodec),+> KeyCodec for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_key(buf);)+
+            }
+
+            fn decode_key(buf: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_key(buf)?,)+))
+            }
+        }
+    };
+}
+
+tuple_key!(A, B);
+tuple_key!(A, B, C);
+tuple_key!(A, B, C, D);
+
+// Keys and values of the default column family.
+pub struct TypedStore<K, V> {
+    store: Store,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: KeyCodec, V: Encode + Decode> TypedStore<K, V> {
+    // Encoded keys are ordered bytewise, so `store` should use the bytewise comparator.
+    pub fn new(store: Store) -> Result<TypedStore<K, V>> {
+        ensure!(
+            store.comparator().name() == BytewiseComparator.name(),
+            "Typed keys require the bytewise comparator, but the store uses {}",
+            store.comparator().name()
+        );
+        Ok(TypedStore {
+            store,
+            _types: PhantomData,
+        })
+    }
+
+    pub fn inner(&self) -> &Store {
+        &self.store
+    }
+
+    pub fn into_inner(self) -> Store {
+        self.store
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        let value = bincode::encode_to_vec(value, config::standard())?;
+        self.store.insert(encode_key(key), value)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.store.get(&encode_key(key))? {
+            Some(value) => Ok(Some(decode_value(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        self.store.remove(&encode_key(key))
+    }
+
+    // Pairs with keys in `range`, ordered by key.
+    pub fn scan<R: RangeBounds<K>>(&self, range: R) -> Result<TypedIter<K, V>> {
+        // An encoding followed by 0x00 is the smallest byte string after it.
+        let successor = |key: &K| {
+            let mut encoded = encode_key(key);
+            encoded.push(0);
+            encoded
+        };
+        let start = match range.start_bound() {
+            Bound::Included(key) => Some(encode_key(key)),
+            Bound::Excluded(key) => Some(successor(key)),
+            Bound::Unbounded => None,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Some(successor(key)),
+            Bound::Excluded(key) => Some(encode_key(key)),
+            Bound::Unbounded => None,
+        };
+        Ok(TypedIter {
+            iter: self.store.scan(start.as_deref(), end.as_deref())?,
+            _types: PhantomData,
+        })
+    }
+}
+
+fn decode_value<V: Decode>(bytes: &[u8]) -> Result<V> {
+    Ok(bincode::decode_from_slice(bytes, config::standard())?.0)
+}
+
+pub struct TypedIter<K, V> {
+    iter: StoreIter,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: KeyCodec, V: Decode> Iterator for TypedIter<K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next()
+            .map(|kv| kv.and_then(|(k, v)| Ok((decode_key(&k)?, decode_value(&v)?))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+    use std::ops::Bound;
+    use std::sync::Arc;
+
+    use crate::comparator::ReverseBytewiseComparator;
+    use crate::options::Options;
+    use crate::test_util::*;
+    use crate::typed_store::*;
+
+    use anyhow::{ensure, Result};
+
+    // `keys` are in ascending order.
+    fn check_order<K: KeyCodec + PartialEq + Debug>(keys: &[K]) -> Result<()> {
+        for pair in keys.windows(2) {
+            ensure!(
+                encode_key(&pair[0]) < encode_key(&pair[1]),
+                "Encoding of {:?} doesn't sort before {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+        for key in keys {
+            ensure!(decode_key::<K>(&encode_key(key))? == *key);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_order() -> Result<()> {
+        check_order(&[0_u64, 1, 255, 256, u64::MAX])?;
+        check_order(&[i32::MIN, -256, -1, 0, 1, 255, i32::MAX])?;
+        check_order(&[false, true])?;
+        let strings = ["", "\0", "\0\0", "\0a", "a", "a\0", "ab", "b"];
+        check_order(&strings.map(String::from))?;
+        // Fields are delimited, so a longer first field doesn't leak into the second.
+        check_order(&[
+            ("a".to_owned(), 9_u64),
+            ("a\0".to_owned(), 0),
+            ("ab".to_owned(), 0),
+        ])?;
+        check_order(&[
+            (1_u8, -5_i64, vec![0xff_u8]),
+            (1, 3, vec![]),
+            (2, -9, vec![]),
+        ])?;
+        ensure!(decode_key::<u64>(&[0; 4]).is_err());
+        ensure!(decode_key::<u8>(&[0, 0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_typed_store() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = TypedStore::<u64, String>::new(Store::new(&test_dir)?)?;
+        for i in 0..300_u64 {
+            store.insert(&i, &format!("value{i}"))?;
+        }
+        store.remove(&15)?;
+        ensure!(store.get(&42)? == Some("value42".to_owned()));
+        ensure!(store.get(&15)?.is_none());
+
+        let keys = |iter: TypedIter<u64, String>| -> Result<Vec<u64>> {
+            iter.map(|kv| kv.map(|(k, _)| k)).collect()
+        };
+        // Bytewise order of strings would put 100 before 11.
+        let expected = (10..20).filter(|&i| i != 15).collect::<Vec<_>>();
+        ensure!(keys(store.scan(10_u64..20)?)? == expected);
+        ensure!(keys(store.scan(..=2)?)? == [0, 1, 2]);
+        ensure!(keys(store.scan((Bound::Excluded(297), Bound::Unbounded))?)? == [298, 299]);
+        let (k, v) = store.scan(299..)?.next().unwrap()?;
+        ensure!((k, v) == (299, "value299".to_owned()));
+
+        // Tuple keys scan by prefix ranges.
+        let test_dir = create_test_dir()?;
+        let mut store = TypedStore::<(String, i32), Vec<u64>>::new(Store::new(&test_dir)?)?;
+        for user in ["bob", "alice", "al"] {
+            for day in [-1, 0, 1] {
+                store.insert(&(user.to_owned(), day), &vec![day as u64; 2])?;
+            }
+        }
+        let alice = store
+            .scan(("alice".to_owned(), i32::MIN)..=("alice".to_owned(), i32::MAX))?
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(alice.iter().map(|((_, day), _)| *day).eq([-1, 0, 1]));
+
+        // Other comparators don't order encoded keys.
+        let options = Options {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            ..Options::default()
+        };
+        let store = Store::new_with_options(&create_test_dir()?, options)?;
+        ensure!(TypedStore::<u64, u64>::new(store).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+// Offline consistency check of a store, see Store::verify().
+// Files are only read, so a torn log is reported instead of being truncated by recovery.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::comparator::Comparator;
+use crate::manifest::{ManifestDump, MANIFEST_LOG_PREFIX, MANIFEST_SNAPSHOT_PREFIX};
+use crate::memtable::{MemTableAction, MemTableKeeper, MEMTABLE_LOG_FILENAME};
+use crate::sst_dump::SstDump;
+use crate::sstable::{parse_family_dir, SstId};
+
+use anyhow::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    MissingSst(SstId),
+    CorruptSst(SstId, String),
+    // Key ranges in manifest and in the file.
+    KeyRangeMismatch(SstId, (Vec<u8>, Vec<u8>), (Vec<u8>, Vec<u8>)),
+    // Ssts of a level >= 1 whose key ranges overlap.
+    Overlap(SstId, SstId),
+    OrphanFile(PathBuf),
+    MissingLog(String),
+    // Log file, number of uncommitted actions and undecodable bytes at its end.
+    TornLog(String, usize, usize),
+    // Writes in the memtable log to a family that isn't in manifest.
+    UnknownFamily(u32),
+}
+
+fn sst_name(id: &SstId) -> String {
+    format!("{}/{}/{}", id.cf, id.level, id.id)
+}
+
+fn range_name((first, last): &(Vec<u8>, Vec<u8>)) -> String {
+    format!("[{}, {}]", first.escape_ascii(), last.escape_ascii())
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MissingSst(id) => write!(f, "sst {} is missing", sst_name(id)),
+            Problem::CorruptSst(id, err) => write!(f, "sst {} is corrupt: {err}", sst_name(id)),
+            Problem::KeyRangeMismatch(id, manifest, file) => write!(
+                f,
+                "sst {} has key range {} in manifest but {} in file",
+                sst_name(id),
+                range_name(manifest),
+                range_name(file)
+            ),
+            Problem::Overlap(a, b) => {
+                write!(f, "ssts {} and {} overlap", sst_name(a), sst_name(b))
+            }
+            Problem::OrphanFile(path) => write!(f, "{path:?} isn't referenced by manifest"),
+            Problem::MissingLog(name) => write!(f, "{name} is missing"),
+            Problem::TornLog(name, uncommitted, undecodable) => write!(
+                f,
+                "{name} ends with {uncommitted} uncommitted actions and {undecodable} \
+                 undecodable bytes, which recovery discards"
+            ),
+            Problem::UnknownFamily(cf) => {
+                write!(
+                    f,
+                    "{MEMTABLE_LOG_FILENAME} writes to unknown column family {cf}"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub ssts: usize,
+    // Committed writes in the memtable log.
+    pub log_writes: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Fails only if the manifest can't be read, otherwise problems are reported.
+pub(crate) fn verify(store_dir: &Path, comparator: Arc<dyn Comparator>) -> Result<VerifyReport> {
+    let cmp = comparator.as_ref();
+    let mut report = VerifyReport::default();
+    let dump = ManifestDump::read(store_dir)?;
+    let manifest = dump.replay(cmp);
+    let undecodable = dump.log_size - dump.decoded_size;
+    if !dump.uncommitted().is_empty() || undecodable > 0 {
+        report.problems.push(Problem::TornLog(
+            dump.log_name.clone(),
+            dump.uncommitted().len(),
+            undecodable,
+        ));
+    }
+
+    let mut families = BTreeSet::new();
+    let mut active = BTreeSet::new();
+    for (_, cf) in manifest.family_names() {
+        families.insert(cf);
+        let family = manifest.family(cf);
+        for id in family.active_sst_ids() {
+            active.insert(id);
+            report.ssts += 1;
+            let path = id.path(store_dir);
+            if !path.exists() {
+                report.problems.push(Problem::MissingSst(id));
+                continue;
+            }
+            let checked = SstDump::open(&path, comparator.clone()).and_then(|dump| {
+                dump.verify()?;
+                let metadata = dump.table().metadata();
+                Ok((metadata.first_key.to_vec(), metadata.last_key.to_vec()))
+            });
+            match checked {
+                Ok(range) => {
+                    let recorded = family.sst_range(&id).cloned().unwrap_or_default();
+                    if recorded != range {
+                        report
+                            .problems
+                            .push(Problem::KeyRangeMismatch(id, recorded, range));
+                    }
+                }
+                Err(err) => report
+                    .problems
+                    .push(Problem::CorruptSst(id, format!("{err:#}"))),
+            }
+        }
+
+        // Ranges may touch, since the exclusive end of a range tombstone counts as the last key.
+        for level in 1..=family.max_level() {
+            let mut ids = family.get_sst_by_level(level);
+            ids.sort_by(|a, b| {
+                let (a, b) = (family.sst_range(a), family.sst_range(b));
+                cmp.compare(&a.unwrap().0, &b.unwrap().0)
+            });
+            for pair in ids.windows(2) {
+                let (previous, next) = (family.sst_range(&pair[0]), family.sst_range(&pair[1]));
+                if cmp.compare(&previous.unwrap().1, &next.unwrap().0).is_gt() {
+                    report.problems.push(Problem::Overlap(pair[0], pair[1]));
+                }
+            }
+        }
+    }
+
+    // Files under family directories that aren't active ssts, and stale manifest files.
+    for entry in fs::read_dir(store_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if path.is_dir() {
+            if let Some(cf) = parse_family_dir(&name) {
+                visit_files(&path, &mut |file| {
+                    match SstId::parse_path(file) {
+                        Some(id) if id.cf == cf && active.contains(&id) => {}
+                        _ => report
+                            .problems
+                            .push(Problem::OrphanFile(file.to_path_buf())),
+                    };
+                })?;
+            }
+        } else if (name.starts_with(MANIFEST_SNAPSHOT_PREFIX) && name != dump.snapshot_name)
+            || (name.starts_with(MANIFEST_LOG_PREFIX) && name != dump.log_name)
+        {
+            report.problems.push(Problem::OrphanFile(path));
+        }
+    }
+
+    // The memtable log should replay up to its end.
+    let log_path = store_dir.join(MEMTABLE_LOG_FILENAME);
+    if !log_path.exists() {
+        report
+            .problems
+            .push(Problem::MissingLog(MEMTABLE_LOG_FILENAME.to_owned()));
+        return Ok(report);
+    }
+    let buf = fs::read(log_path)?;
+    let (actions, end) = MemTableKeeper::decode_log(&buf);
+    let committed = actions
+        .iter()
+        .rposition(|action| *action == MemTableAction::Commit)
+        .map_or(0, |i| i + 1);
+    if committed < actions.len() || end < buf.len() {
+        report.problems.push(Problem::TornLog(
+            MEMTABLE_LOG_FILENAME.to_owned(),
+            actions.len() - committed,
+            buf.len() - end,
+        ));
+    }
+    let mut unknown = BTreeSet::new();
+    for action in &actions[..committed] {
+        let cf = match action {
+            MemTableAction::Insert((cf, ..)) | MemTableAction::DeleteRange((cf, _)) => *cf,
+            MemTableAction::Commit => continue,
+        };
+        report.log_writes += 1;
+        if !families.contains(&cf) && unknown.insert(cf) {
+            report.problems.push(Problem::UnknownFamily(cf));
+        }
+    }
+    Ok(report)
+}
+
+// Call `visit` on every file under `dir`, recursively.
+fn visit_files(dir: &Path, visit: &mut dyn FnMut(&Path)) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            visit_files(&path, visit)?;
+        } else {
+            visit(&path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use crate::manifest::ManifestAction;
+    use crate::memtable::MEMTABLE_LOG_FILENAME;
+    use crate::sstable::{family_dir, SstId};
+    use crate::store::Store;
+    use crate::test_util::*;
+    use crate::verify::*;
+
+    use anyhow::{ensure, Result};
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        for i in 0..3_u8 {
+            store.insert(vec![i], vec![i])?;
+            store.delete_range(&[i, 1], &[i, 2])?;
+            store.flush()?;
+        }
+        store.insert(b"unflushed".to_vec(), b"1".to_vec())?;
+        drop(store);
+        let report = Store::verify(&test_dir)?;
+        ensure!(report.is_ok(), "{:?}", report.problems);
+        ensure!((report.ssts, report.log_writes) == (3, 1));
+
+        // Break the files, then check that every problem is reported.
+        let level0 = family_dir(&test_dir, 0).join("0");
+        fs::write(level0.join("0"), b"truncated")?;
+        fs::remove_file(level0.join("1"))?;
+        fs::write(level0.join("9"), b"orphan")?;
+        let mut log = fs::OpenOptions::new()
+            .append(true)
+            .open(test_dir.join(MEMTABLE_LOG_FILENAME))?;
+        log.write_all(&[1])?;
+        drop(log);
+        let sst = |id| SstId {
+            cf: 0,
+            level: 0,
+            id,
+        };
+        let problems = Store::verify(&test_dir)?.problems;
+        ensure!(problems.len() == 4, "{problems:?}");
+        ensure!(matches!(problems[0], Problem::CorruptSst(id, _) if id == sst(0)));
+        ensure!(problems[1] == Problem::MissingSst(sst(1)));
+        ensure!(problems[2] == Problem::OrphanFile(level0.join("9")));
+        ensure!(problems[3] == Problem::TornLog(MEMTABLE_LOG_FILENAME.to_owned(), 0, 1));
+        ensure!(problems[3].to_string().contains("1 undecodable bytes"));
+
+        // Verifying changes nothing.
+        ensure!(level0.join("9").exists());
+        ensure!(Store::verify(&test_dir)?.problems == problems);
+        fs::remove_file(test_dir.join(MEMTABLE_LOG_FILENAME))?;
+        let missing = Problem::MissingLog(MEMTABLE_LOG_FILENAME.to_owned());
+        ensure!(Store::verify(&test_dir)?.problems == [&problems[..3], &[missing]].concat());
+
+        // Move two overlapping ssts to level 1, recording a wrong range for one of them.
+        let test_dir = create_test_dir()?;
+        let mut store = Store::new(&test_dir)?;
+        for keys in [[b"a", b"c"], [b"b", b"d"]] {
+            for key in keys {
+                store.insert(key.to_vec(), b"1".to_vec())?;
+            }
+            store.flush()?;
+        }
+        drop(store);
+        let (first, second) = (SstId { level: 1, ..sst(0) }, SstId { level: 1, ..sst(1) });
+        fs::create_dir_all(first.path(&test_dir).parent().unwrap())?;
+        fs::rename(sst(0).path(&test_dir), first.path(&test_dir))?;
+        fs::rename(sst(1).path(&test_dir), second.path(&test_dir))?;
+        let log_name = ManifestDump::read(&test_dir)?.log_name;
+        let mut log = fs::OpenOptions::new()
+            .append(true)
+            .open(test_dir.join(log_name))?;
+        for action in [
+            ManifestAction::Remove((sst(0),)),
+            ManifestAction::Remove((sst(1),)),
+            ManifestAction::Add((first, b"a".to_vec(), b"c".to_vec())),
+            ManifestAction::Add((second, b"b".to_vec(), b"e".to_vec())),
+            ManifestAction::Commit,
+        ] {
+            log.write_all(&bincode::encode_to_vec(
+                action,
+                bincode::config::standard(),
+            )?)?;
+        }
+        drop(log);
+        let problems = Store::verify(&test_dir)?.problems;
+        ensure!(problems.len() == 2, "{problems:?}");
+        let ranges = (
+            (b"b".to_vec(), b"e".to_vec()),
+            (b"b".to_vec(), b"d".to_vec()),
+        );
+        ensure!(problems.contains(&Problem::KeyRangeMismatch(second, ranges.0, ranges.1)));
+        ensure!(problems.contains(&Problem::Overlap(first, second)));
+        Ok(())
+    }
+}
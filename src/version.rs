@@ -0,0 +1,160 @@
+// A version is an immutable copy of manifest.
+// Reads and iterators pin the version they start with,
+// so ssts removed by later compactions stay on disk until no live version refers to them.
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::manifest::Manifest;
+use crate::sstable::*;
+
+pub struct Version {
+    manifest: Manifest,
+    tracker: Arc<Mutex<SstTracker>>,
+}
+
+impl Deref for Version {
+    type Target = Manifest;
+
+    fn deref(&self) -> &Self::Target {
+        &self.manifest
+    }
+}
+
+impl Drop for Version {
+    fn drop(&mut self) {
+        let mut tracker = self.tracker.lock().unwrap();
+        for id in self.manifest.active_sst_ids() {
+            tracker.unref(&id);
+        }
+    }
+}
+
+// Count live versions referring to each sst.
+// Obsolete ssts are deleted once unreferenced.
+struct SstTracker {
+    store_dir: PathBuf,
+    refs: BTreeMap<SstId, usize>,
+    obsolete: BTreeSet<SstId>,
+}
+
+impl SstTracker {
+    fn unref(&mut self, id: &SstId) {
+        if let Some(count) = self.refs.get_mut(id) {
+            *count -= 1;
+            if *count == 0 {
+                self.refs.remove(id);
+                if self.obsolete.remove(id) {
+                    self.delete(id);
+                }
+            }
+        }
+    }
+
+    fn delete(&self, id: &SstId) {
+        match SSTable::remove(&self.store_dir, id) {
+            Ok(()) => {}
+            Err(err) => {
+                eprintln!("Failed to remove SST file {id:#?}: {err}");
+            }
+        }
+    }
+}
+
+pub struct VersionSet {
+    current: Arc<Version>,
+    tracker: Arc<Mutex<SstTracker>>,
+}
+
+impl VersionSet {
+    pub fn new(manifest: &Manifest, store_dir: &Path) -> VersionSet {
+        let tracker = Arc::new(Mutex::new(SstTracker {
+            store_dir: store_dir.to_path_buf(),
+            refs: BTreeMap::new(),
+            obsolete: BTreeSet::new(),
+        }));
+        VersionSet {
+            current: Self::build(manifest, &tracker),
+            tracker,
+        }
+    }
+
+    fn build(manifest: &Manifest, tracker: &Arc<Mutex<SstTracker>>) -> Arc<Version> {
+        let mut locked = tracker.lock().unwrap();
+        for id in manifest.active_sst_ids() {
+            *locked.refs.entry(id).or_insert(0) += 1;
+        }
+        Arc::new(Version {
+            manifest: manifest.clone(),
+            tracker: tracker.clone(),
+        })
+    }
+
+    pub fn current(&self) -> Arc<Version> {
+        self.current.clone()
+    }
+
+    // `id` is removed from manifest.
+    // Delete it now if no live version refers to it, or when the last one is dropped.
+    pub fn retire(&mut self, id: &SstId) {
+        let mut tracker = self.tracker.lock().unwrap();
+        if tracker.refs.contains_key(id) {
+            tracker.obsolete.insert(*id);
+        } else {
+            tracker.delete(id);
+        }
+    }
+
+    // Replace current version with a copy of `manifest`.
+    pub fn install(&mut self, manifest: &Manifest) {
+        self.current = Self::build(manifest, &self.tracker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::comparator::BytewiseComparator;
+    use crate::manifest::*;
+    use crate::test_util::*;
+    use crate::version::*;
+
+    use anyhow::{ensure, Result};
+
+    #[test]
+    fn test_pinned_version() -> Result<()> {
+        let test_dir = create_test_dir()?;
+        let mut keeper = ManifestKeeper::new(&test_dir, Arc::new(BytewiseComparator))?;
+        let sst_id = keeper.family(0).latest_sst_id(1);
+        keeper.new_id(0, 1);
+        sst_id.create_file(&test_dir)?;
+        keeper.add(sst_id, b"a", b"z");
+        keeper.commit()?;
+
+        let pinned = keeper.current_version();
+        keeper.batch_start();
+        keeper.remove(&sst_id);
+        keeper.commit()?;
+        ensure!(
+            keeper.active_sst_ids().is_empty(),
+            "Removed sst is still active"
+        );
+        ensure!(
+            pinned.active_sst_ids() == vec![sst_id],
+            "Pinned version changed"
+        );
+        ensure!(
+            sst_id.path(&test_dir).exists(),
+            "SST file used by a pinned version is deleted"
+        );
+
+        drop(pinned);
+        ensure!(
+            !sst_id.path(&test_dir).exists(),
+            "Unreferenced obsolete SST file is not deleted"
+        );
+        Ok(())
+    }
+}